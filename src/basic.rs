@@ -0,0 +1,136 @@
+// Basic strategy for a multi-deck shoe where the dealer stands on soft 17,
+// following the usual published charts. It's what simulated players use when
+// all that matters is that they play sensibly, e.g. when dealing out shoes.
+
+use super::{Action, GameState, can_double, can_split, can_surrender, hand_value,
+            min_hand_value};
+#[cfg(test)]
+use super::{DEFAULT_RULES, FULL_DECK, parse_rules};
+
+// Whether `upcard` is from `low` to `high`, counting an ace as 11.
+fn against(upcard: u16, low: u16, high: u16) -> bool {
+    let upcard = if upcard == 1 { 11 } else { upcard };
+    low <= upcard && upcard <= high
+}
+
+fn split_pair(card: u16, upcard: u16, double_after_split: bool) -> bool {
+    match card {
+        1 | 8 => true,
+        9 => against(upcard, 2, 6) || against(upcard, 8, 9),
+        7 => against(upcard, 2, 7),
+        6 if double_after_split => against(upcard, 2, 6),
+        6 => against(upcard, 3, 6),
+        4 if double_after_split => against(upcard, 5, 6),
+        2 | 3 if double_after_split => against(upcard, 2, 7),
+        2 | 3 => against(upcard, 4, 7),
+        _ => false,
+    }
+}
+
+fn surrender_hand(total: u16, upcard: u16) -> bool {
+    (total == 16 && against(upcard, 9, 11)) || (total == 15 && upcard == 10)
+}
+
+// Doubling soft hands, by the total with the ace counted as 11.
+fn double_soft(total: u16, upcard: u16) -> bool {
+    match total {
+        13 | 14 => against(upcard, 5, 6),
+        15 | 16 => against(upcard, 4, 6),
+        17 | 18 => against(upcard, 3, 6),
+        _ => false,
+    }
+}
+
+fn double_hard(total: u16, upcard: u16) -> bool {
+    match total {
+        9 => against(upcard, 3, 6),
+        10 => against(upcard, 2, 9),
+        11 => against(upcard, 2, 10),
+        _ => false,
+    }
+}
+
+fn stand_soft(total: u16, upcard: u16) -> bool {
+    total >= 19 || (total == 18 && against(upcard, 2, 8))
+}
+
+fn stand_hard(total: u16, upcard: u16) -> bool {
+    match total {
+        0..=11 => false,
+        12 => against(upcard, 4, 6),
+        13..=16 => against(upcard, 2, 6),
+        _ => true,
+    }
+}
+
+/// What basic strategy does with the player's hand against the dealer's
+/// upcard, given what the state allows. Never takes insurance.
+pub fn basic_strategy(state: &mut GameState) -> Action {
+    let player = &state.player;
+    let upcard = state.dealer[0];
+    if can_split(state) && split_pair(player[0], upcard, state.rules.double_after_split) {
+        return Action::Split;
+    }
+    let total = hand_value(player);
+    let soft = total != min_hand_value(player);
+    if can_surrender(state) && !soft && surrender_hand(total, upcard) {
+        return Action::Surrender;
+    }
+    let double = if soft {
+        double_soft(total, upcard)
+    } else {
+        double_hard(total, upcard)
+    };
+    if double && can_double(state) {
+        return Action::Double;
+    }
+    let stand = if soft {
+        stand_soft(total, upcard) || (double && total == 18)
+    } else {
+        stand_hard(total, upcard)
+    };
+    if stand { Action::Stand } else { Action::Hit }
+}
+
+#[test]
+fn test_basic_strategy() {
+    let mut state = GameState {
+        player: vec![10, 6],
+        dealer: vec![10],
+        deck: FULL_DECK,
+        shoe: FULL_DECK,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules: DEFAULT_RULES,
+    };
+    let mut play = |player: Vec<u16>, upcard: u16| {
+        state.player = player;
+        state.dealer = vec![upcard];
+        basic_strategy(&mut state)
+    };
+    assert!(Action::Surrender == play(vec![10, 6], 10));
+    assert!(Action::Hit == play(vec![10, 3, 3], 10));
+    assert!(Action::Stand == play(vec![10, 2], 4));
+    assert!(Action::Hit == play(vec![10, 2], 3));
+    assert!(Action::Double == play(vec![6, 5], 10));
+    assert!(Action::Hit == play(vec![6, 5], 1));
+    assert!(Action::Hit == play(vec![4, 3, 4], 6));
+    assert!(Action::Double == play(vec![1, 7], 6));
+    assert!(Action::Stand == play(vec![1, 4, 3], 6));
+    assert!(Action::Stand == play(vec![1, 7], 7));
+    assert!(Action::Hit == play(vec![1, 7], 9));
+    assert!(Action::Split == play(vec![8, 8], 1));
+    assert!(Action::Stand == play(vec![9, 9], 7));
+    assert!(Action::Stand == play(vec![10, 10], 6));
+    assert!(Action::Double == play(vec![5, 5], 9));
+    assert!(Action::Split == play(vec![2, 2], 2));
+
+    state.rules = parse_rules("nodas,nols");
+    state.player = vec![2, 2];
+    state.dealer = vec![2];
+    assert!(Action::Hit == basic_strategy(&mut state));
+    state.player = vec![10, 6];
+    state.dealer = vec![10];
+    assert!(Action::Hit == basic_strategy(&mut state));
+}
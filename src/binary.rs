@@ -0,0 +1,231 @@
+// A compact binary form of the advantage dataset, for looking advantages up
+// without parsing CSV. The file holds the dataset's metadata, then fixed size
+// rows sorted by composition, so a lookup is a binary search of a few reads:
+//
+//   magic       8 bytes, "BJSIMADV"
+//   version     u32
+//   metadata    u32 length, then the CSV header's comment lines as UTF-8
+//   count       u64
+//   rows        count of: 10 u16 rank counts, f32 advantage, f32 seconds
+//
+// Numbers are little endian, and unknown seconds are NaN.
+
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::Deck;
+use super::dataset::{self, Metadata, Row};
+#[cfg(test)]
+use super::{DEFAULT_RULES, FULL_DECK};
+#[cfg(test)]
+use super::queue::test_dir;
+
+const MAGIC: &[u8; 8] = b"BJSIMADV";
+const FORMAT_VERSION: u32 = 1;
+const ROW_SIZE: u64 = 28;
+
+fn encode_row(row: &Row) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ROW_SIZE as usize);
+    for count in row.deck.cards.iter() {
+        bytes.extend_from_slice(&count.to_le_bytes());
+    }
+    bytes.extend_from_slice(&row.advantage.to_le_bytes());
+    bytes.extend_from_slice(&row.seconds.unwrap_or(f32::NAN).to_le_bytes());
+    bytes
+}
+
+fn decode_row(bytes: &[u8]) -> Row {
+    let mut cards = [0; 10];
+    for (count, pair) in cards.iter_mut().zip(bytes.chunks(2)) {
+        *count = u16::from_le_bytes([pair[0], pair[1]]);
+    }
+    let float = |at: usize| {
+        f32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    };
+    let seconds = float(24);
+    Row {
+        deck: Deck {
+            cards,
+            size: cards.iter().sum(),
+        },
+        advantage: float(20),
+        seconds: if seconds.is_nan() { None } else { Some(seconds) },
+    }
+}
+
+/// Writes the rows, each deck once, to a new binary file.
+pub fn write_binary(path: &Path,
+                    metadata: &Metadata,
+                    rows: &[Row])
+                    -> Result<(), Box<dyn Error>> {
+    let mut rows = rows.to_vec();
+    rows.sort_by_key(|row| row.deck.cards);
+    rows.dedup_by(|a, b| a.deck == b.deck);
+    let header = metadata.header();
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&(header.len() as u32).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    writer.write_all(&(rows.len() as u64).to_le_bytes())?;
+    for row in rows.iter() {
+        writer.write_all(&encode_row(row))?;
+    }
+    writer.into_inner()?.sync_all()?;
+    Ok(())
+}
+
+/// An open binary dataset. Rows are read from the file as they're needed.
+pub struct BinaryDataset {
+    file: File,
+    pub metadata: Metadata,
+    count: u64,
+    // Where the rows start.
+    start: u64,
+}
+
+impl BinaryDataset {
+    pub fn open(path: &Path) -> Result<BinaryDataset, Box<dyn Error>> {
+        let mut file = File::open(path)?;
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(format!("{} isn't a binary dataset", path.display()).into());
+        }
+        let mut word = [0; 4];
+        file.read_exact(&mut word)?;
+        let version = u32::from_le_bytes(word);
+        if version != FORMAT_VERSION {
+            return Err(format!("{} is binary format {}, not {}",
+                               path.display(),
+                               version,
+                               FORMAT_VERSION)
+                .into());
+        }
+        file.read_exact(&mut word)?;
+        let mut header = vec![0; u32::from_le_bytes(word) as usize];
+        file.read_exact(&mut header)?;
+        let metadata = dataset::parse_metadata(&String::from_utf8(header)?)?
+            .ok_or("The binary dataset has no metadata")?;
+        let mut count = [0; 8];
+        file.read_exact(&mut count)?;
+        let count = u64::from_le_bytes(count);
+        let start = file.stream_position()?;
+        if file.metadata()?.len() != start + count * ROW_SIZE {
+            return Err(format!("{} should have {} rows but is the wrong size",
+                               path.display(),
+                               count)
+                .into());
+        }
+        Ok(BinaryDataset {
+            file,
+            metadata,
+            count,
+            start,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    fn row(&mut self, index: u64) -> Result<Row, Box<dyn Error>> {
+        let mut bytes = [0; ROW_SIZE as usize];
+        self.file.seek(SeekFrom::Start(self.start + index * ROW_SIZE))?;
+        self.file.read_exact(&mut bytes)?;
+        Ok(decode_row(&bytes))
+    }
+
+    /// The row for `deck`, found by binary search.
+    pub fn lookup(&mut self, deck: &Deck) -> Result<Option<Row>, Box<dyn Error>> {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let row = self.row(middle)?;
+            match row.deck.cards.cmp(&deck.cards) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return Ok(Some(row)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Every row, in order of composition.
+    pub fn rows(&mut self) -> Result<Vec<Row>, Box<dyn Error>> {
+        self.file.seek(SeekFrom::Start(self.start))?;
+        let mut reader = BufReader::new(&self.file);
+        let mut bytes = [0; ROW_SIZE as usize];
+        let mut rows = vec![];
+        for _ in 0..self.count {
+            reader.read_exact(&mut bytes)?;
+            rows.push(decode_row(&bytes));
+        }
+        Ok(rows)
+    }
+}
+
+/// Converts a data file to a new binary file, and returns how many rows it
+/// holds.
+pub fn csv_to_binary(csv: &Path, binary: &Path) -> Result<u64, Box<dyn Error>> {
+    let metadata = dataset::read_metadata(csv)?
+        .ok_or_else(|| format!("{} has no header, migrate it first", csv.display()))?;
+    write_binary(binary, &metadata, &dataset::read_rows(csv)?)?;
+    Ok(BinaryDataset::open(binary)?.len())
+}
+
+/// Converts a binary file to a new data file, and returns how many rows it
+/// holds.
+pub fn binary_to_csv(binary: &Path, csv: &Path) -> Result<usize, Box<dyn Error>> {
+    let mut dataset = BinaryDataset::open(binary)?;
+    let rows = dataset.rows()?;
+    dataset::write_dataset(csv, &dataset.metadata, &rows)?;
+    Ok(rows.len())
+}
+
+#[test]
+fn test_binary_dataset() {
+    use std::fs;
+    let dir = test_dir("binary");
+    let metadata = Metadata::current(&DEFAULT_RULES, 8);
+    let mut rows = vec![];
+    for card in (1..11).rev() {
+        let mut deck = FULL_DECK;
+        deck.draw(card);
+        rows.push(Row {
+            deck,
+            advantage: card as f32 / 1000.0,
+            seconds: if card % 2 == 0 { Some(card as f32) } else { None },
+        });
+    }
+    let csv = dir.join("data.csv");
+    dataset::write_dataset(&csv, &metadata, &rows).unwrap();
+    let binary = dir.join("data.bin");
+    assert!(10 == csv_to_binary(&csv, &binary).unwrap());
+    assert!(csv_to_binary(&csv, &binary).is_err());
+    assert!(fs::metadata(&binary).unwrap().len() > 10 * ROW_SIZE);
+
+    let mut dataset = BinaryDataset::open(&binary).unwrap();
+    assert!(metadata == dataset.metadata);
+    for row in rows.iter() {
+        assert!(Some(*row) == dataset.lookup(&row.deck).unwrap());
+    }
+    assert!(dataset.lookup(&FULL_DECK).unwrap().is_none());
+    let sorted = dataset.rows().unwrap();
+    for pair in sorted.windows(2) {
+        assert!(pair[0].deck.cards < pair[1].deck.cards);
+    }
+
+    let back = dir.join("back.csv");
+    assert!(10 == binary_to_csv(&binary, &back).unwrap());
+    assert!(Some(metadata) == dataset::read_metadata(&back).unwrap());
+    assert!(sorted == dataset::read_rows(&back).unwrap());
+
+    fs::write(&binary, b"not a dataset").unwrap();
+    assert!(BinaryDataset::open(&binary).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,421 @@
+// Spreads advantage computation over machines. A coordinator owns the work
+// queue and the data file, and workers connect to it over TCP. Each request is
+// one line on a fresh connection, answered with one line:
+//
+//   WORK <engine>                       -> DECK <counts> <rules> <decks> | WAIT | DONE
+//   HEARTBEAT <counts>                  -> OK | LOST
+//   RESULT <counts> <value> <seconds>   -> OK
+//
+// where <counts> is the count of each rank from ace to ten, separated by
+// commas, and <decks> is how many decks the dataset's shoe holds. Workers
+// compute with the coordinator's rules and shoe, and one with an engine
+// version other than the dataset's is turned away. A worker sends heartbeats
+// while it computes, and a deck whose worker has gone quiet for longer than
+// the timeout is handed to the next worker that asks. Requests that get no
+// answer are retried, so workers ride out a coordinator restart, and a worker
+// still computing a deck handed out before the restart keeps it.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Deck, Rules, full_shoe, try_parse_rules};
+use super::dataset::{format_counts, parse_counts};
+use super::queue::WorkQueue;
+#[cfg(test)]
+use super::dataset::{Metadata, read_rows};
+#[cfg(test)]
+use super::queue::test_dir;
+
+struct Assignments {
+    // When each deck out with a worker was last heard about.
+    out: HashMap<Deck, Instant>,
+    // Decks whose workers went quiet, to hand out again.
+    lost: Vec<Deck>,
+}
+
+pub struct Coordinator {
+    queue: WorkQueue,
+    timeout: Duration,
+    assignments: Mutex<Assignments>,
+}
+
+impl Coordinator {
+    /// A coordinator handing out decks from `queue`, which reassigns a deck
+    /// once its worker hasn't been heard from for `timeout`.
+    pub fn new(queue: WorkQueue, timeout: Duration) -> Coordinator {
+        Coordinator {
+            queue,
+            timeout,
+            assignments: Mutex::new(Assignments {
+                out: HashMap::new(),
+                lost: vec![],
+            }),
+        }
+    }
+
+    /// Whether every deck has been computed.
+    pub fn is_finished(&self) -> Result<bool, Box<dyn Error>> {
+        let mut assignments = self.assignments.lock().unwrap();
+        if !assignments.out.is_empty() || !assignments.lost.is_empty() {
+            return Ok(false);
+        }
+        match self.queue.claim()? {
+            Some(deck) => {
+                assignments.lost.push(deck);
+                Ok(false)
+            }
+            // Decks claimed before a restart may still be out.
+            None => Ok(self.queue.is_complete()),
+        }
+    }
+
+    fn assign(&self, engine: &str) -> Result<String, Box<dyn Error>> {
+        let metadata = self.queue.metadata();
+        if engine != metadata.engine {
+            return Err(format!("The dataset is for engine {}, not {}", metadata.engine, engine)
+                .into());
+        }
+        let mut assignments = self.assignments.lock().unwrap();
+        let now = Instant::now();
+        let timeout = self.timeout;
+        let quiet: Vec<Deck> = assignments.out
+            .iter()
+            .filter(|&(_, &heard)| now.duration_since(heard) > timeout)
+            .map(|(&deck, _)| deck)
+            .collect();
+        for deck in quiet {
+            assignments.out.remove(&deck);
+            assignments.lost.push(deck);
+        }
+        let deck = match assignments.lost.pop() {
+            Some(deck) => Some(deck),
+            None => self.queue.claim()?,
+        };
+        Ok(match deck {
+            Some(deck) => {
+                assignments.out.insert(deck, now);
+                format!("DECK {} {} {}", format_counts(&deck), metadata.rules, metadata.decks)
+            }
+            None if assignments.out.is_empty() && self.queue.is_complete() => {
+                "DONE".to_string()
+            }
+            None => "WAIT".to_string(),
+        })
+    }
+
+    fn heartbeat(&self, deck: Deck) -> Result<String, Box<dyn Error>> {
+        let mut guard = self.assignments.lock().unwrap();
+        let assignments = &mut *guard;
+        let now = Instant::now();
+        match assignments.out.get_mut(&deck) {
+            Some(heard) => *heard = now,
+            // A deck handed out before a restart is still claimed, and is
+            // out again while its worker is heard from.
+            None if self.queue.is_claimed(&deck) && !self.queue.is_computed(&deck) &&
+                    !assignments.lost.contains(&deck) => {
+                assignments.out.insert(deck, now);
+            }
+            None => return Ok("LOST".to_string()),
+        }
+        // Keeps the claim from going stale if the coordinator restarts.
+        self.queue.refresh(&deck)?;
+        Ok("OK".to_string())
+    }
+
+    fn result(&self, deck: Deck, advantage: f32, seconds: f32) -> Result<String, Box<dyn Error>> {
+        let mut assignments = self.assignments.lock().unwrap();
+        // Decks handed out before a restart are still claimed, so their
+        // results are taken too.
+        if !self.queue.is_claimed(&deck) {
+            return Err(format!("{} was never handed out", format_counts(&deck)).into());
+        }
+        self.queue.complete(deck, advantage, seconds)?;
+        // The deck may have been reassigned, but the first result is as good
+        // as any.
+        assignments.out.remove(&deck);
+        assignments.lost.retain(|&lost| lost != deck);
+        Ok("OK".to_string())
+    }
+
+    /// Answers one request line.
+    pub fn respond(&self, request: &str) -> Result<String, Box<dyn Error>> {
+        let words: Vec<&str> = request.split_whitespace().collect();
+        match words[..] {
+            ["WORK", engine] => self.assign(engine),
+            ["HEARTBEAT", counts] => self.heartbeat(parse_counts(counts)?),
+            ["RESULT", counts, advantage, seconds] => {
+                self.result(parse_counts(counts)?,
+                            advantage.parse::<f32>()?,
+                            seconds.parse::<f32>()?)
+            }
+            _ => Err(format!("Unknown request {:?}", request).into()),
+        }
+    }
+
+    fn handle(&self, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let mut request = String::new();
+        BufReader::new(&stream).read_line(&mut request)?;
+        let response = match self.respond(&request) {
+            Ok(response) => response,
+            Err(err) => format!("ERROR {}", err),
+        };
+        (&stream).write_all(format!("{}\n", response).as_bytes())?;
+        Ok(())
+    }
+
+    /// Serves workers on `listener` until every deck has been computed and
+    /// no worker has asked for anything for the timeout, so the workers still
+    /// waiting hear that they're done. Each connection is answered on a
+    /// thread of its own, so a worker that stalls holds up nobody else.
+    pub fn serve(&self, listener: &TcpListener) -> Result<(), Box<dyn Error>> {
+        listener.set_nonblocking(true)?;
+        let mut last_request = Instant::now();
+        thread::scope(|scope| loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    last_request = Instant::now();
+                    scope.spawn(move || {
+                        let answered = stream.set_nonblocking(false)
+                            .map_err(|err| err.into())
+                            .and_then(|_| self.handle(stream));
+                        if let Err(err) = answered {
+                            eprintln!("Failed to answer a worker: {}", err);
+                        }
+                    });
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if last_request.elapsed() > self.timeout && self.is_finished()? {
+                        return Ok(());
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => eprintln!("Failed to accept a worker: {}", err),
+            }
+        })
+    }
+}
+
+/// How a worker talks to its coordinator.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Connection<A: ToSocketAddrs> {
+    pub address: A,
+    /// How often to send heartbeats, and to ask again when told to wait.
+    pub interval: Duration,
+    /// How many times to try a request before giving up.
+    pub attempts: usize,
+}
+
+impl<A: ToSocketAddrs> Connection<A> {
+    fn try_request(&self, request: &str) -> Result<String, Box<dyn Error>> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        stream.write_all(format!("{}\n", request).as_bytes())?;
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        let response = response.trim().to_string();
+        if response.is_empty() {
+            return Err("The coordinator closed the connection".into());
+        }
+        Ok(response)
+    }
+
+    // Sends a request, trying again after an interval each time it doesn't
+    // get an answer. An ERROR answer would only come again, so it's returned
+    // at once.
+    fn request(&self, request: &str) -> Result<String, Box<dyn Error>> {
+        let mut attempt = 1;
+        loop {
+            match self.try_request(request) {
+                Ok(ref response) if response.starts_with("ERROR") => {
+                    return Err(response.clone().into())
+                }
+                Ok(response) => return Ok(response),
+                Err(err) => {
+                    if attempt >= self.attempts {
+                        return Err(err);
+                    }
+                    eprintln!("Request failed, retrying: {}", err);
+                }
+            }
+            attempt += 1;
+            thread::sleep(self.interval);
+        }
+    }
+}
+
+/// Computes advantages with the coordinator's rules until it has no more
+/// work, and returns how many this worker computed. `advantage` is given the
+/// dataset's full shoe, the deck, and the rules.
+pub fn work<A, F>(connection: &Connection<A>, advantage: F) -> Result<usize, Box<dyn Error>>
+    where A: ToSocketAddrs + Sync,
+          F: Fn(&Deck, Deck, &Rules) -> f32
+{
+    let request = format!("WORK {}", env!("CARGO_PKG_VERSION"));
+    let mut computed = 0;
+    loop {
+        let response = connection.request(&request)?;
+        let words: Vec<&str> = response.split_whitespace().collect();
+        let (deck, rules, shoe) = match words[..] {
+            ["DECK", counts, rules, decks] => {
+                (parse_counts(counts)?, try_parse_rules(rules)?, full_shoe(decks.parse()?))
+            }
+            ["WAIT"] => {
+                thread::sleep(connection.interval);
+                continue;
+            }
+            ["DONE"] => return Ok(computed),
+            _ => return Err(format!("Unexpected response {:?}", response).into()),
+        };
+        let heartbeat = format!("HEARTBEAT {}", format_counts(&deck));
+        let heartbeat = &heartbeat;
+        let (done, beat) = mpsc::channel::<()>();
+        let start = Instant::now();
+        let value = thread::scope(|scope| {
+            scope.spawn(move || {
+                // Beats until the sender is dropped. A lost deck is finished
+                // anyway, in case it's quicker than starting over.
+                let interval = connection.interval;
+                while beat.recv_timeout(interval) == Err(mpsc::RecvTimeoutError::Timeout) {
+                    if let Err(err) = connection.request(heartbeat) {
+                        eprintln!("Heartbeat failed: {}", err);
+                    }
+                }
+            });
+            let value = advantage(&shoe, deck, &rules);
+            drop(done);
+            value
+        });
+        let seconds = start.elapsed().as_secs_f32();
+        connection.request(&format!("RESULT {} {} {}", format_counts(&deck), value, seconds))?;
+        computed += 1;
+    }
+}
+
+#[test]
+fn test_coordinator() {
+    use std::path::Path;
+    use super::FULL_DECK;
+    use super::samples::samples_csv;
+    let dir = test_dir("coordinator");
+    let mut samples = vec![];
+    for card in 1..11 {
+        let mut deck = FULL_DECK;
+        deck.draw(card);
+        samples.push(deck);
+    }
+    let samples_path = dir.join("samples.csv");
+    ::std::fs::write(&samples_path, samples_csv(&samples).unwrap()).unwrap();
+    let data_path = dir.join("data.csv");
+    let metadata = Metadata::current(&super::parse_rules("h17"), 8);
+    let queue = WorkQueue::open(samples_path.to_str().unwrap(),
+                                data_path.to_str().unwrap(),
+                                &metadata,
+                                None,
+                                Duration::from_secs(3600))
+        .unwrap();
+    let coordinator = Coordinator::new(queue, Duration::from_millis(300));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let connection = Connection {
+        address: listener.local_addr().unwrap(),
+        interval: Duration::from_millis(50),
+        attempts: 3,
+    };
+    // Slower than the timeout, so only the heartbeats keep the decks.
+    let slow = |shoe: &Deck, deck: Deck, rules: &Rules| {
+        assert!(rules.dealer_hits_soft_17 && FULL_DECK == *shoe);
+        thread::sleep(Duration::from_millis(400));
+        deck.size as f32
+    };
+    let computed = thread::scope(|scope| {
+        let server = scope.spawn(|| coordinator.serve(&listener).unwrap());
+        // Answered with an ERROR, which isn't retried.
+        let start = Instant::now();
+        assert!(connection.request("WORK 0.0.0").is_err());
+        assert!(start.elapsed() < connection.interval);
+        // Results only count for decks that were handed out.
+        let never = format!("RESULT {} 0 1", format_counts(&samples[0]));
+        assert!(connection.request(&never).is_err());
+        let unknown = format!("RESULT {} 0 1", format_counts(&FULL_DECK));
+        assert!(connection.request(&unknown).is_err());
+        // A client that connects and says nothing.
+        let stalled = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        // A worker that dies after taking a deck.
+        let taken = connection.try_request(&format!("WORK {}", metadata.engine)).unwrap();
+        assert!(taken.starts_with("DECK"));
+        let workers: Vec<_> =
+            (0..3).map(|_| scope.spawn(|| work(&connection, slow).unwrap())).collect();
+        let computed: usize = workers.into_iter().map(|worker| worker.join().unwrap()).sum();
+        drop(stalled);
+        server.join().unwrap();
+        computed
+    });
+    assert!(samples.len() == computed);
+    let rows = read_rows(Path::new(&data_path)).unwrap();
+    assert!(samples.len() == rows.len());
+    assert!(rows.iter().all(|row| row.advantage == row.deck.size as f32));
+    assert!(rows.iter().all(|row| row.seconds.unwrap() >= 0.4));
+    // With the coordinator gone, requests fail once the retries run out.
+    drop(listener);
+    assert!(connection.request("WORK").is_err());
+    ::std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_restart() {
+    use std::fs;
+    use super::FULL_DECK;
+    use super::samples::samples_csv;
+    let dir = test_dir("restart");
+    let samples: Vec<Deck> = (1..4)
+        .map(|card| {
+            let mut deck = FULL_DECK;
+            deck.draw(card);
+            deck
+        })
+        .collect();
+    let samples_path = dir.join("samples.csv");
+    fs::write(&samples_path, samples_csv(&samples).unwrap()).unwrap();
+    let data_path = dir.join("data.csv");
+    let metadata = Metadata::current(&super::DEFAULT_RULES, 8);
+    let open = || {
+        WorkQueue::open(samples_path.to_str().unwrap(),
+                        data_path.to_str().unwrap(),
+                        &metadata,
+                        None,
+                        Duration::from_secs(3600))
+            .unwrap()
+    };
+    let work = format!("WORK {}", metadata.engine);
+    let out = Coordinator::new(open(), Duration::from_secs(3600)).respond(&work).unwrap();
+    let counts = out.split_whitespace().nth(1).unwrap().to_string();
+
+    // After the restart the deck handed out before it is still being
+    // computed, so the others are handed out but the work isn't done.
+    let coordinator = Coordinator::new(open(), Duration::from_secs(3600));
+    for _ in 1..samples.len() {
+        let response = coordinator.respond(&work).unwrap();
+        let deck = response.split_whitespace().nth(1).unwrap();
+        assert!(deck != counts);
+        coordinator.respond(&format!("RESULT {} 0 1", deck)).unwrap();
+    }
+    assert!("WAIT" == coordinator.respond(&work).unwrap());
+    assert!(!coordinator.is_finished().unwrap());
+
+    // Its worker's heartbeats keep it, and freshen its claim.
+    let claim = dir.join("data.csv.claims").join(counts.replace(',', "-"));
+    let old = ::std::time::SystemTime::now() - Duration::from_secs(7200);
+    fs::OpenOptions::new().write(true).open(&claim).unwrap().set_modified(old).unwrap();
+    assert!("OK" == coordinator.respond(&format!("HEARTBEAT {}", counts)).unwrap());
+    assert!(fs::metadata(&claim).unwrap().modified().unwrap().elapsed().unwrap().as_secs() < 60);
+    coordinator.respond(&format!("RESULT {} 0 1", counts)).unwrap();
+    assert!("DONE" == coordinator.respond(&work).unwrap());
+    assert!(coordinator.is_finished().unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+}
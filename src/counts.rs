@@ -0,0 +1,206 @@
+// Card counting systems. A system tags each rank with a value and the running
+// count is the sum of the tags of the cards seen. How well a system tracks the
+// player's edge, the playing decisions and the insurance bet is measured by
+// correlating its tags with the effects of removal for each.
+
+use super::{Deck, card_index};
+use super::eor::Effects;
+
+/// The proportions of the ranks in a deck, used to weight correlations.
+const SINGLE_DECK: [f32; 10] = [4.0, 4.0, 4.0, 4.0, 4.0, 4.0, 4.0, 4.0, 4.0, 16.0];
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct CountSystem {
+    pub name: String,
+    /// The tag for each rank, indexed like `Deck::cards`.
+    pub tags: [f32; 10],
+}
+
+fn system(name: &str, tags: [f32; 10]) -> CountSystem {
+    CountSystem {
+        name: String::from(name),
+        tags,
+    }
+}
+
+/// The well known systems, with tags from ace to ten.
+pub fn systems() -> Vec<CountSystem> {
+    vec![system("Hi-Lo", [-1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0, -1.0]),
+         system("KO", [-1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, -1.0]),
+         system("Hi-Opt I", [0.0, 0.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0, -1.0]),
+         system("Hi-Opt II", [0.0, 1.0, 1.0, 2.0, 2.0, 1.0, 1.0, 0.0, 0.0, -2.0]),
+         system("Omega II", [0.0, 1.0, 1.0, 2.0, 2.0, 2.0, 1.0, 0.0, -1.0, -2.0]),
+         system("Zen", [-1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 1.0, 0.0, 0.0, -2.0]),
+         system("Wong Halves", [-1.0, 0.5, 1.0, 1.0, 1.5, 1.0, 0.5, 0.0, -0.5, -1.0])]
+}
+
+/// Reads a user defined system from ten comma separated tags, ace first, e.g.
+/// "-1,1,1,1,1,1,0,0,0,-1".
+pub fn parse_tags(name: &str, tags_str: &str) -> CountSystem {
+    let tags: Vec<f32> = tags_str.split(',').map(|tag| tag.trim().parse().unwrap()).collect();
+    assert!(tags.len() == 10, "Expected ten tags, got {}", tags.len());
+    let mut system = system(name, [0.0; 10]);
+    system.tags.copy_from_slice(&tags);
+    system
+}
+
+/// One of the built in systems by name, or a user defined system if `arg`
+/// is a list of tags.
+pub fn parse_system(arg: &str) -> CountSystem {
+    systems()
+        .into_iter()
+        .find(|system| system.name.eq_ignore_ascii_case(arg))
+        .unwrap_or_else(|| parse_tags("Custom", arg))
+}
+
+#[test]
+fn test_parse_tags() {
+    assert!(systems()[0] == parse_tags("Hi-Lo", "-1,1,1,1,1,1,0,0,0,-1"));
+    assert!(0.5 == parse_tags("Halves", "-1, .5,1,1,1.5,1,.5,0,-.5,-1").tags[1]);
+    assert!("Zen" == parse_system("zen").name);
+    assert!(parse_tags("Custom", "0,0,0,0,0,0,0,0,0,1") == parse_system("0,0,0,0,0,0,0,0,0,1"));
+}
+
+#[test]
+#[should_panic]
+fn test_parse_too_few_tags() {
+    parse_tags("Short", "-1,1,1");
+}
+
+impl CountSystem {
+    /// Whether a full shoe counts out to zero.
+    pub fn is_balanced(&self) -> bool {
+        self.tags.iter().zip(SINGLE_DECK.iter()).map(|(tag, count)| tag * count).sum::<f32>() == 0.0
+    }
+
+    /// The count of the cards that have been dealt from `shoe`, leaving
+    /// `remaining`, which has to be out of `shoe`.
+    pub fn running_count(&self, shoe: &Deck, remaining: &Deck) -> f32 {
+        (1..11)
+            .map(|card| {
+                let seen = shoe.cards[card_index(card)] - remaining.cards[card_index(card)];
+                seen as f32 * self.tags[card_index(card)]
+            })
+            .sum()
+    }
+
+    /// The running count per deck left in `remaining`.
+    pub fn true_count(&self, shoe: &Deck, remaining: &Deck) -> f32 {
+        self.running_count(shoe, remaining) * 52.0 / remaining.size as f32
+    }
+}
+
+#[test]
+fn test_counts() {
+    let systems = systems();
+    let hi_lo = &systems[0];
+    let ko = &systems[1];
+    assert!(hi_lo.is_balanced());
+    assert!(!ko.is_balanced());
+    assert!(systems.iter().filter(|system| !system.is_balanced()).count() == 1);
+
+    let shoe = Deck {
+        cards: [8, 8, 8, 8, 8, 8, 8, 8, 8, 32],
+        size: 104,
+    };
+    let remaining = Deck {
+        cards: [8, 6, 7, 8, 5, 8, 7, 8, 8, 31],
+        size: 96,
+    };
+    assert!(5.0 == hi_lo.running_count(&shoe, &remaining));
+    assert!(6.0 == ko.running_count(&shoe, &remaining));
+    assert!((hi_lo.true_count(&shoe, &remaining) - 5.0 * 52.0 / 96.0).abs() < 1e-6);
+}
+
+// The correlation of the tags with a set of effects over the cards of a deck.
+fn correlation(tags: &[f32; 10], effects: &[f32; 10]) -> f32 {
+    let total: f32 = SINGLE_DECK.iter().sum();
+    let mean = |values: &[f32; 10]| {
+        values.iter().zip(SINGLE_DECK.iter()).map(|(value, count)| value * count).sum::<f32>() /
+        total
+    };
+    let (tag_mean, effect_mean) = (mean(tags), mean(effects));
+    let mut covariance = 0.0;
+    let mut tag_variance = 0.0;
+    let mut effect_variance = 0.0;
+    for i in 0..10 {
+        let tag = tags[i] - tag_mean;
+        let effect = effects[i] - effect_mean;
+        covariance += SINGLE_DECK[i] * tag * effect;
+        tag_variance += SINGLE_DECK[i] * tag * tag;
+        effect_variance += SINGLE_DECK[i] * effect * effect;
+    }
+    if tag_variance == 0.0 || effect_variance == 0.0 {
+        return 0.0;
+    }
+    covariance / (tag_variance * effect_variance).sqrt()
+}
+
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Metrics {
+    /// Betting correlation: how well the count tracks the player's edge.
+    pub betting_correlation: f32,
+    /// Playing efficiency: how well the count tracks the value of the playing
+    /// decisions, on average. A count that moves the wrong way for a decision
+    /// still tells us when to deviate, so the size of each correlation is
+    /// what counts.
+    pub playing_efficiency: f32,
+    /// Insurance correlation: how well the count tracks the insurance bet.
+    pub insurance_correlation: f32,
+}
+
+/// Scores `system` against the effects of removal for the game, the insurance
+/// bet and a set of playing decisions.
+pub fn metrics(system: &CountSystem,
+               game: &Effects,
+               insurance: &Effects,
+               decisions: &[Effects])
+               -> Metrics {
+    let playing_efficiency = if decisions.is_empty() {
+        0.0
+    } else {
+        decisions.iter()
+            .map(|decision| correlation(&system.tags, &decision.effects).abs())
+            .sum::<f32>() / decisions.len() as f32
+    };
+    Metrics {
+        betting_correlation: correlation(&system.tags, &game.effects),
+        playing_efficiency,
+        insurance_correlation: correlation(&system.tags, &insurance.effects),
+    }
+}
+
+#[test]
+fn test_metrics() {
+    use super::eor::insurance_effects;
+    // Single deck effects of removal, in percent, from the literature.
+    let game = Effects {
+        name: String::from("Game"),
+        base: 0.0,
+        effects: [-0.61, 0.38, 0.44, 0.55, 0.69, 0.46, 0.28, 0.0, -0.18, -0.51],
+    };
+    let insurance = insurance_effects(Deck {
+        cards: [3, 4, 4, 4, 4, 4, 4, 4, 4, 16],
+        size: 51,
+    });
+    let decisions = vec![Effects {
+        name: String::from("16 v 10 Stand over Hit"),
+        base: 0.0,
+        effects: [-0.1, -0.2, -0.2, -0.3, -0.4, 0.0, 0.1, 0.1, 0.1, 0.2],
+    }];
+    let systems = systems();
+    let hi_lo = metrics(&systems[0], &game, &insurance, &decisions);
+    assert!(hi_lo.betting_correlation > 0.96 && hi_lo.betting_correlation < 0.98);
+    assert!(hi_lo.insurance_correlation > 0.7 && hi_lo.insurance_correlation < 0.8);
+    assert!(hi_lo.playing_efficiency > 0.0 && hi_lo.playing_efficiency <= 1.0);
+
+    // A count of tens alone is perfect for insurance.
+    let tens = parse_tags("Tens", "1,1,1,1,1,1,1,1,1,-2.25");
+    assert!((metrics(&tens, &game, &insurance, &[]).insurance_correlation - 1.0).abs() < 1e-5);
+
+    let perfect = CountSystem {
+        name: String::from("Perfect"),
+        tags: decisions[0].effects,
+    };
+    assert!((metrics(&perfect, &game, &insurance, &decisions).playing_efficiency - 1.0).abs() < 1e-5);
+}
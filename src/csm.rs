@@ -0,0 +1,213 @@
+// Continuous shuffling machines. Instead of dealing a shoe down to a cut card,
+// the dealer loads each round's discards back into the machine, sometimes
+// only after a few more rounds, and the machine shuffles them in among the
+// cards it holds. The machine has already pushed out the next few cards to
+// be dealt, so returned cards can't land among them.
+//
+// The cards left to deal are then the full shoe less the discards waiting to
+// go back in and the ones on the table, so the composition moves only as far
+// as a few rounds of cards can move it. How far it moves is what's left for a
+// counter to track. If a long lag lets the machine run dry mid-round, the
+// dealer loads every waiting discard at once and carries on.
+//
+// Only simulations deal from a machine; the expectation engine still treats
+// every shoe as dealt down to its last card. What it can do is work out the
+// expectation or effects of removal of the deck a machine holds at a moment,
+// which `machine_deck` gives.
+
+use std::collections::VecDeque;
+use std::mem;
+
+use rand::{SeedableRng, StdRng};
+
+use super::{Action, Deck, GameState, OrderedDeck, Rules};
+use super::basic::basic_strategy;
+use super::counts::CountSystem;
+use super::round::play_round;
+use super::simulator::{Round, Summary};
+#[cfg(test)]
+use super::{DEFAULT_RULES, full_shoe};
+#[cfg(test)]
+use super::counts::systems;
+
+/// How a machine is loaded and dealt from.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Csm {
+    pub decks: u16,
+    /// How many rounds the discards of a round wait before going back in.
+    pub lag: usize,
+    /// How many cards the machine has pushed out ready to deal.
+    pub ahead: usize,
+}
+
+/// The cards in a machine loaded from `shoe`: the shoe less the `waiting`
+/// discards and the cards on the `table`. None if those couldn't all have
+/// come from the shoe.
+pub fn machine_deck(shoe: &Deck, waiting: &[u16], table: &[u16]) -> Option<Deck> {
+    let mut deck = *shoe;
+    for &card in waiting.iter().chain(table.iter()) {
+        if deck.cards[card as usize - 1] == 0 {
+            return None;
+        }
+        deck.draw(card);
+    }
+    Some(deck)
+}
+
+#[test]
+fn test_machine_deck() {
+    let shoe = full_shoe(1);
+    let deck = machine_deck(&shoe, &[10, 10, 5, 1], &[2, 3]).unwrap();
+    assert!(46 == deck.size && 14 == deck.cards[9] && 3 == deck.cards[0]);
+    assert!(Some(shoe) == machine_deck(&shoe, &[], &[]));
+    assert!(machine_deck(&shoe, &[1, 1, 1], &[1, 1]).is_none());
+}
+
+/// Deals `rounds` rounds from the machine, with `strategy` making the
+/// player's decisions, and passes each round to `record`. The same seed
+/// always deals the same rounds.
+pub fn simulate_csm<S, R>(csm: &Csm,
+                          rules: &Rules,
+                          rounds: usize,
+                          seed: usize,
+                          strategy: &S,
+                          mut record: R)
+    where S: Fn(&mut GameState) -> Action,
+          R: FnMut(Round)
+{
+    let seed: &[_] = &[seed];
+    let mut rng: StdRng = SeedableRng::from_seed(seed);
+    let mut machine = OrderedDeck::shuffled(csm.decks, &mut rng);
+    // The discards of each round that hasn't been loaded back in yet.
+    let mut waiting: VecDeque<Vec<u16>> = VecDeque::new();
+    for number in 0..rounds {
+        // The waiting discards are what the machine is refilled with when it
+        // runs dry, between rounds or during one.
+        let waiting_cards: usize = waiting.iter().map(Vec::len).sum();
+        machine.discards = waiting.iter().flatten().cloned().collect();
+        let shuffles = machine.shuffles;
+        if machine.cards.is_empty() {
+            machine.reshuffle(&mut rng);
+        }
+        let start = machine.deck;
+        let result = play_round(&mut machine, rules, strategy, &mut rng);
+        record(Round {
+            shoe: 0,
+            number,
+            start,
+            result,
+        });
+        let mut discards = mem::take(&mut machine.discards);
+        if machine.shuffles == shuffles {
+            discards.drain(..waiting_cards);
+        } else {
+            waiting.clear();
+        }
+        waiting.push_back(discards);
+        while waiting.len() > csm.lag {
+            let discards = waiting.pop_front().unwrap();
+            machine.insert(&discards, csm.ahead, &mut rng);
+        }
+    }
+}
+
+/// How a basic strategy player fares at a machine, and how much the cards
+/// left to deal vary.
+#[derive(Debug,PartialEq,Clone,Copy,Default)]
+pub struct Variability {
+    pub results: Summary,
+    /// The true count of the cards in the machine at the start of each round.
+    pub true_counts: Summary,
+    pub highest_true_count: f32,
+    /// How many rounds started at a true count of +1 or more.
+    pub favourable: usize,
+    /// How many cards were out of the machine at the start of each round.
+    pub missing: Summary,
+}
+
+/// Deals `rounds` rounds from the machine to a basic strategy player, and
+/// counts the cards left at the start of each with `system`.
+pub fn csm_variability(csm: &Csm,
+                       rules: &Rules,
+                       rounds: usize,
+                       seed: usize,
+                       system: &CountSystem)
+                       -> Variability {
+    let mut full = None;
+    let mut variability = Variability {
+        highest_true_count: f32::NEG_INFINITY,
+        ..Variability::default()
+    };
+    simulate_csm(csm, rules, rounds, seed, &basic_strategy, |round| {
+        let full = *full.get_or_insert(round.start);
+        let true_count = system.true_count(&full, &round.start);
+        variability.results.add(round.result);
+        variability.true_counts.add(true_count);
+        variability.highest_true_count = variability.highest_true_count.max(true_count);
+        if true_count >= 1.0 {
+            variability.favourable += 1;
+        }
+        variability.missing.add((full.size - round.start.size) as f32);
+    });
+    variability
+}
+
+#[test]
+fn test_csm_variability() {
+    let hi_lo = &systems()[0];
+    let variability = |lag: usize| {
+        let csm = Csm {
+            decks: 6,
+            lag,
+            ahead: 10,
+        };
+        csm_variability(&csm, &DEFAULT_RULES, 2000, 9, hi_lo)
+    };
+    // Discards that go straight back leave the machine full every round.
+    let immediate = variability(0);
+    assert!(2000 == immediate.results.rounds);
+    assert!(0.0 == immediate.missing.mean() && 0.0 == immediate.true_counts.variance());
+    assert!(0.0 == immediate.highest_true_count && 0 == immediate.favourable);
+
+    let lagged = variability(3);
+    assert!(lagged.missing.mean() > 3.0 * 4.0 && lagged.missing.mean() < 3.0 * 12.0);
+    // Three rounds of cards only move the true count a little.
+    let spread = lagged.true_counts.variance().sqrt();
+    assert!(spread > 0.3 && spread < 1.0);
+    assert!(lagged.true_counts.mean().abs() < 0.1);
+    assert!(lagged.highest_true_count > 1.0 && lagged.highest_true_count < 4.0);
+    assert!(lagged.favourable > 0 && lagged.favourable < 2000 / 5);
+    assert!(lagged == variability(3));
+}
+
+#[test]
+fn test_simulate_csm() {
+    let csm = Csm {
+        decks: 1,
+        lag: 1,
+        ahead: 0,
+    };
+    let mut rounds = vec![];
+    simulate_csm(&csm, &DEFAULT_RULES, 300, 4, &basic_strategy, |round| rounds.push(round));
+    assert!(300 == rounds.len() && 52 == rounds[0].start.size);
+    for (number, round) in rounds.iter().enumerate() {
+        assert!(number == round.number && 0 == round.shoe);
+        assert!(round.start.cards.iter().sum::<u16>() == round.start.size);
+    }
+    // Only the last round's cards are ever out of the machine.
+    assert!(rounds[1..].iter().all(|round| round.start.size < 52 && round.start.size >= 52 - 20));
+
+    // A lag this long on one deck runs the machine dry, and the waiting
+    // discards are loaded back in to finish the round.
+    let csm = Csm {
+        decks: 1,
+        lag: 12,
+        ahead: 1,
+    };
+    let mut rounds = vec![];
+    simulate_csm(&csm, &DEFAULT_RULES, 2000, 1, &basic_strategy, |round| rounds.push(round));
+    assert!(2000 == rounds.len());
+    assert!(rounds.iter().all(|round| round.start.size > 0 && round.start.size <= 52));
+    // A refill brings back many rounds of cards at once.
+    assert!(rounds.windows(2).any(|pair| pair[1].start.size > pair[0].start.size + 30));
+}
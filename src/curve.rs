@@ -0,0 +1,119 @@
+// The player's advantage by true count. Shoes are dealt at random to depths
+// up to the cut card, binned by the true count for a count system, and the
+// advantage of each is computed or estimated. The frequency, mean and spread
+// of the advantage in each bin is what bet ramps are built from.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use csv;
+use rand::{Rng, SeedableRng, StdRng};
+
+use super::Deck;
+use super::counts::CountSystem;
+#[cfg(test)]
+use super::FULL_DECK;
+
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Bin {
+    /// The true count, rounded to the nearest whole count.
+    pub true_count: i32,
+    /// The share of sampled shoes in the bin.
+    pub frequency: f32,
+    pub mean_advantage: f32,
+    pub std_dev: f32,
+}
+
+/// Deals `samples` shoes from `shoe`, each to a depth chosen evenly between
+/// none and `penetration` of the shoe, and bins their advantages by true
+/// count. The same seed always deals the same shoes.
+pub fn advantage_curve<F>(shoe: &Deck,
+                          system: &CountSystem,
+                          samples: usize,
+                          penetration: f32,
+                          seed: usize,
+                          advantage: F)
+                          -> Vec<Bin>
+    where F: Fn(&Deck) -> f32
+{
+    let seed: &[_] = &[seed];
+    let mut rng: StdRng = SeedableRng::from_seed(seed);
+    let max_depth = (penetration * shoe.size as f32) as u16;
+    // The number of shoes, and the sums of their advantages and squared
+    // advantages, by true count.
+    let mut sums: BTreeMap<i32, (f64, f64, f64)> = BTreeMap::new();
+    for _ in 0..samples {
+        let mut deck = *shoe;
+        for _ in 0..rng.gen_range(0, max_depth + 1) {
+            deck.draw_random(&mut rng);
+        }
+        let true_count = system.true_count(shoe, &deck).round() as i32;
+        let advantage = advantage(&deck) as f64;
+        let sum = sums.entry(true_count).or_insert((0.0, 0.0, 0.0));
+        sum.0 += 1.0;
+        sum.1 += advantage;
+        sum.2 += advantage * advantage;
+    }
+    sums.into_iter()
+        .map(|(true_count, (count, total, squares))| {
+            let mean = total / count;
+            Bin {
+                true_count,
+                frequency: (count / samples as f64) as f32,
+                mean_advantage: mean as f32,
+                std_dev: (squares / count - mean * mean).max(0.0).sqrt() as f32,
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_advantage_curve() {
+    let hi_lo = &super::counts::systems()[0];
+    let advantage = |deck: &Deck| 0.005 * hi_lo.true_count(&FULL_DECK, deck);
+    let bins = advantage_curve(&FULL_DECK, hi_lo, 500, 0.75, 7, advantage);
+    assert!((bins.iter().map(|bin| bin.frequency).sum::<f32>() - 1.0).abs() < 1e-5);
+    for pair in bins.windows(2) {
+        assert!(pair[0].true_count < pair[1].true_count);
+    }
+    for bin in bins.iter() {
+        assert!((bin.mean_advantage - 0.005 * bin.true_count as f32).abs() <= 0.0025 + 1e-6);
+        assert!(bin.std_dev <= 0.0025);
+    }
+    let zero = bins.iter().find(|bin| bin.true_count == 0).unwrap();
+    assert!(bins.iter().all(|bin| bin.frequency <= zero.frequency));
+    assert!(bins == advantage_curve(&FULL_DECK, hi_lo, 500, 0.75, 7, advantage));
+}
+
+/// The curve as a table with columns true_count, frequency, mean_advantage and
+/// std_dev.
+pub fn curve_csv(bins: &[Bin]) -> Result<String, Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(["true_count", "frequency", "mean_advantage", "std_dev"])?;
+    for bin in bins {
+        writer.write_record(&[bin.true_count.to_string(),
+                              bin.frequency.to_string(),
+                              bin.mean_advantage.to_string(),
+                              bin.std_dev.to_string()])?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+#[test]
+fn test_curve_csv() {
+    let csv = curve_csv(&[Bin {
+                              true_count: -1,
+                              frequency: 0.25,
+                              mean_advantage: -0.01,
+                              std_dev: 0.005,
+                          },
+                          Bin {
+                              true_count: 2,
+                              frequency: 0.75,
+                              mean_advantage: 0.005,
+                              std_dev: 0.0,
+                          }])
+        .unwrap();
+    assert!("true_count,frequency,mean_advantage,std_dev\n-1,0.25,-0.01,0.005\n2,0.75,0.005,0\n" ==
+            csv);
+}
@@ -0,0 +1,184 @@
+// The cut card effect. Shoes are reshuffled at the cut card rather than after
+// a fixed number of rounds, so rich shoes, which use fewer cards a round, get
+// more rounds dealt from them than poor ones, and vice versa. The expectation
+// per round over whole shoes is then not the full shoe's expectation. Whole
+// shoes are simulated and the results broken down by round number.
+
+use std::error::Error;
+
+use csv;
+
+use super::{Deck, Rules};
+use super::basic::basic_strategy;
+use super::simulator::{Shoe, Summary, simulate};
+#[cfg(test)]
+use super::DEFAULT_RULES;
+#[cfg(test)]
+use super::simulator::Cut;
+
+/// The rounds dealt at one round number, or at all of them.
+#[derive(Debug,PartialEq,Clone,Copy,Default)]
+pub struct Rounds {
+    /// The simulated results of a basic strategy player.
+    pub results: Summary,
+    /// The advantages of the shoes the rounds were dealt from, if they're
+    /// being worked out.
+    pub advantages: Summary,
+}
+
+impl Rounds {
+    fn add(&mut self, result: f32, advantage: Option<f32>) {
+        self.results.add(result);
+        if let Some(advantage) = advantage {
+            self.advantages.add(advantage);
+        }
+    }
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct CutCardEffect {
+    pub shoes: usize,
+    pub overall: Rounds,
+    /// The rounds by their number within the shoe, from the first.
+    pub by_round: Vec<Rounds>,
+}
+
+impl CutCardEffect {
+    pub fn rounds_per_shoe(&self) -> f64 {
+        self.overall.results.rounds as f64 / self.shoes as f64
+    }
+
+    /// How much the expectation per round over whole shoes differs from the
+    /// expectation of the first round, which is dealt from a full shoe. Taken
+    /// from the advantages if they were worked out, as they're far less noisy
+    /// than the results.
+    pub fn effect(&self) -> f64 {
+        if self.overall.advantages.rounds > 0 {
+            self.overall.advantages.mean() - self.by_round[0].advantages.mean()
+        } else {
+            self.overall.results.mean() - self.by_round[0].results.mean()
+        }
+    }
+}
+
+/// Deals `shoes` shoes with a basic strategy player, and breaks the results
+/// down by round number. If `advantage` is given, it's worked out for the shoe
+/// at the start of every round too, which shows the effect with less noise.
+pub fn cut_card_effect(shoe: &Shoe,
+                       rules: &Rules,
+                       shoes: usize,
+                       seed: usize,
+                       advantage: Option<&dyn Fn(&Deck) -> f32>)
+                       -> CutCardEffect {
+    let mut effect = CutCardEffect {
+        shoes,
+        overall: Rounds::default(),
+        by_round: vec![],
+    };
+    simulate(shoe, rules, shoes, seed, &basic_strategy, |round| {
+        let start_advantage = advantage.map(|advantage| advantage(&round.start));
+        if effect.by_round.len() <= round.number {
+            effect.by_round.push(Rounds::default());
+        }
+        effect.by_round[round.number].add(round.result, start_advantage);
+        effect.overall.add(round.result, start_advantage);
+    });
+    effect
+}
+
+#[test]
+fn test_cut_card_effect() {
+    let shoe = Shoe {
+        decks: 2,
+        cut: Cut::Penetration(0.75),
+        burn: 1,
+    };
+    let size = |deck: &Deck| deck.size as f32;
+    let effect = cut_card_effect(&shoe, &DEFAULT_RULES, 200, 3, Some(&size));
+    assert!(200 == effect.by_round[0].results.rounds);
+    assert!(104.0 == effect.by_round[0].advantages.mean());
+    for pair in effect.by_round.windows(2) {
+        assert!(pair[1].results.rounds <= pair[0].results.rounds);
+        assert!(pair[1].advantages.mean() < pair[0].advantages.mean());
+    }
+    let rounds: usize = effect.by_round.iter().map(|rounds| rounds.results.rounds).sum();
+    assert!(rounds == effect.overall.results.rounds);
+    assert!(rounds == effect.overall.advantages.rounds);
+    assert!(effect.rounds_per_shoe() > 10.0 && effect.rounds_per_shoe() < 40.0);
+    assert!(effect.overall.advantages.mean() < 104.0 && effect.overall.advantages.mean() > 26.0);
+    assert!(effect.effect().is_finite());
+
+    let simulated = cut_card_effect(&shoe, &DEFAULT_RULES, 200, 3, None);
+    assert!(effect.overall.results == simulated.overall.results);
+    assert!(0 == simulated.overall.advantages.rounds);
+}
+
+/// The breakdown as a table with columns round, shoes (how many shoes reached
+/// the round), mean_result and std_error, and mean_advantage if advantages
+/// were worked out. The first row is every round together, with every shoe
+/// dealt.
+pub fn cut_card_csv(effect: &CutCardEffect) -> Result<String, Box<dyn Error>> {
+    let with_advantages = effect.overall.advantages.rounds > 0;
+    let mut writer = csv::Writer::from_writer(vec![]);
+    let mut header = vec!["round", "shoes", "mean_result", "std_error"];
+    if with_advantages {
+        header.push("mean_advantage");
+    }
+    writer.write_record(&header)?;
+    // Every shoe reaches round one, so the number of rounds at a round number
+    // is the number of shoes that reached it.
+    let rows = Some((String::from("all"), effect.shoes, &effect.overall))
+        .into_iter()
+        .chain(effect.by_round.iter().enumerate().map(|(number, rounds)| {
+            ((number + 1).to_string(), rounds.results.rounds, rounds)
+        }));
+    for (round, shoes, rounds) in rows {
+        let mut record = vec![round,
+                              shoes.to_string(),
+                              rounds.results.mean().to_string(),
+                              rounds.results.std_error().to_string()];
+        if with_advantages {
+            record.push(rounds.advantages.mean().to_string());
+        }
+        writer.write_record(&record)?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+#[test]
+fn test_cut_card_csv() {
+    let mut first = Rounds::default();
+    first.add(1.0, Some(0.25));
+    first.add(-1.0, Some(0.25));
+    let mut second = Rounds::default();
+    second.add(0.5, Some(0.5));
+    let mut overall = first;
+    overall.add(0.5, Some(0.5));
+    let effect = CutCardEffect {
+        shoes: 2,
+        overall,
+        by_round: vec![first, second],
+    };
+    assert!(1.5 == effect.rounds_per_shoe());
+    assert!((effect.effect() - (1.0 / 3.0 - 0.25)).abs() < 1e-12);
+    let csv = cut_card_csv(&effect).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert!("round,shoes,mean_result,std_error,mean_advantage" == lines[0]);
+    assert!(lines[1].starts_with("all,2,"));
+    assert!("1,2,0,0.7071067811865476,0.25" == lines[2]);
+    assert!("2,1,0.5,0,0.5" == lines[3]);
+
+    // Without advantages the effect comes from the results.
+    let simulated = CutCardEffect {
+        shoes: 2,
+        overall: Rounds {
+            advantages: Summary::default(),
+            ..effect.overall
+        },
+        by_round: vec![Rounds {
+                           advantages: Summary::default(),
+                           ..first
+                       }],
+    };
+    assert!((simulated.effect() - 0.5 / 3.0).abs() < 1e-12);
+}
@@ -0,0 +1,382 @@
+// The advantage dataset format. A data file starts with comment lines saying
+// what its results were computed with, then a CSV table of the count of each
+// rank, the advantage and how long it took to compute:
+//
+//   # blackjack_sim advantage data
+//   # version=1
+//   # rules=s17,ls,das,3:2
+//   # decks=8
+//   # engine=0.1.0
+//   # precision=f32
+//   A,2,3,4,5,6,7,8,9,10,advantage,seconds
+//   32,32,32,32,32,32,32,32,32,128,-0.0045,2712
+//
+// Files from before the format had a header are read as legacy data, and
+// `migrate` gives them one.
+
+use std::collections::HashSet;
+use std::error::Error;
+#[cfg(test)]
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use csv;
+
+use super::{Deck, Rules, full_shoe, try_parse_rules};
+#[cfg(test)]
+use super::{DEFAULT_RULES, FULL_DECK};
+#[cfg(test)]
+use super::queue::test_dir;
+
+/// The version of the format written by this build.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const MARKER: &str = "# blackjack_sim advantage data";
+
+/// The columns of the table.
+pub const COLUMNS: [&str; 12] = ["A", "2", "3", "4", "5", "6", "7", "8", "9", "10", "advantage",
+                                 "seconds"];
+
+/// What a dataset was computed with. Results can only be combined when all of
+/// it matches.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Metadata {
+    pub version: u32,
+    pub rules: Rules,
+    /// The number of decks in the shoe the samples were dealt from.
+    pub decks: u16,
+    /// The version of the engine that computed the advantages.
+    pub engine: String,
+    /// The type advantages were computed in.
+    pub precision: String,
+}
+
+impl Metadata {
+    /// Metadata for results computed by this build.
+    pub fn current(rules: &Rules, decks: u16) -> Metadata {
+        Metadata {
+            version: SCHEMA_VERSION,
+            rules: *rules,
+            decks,
+            engine: env!("CARGO_PKG_VERSION").to_string(),
+            precision: "f32".to_string(),
+        }
+    }
+
+    /// The comment lines and column names a data file starts with.
+    pub fn header(&self) -> String {
+        format!("{}\n# version={}\n# rules={}\n# decks={}\n# engine={}\n# precision={}\n{}\n",
+                MARKER,
+                self.version,
+                self.rules,
+                self.decks,
+                self.engine,
+                self.precision,
+                COLUMNS.join(","))
+    }
+
+    /// An error naming the first difference that makes results computed with
+    /// `other` unfit to mix with these.
+    pub fn check_compatible(&self, other: &Metadata) -> Result<(), Box<dyn Error>> {
+        let differences = [("version", self.version.to_string(), other.version.to_string()),
+                           ("rules", self.rules.to_string(), other.rules.to_string()),
+                           ("decks", self.decks.to_string(), other.decks.to_string()),
+                           ("engine", self.engine.clone(), other.engine.clone()),
+                           ("precision", self.precision.clone(), other.precision.clone())];
+        for &(name, ref ours, ref theirs) in differences.iter() {
+            if ours != theirs {
+                return Err(format!("Incompatible datasets: {} {} and {}", name, ours, theirs)
+                    .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The metadata at the start of a data file's contents, or None for legacy data.
+pub fn parse_metadata(contents: &str) -> Result<Option<Metadata>, Box<dyn Error>> {
+    let mut lines = contents.lines();
+    if lines.next() != Some(MARKER) {
+        return Ok(None);
+    }
+    let mut version = None;
+    let mut rules = None;
+    let mut decks = None;
+    let mut engine = None;
+    let mut precision = None;
+    for line in lines.take_while(|line| line.starts_with('#')) {
+        let (key, value) = match line.trim_start_matches('#').trim().split_once('=') {
+            Some(field) => field,
+            None => continue,
+        };
+        match key {
+            "version" => version = Some(value.parse::<u32>()?),
+            "rules" => rules = Some(try_parse_rules(value)?),
+            "decks" => decks = Some(value.parse::<u16>()?),
+            "engine" => engine = Some(value.to_string()),
+            "precision" => precision = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    let version = version.ok_or("The dataset has no version")?;
+    if version > SCHEMA_VERSION {
+        return Err(format!("The dataset is version {}, newer than this build's {}",
+                           version,
+                           SCHEMA_VERSION)
+            .into());
+    }
+    Ok(Some(Metadata {
+        version,
+        rules: rules.ok_or("The dataset has no rules")?,
+        decks: decks.ok_or("The dataset has no deck count")?,
+        engine: engine.ok_or("The dataset has no engine version")?,
+        precision: precision.ok_or("The dataset has no precision")?,
+    }))
+}
+
+/// The metadata a data file starts with, or None for legacy data without it.
+pub fn read_metadata(path: &Path) -> Result<Option<Metadata>, Box<dyn Error>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    parse_metadata(&contents)
+}
+
+/// The full shoe the decks in a data file were dealt from, as its header says,
+/// or a shoe of `legacy_decks` decks for legacy data.
+pub fn read_shoe(path: &Path, legacy_decks: u16) -> Result<Deck, Box<dyn Error>> {
+    let decks = read_metadata(path)?.map_or(legacy_decks, |metadata| metadata.decks);
+    Ok(full_shoe(decks))
+}
+
+/// The count of each rank from ace to ten, separated by commas.
+pub fn format_counts(deck: &Deck) -> String {
+    deck.cards.iter().map(|count| count.to_string()).collect::<Vec<_>>().join(",")
+}
+
+/// Reads counts written by `format_counts`.
+pub fn parse_counts(counts: &str) -> Result<Deck, Box<dyn Error>> {
+    let counts: Vec<u16> =
+        counts.split(',').map(|count| count.parse::<u16>()).collect::<Result<_, _>>()?;
+    if counts.len() != 10 {
+        return Err(format!("Expected 10 rank counts, got {}", counts.len()).into());
+    }
+    let mut cards = [0; 10];
+    cards.copy_from_slice(&counts);
+    Ok(Deck {
+        cards,
+        size: cards.iter().sum(),
+    })
+}
+
+#[test]
+fn test_parse_counts() {
+    let deck = parse_counts(&format_counts(&FULL_DECK)).unwrap();
+    assert!(FULL_DECK == deck);
+    assert!(parse_counts("1,2,3").is_err());
+    assert!(parse_counts("1,2,3,4,5,6,7,8,9,x").is_err());
+}
+
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Row {
+    pub deck: Deck,
+    pub advantage: f32,
+    /// How long the advantage took to compute, unknown for legacy data.
+    pub seconds: Option<f32>,
+}
+
+fn parse_row(record: &csv::StringRecord) -> Option<Row> {
+    let mut cards = [0; 10];
+    for (count, field) in cards.iter_mut().zip(record.iter()) {
+        *count = field.parse::<u16>().ok()?;
+    }
+    let advantage = record.get(10)?.parse::<f32>().ok()?;
+    let seconds = match record.get(11) {
+        Some("") | None => None,
+        Some(seconds) => Some(seconds.parse::<f32>().ok()?),
+    };
+    Some(Row {
+        deck: Deck {
+            cards,
+            size: cards.iter().sum(),
+        },
+        advantage,
+        seconds,
+    })
+}
+
+/// The rows of a data file, legacy or not. Rows that don't parse, such as a
+/// line cut short by a crash, are skipped, and only the first row for a deck
+/// is kept.
+pub fn read_rows(path: &Path) -> Result<Vec<Row>, Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).comment(Some(b'#')).from_path(path)?;
+    let mut seen = HashSet::new();
+    let mut rows = vec![];
+    for record in reader.records() {
+        if let Some(row) = record.ok().as_ref().and_then(parse_row) {
+            if seen.insert(row.deck) {
+                rows.push(row);
+            }
+        }
+    }
+    Ok(rows)
+}
+
+/// The advantage of each deck in a data file.
+pub fn read_data(path: &Path) -> Result<Vec<(Deck, f32)>, Box<dyn Error>> {
+    Ok(read_rows(path)?.into_iter().map(|row| (row.deck, row.advantage)).collect())
+}
+
+/// A row as a line of the table.
+pub fn row_line(row: &Row) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    let mut record: Vec<String> = row.deck.cards.iter().map(|count| count.to_string()).collect();
+    record.push(row.advantage.to_string());
+    record.push(row.seconds.map_or(String::new(), |seconds| seconds.to_string()));
+    writer.write_record(&record)?;
+    Ok(writer.into_inner()?)
+}
+
+/// Makes `path` ready for results computed with `metadata`: creates it with
+/// its header if it doesn't exist, and otherwise checks it holds compatible
+/// results and ends a line left partial by a crash, so the next row starts on
+/// a line of its own.
+pub fn prepare(path: &Path, metadata: &Metadata) -> Result<(), Box<dyn Error>> {
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(mut file) => {
+            file.write_all(metadata.header().as_bytes())?;
+            file.sync_all()?;
+            return Ok(());
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err.into()),
+    }
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    match parse_metadata(&contents)? {
+        Some(existing) => existing.check_compatible(metadata)?,
+        None => {
+            return Err(format!("{} has no header, migrate it first", path.display()).into())
+        }
+    }
+    if !contents.ends_with('\n') {
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Writes a new data file, refusing to replace one that exists.
+pub fn write_dataset(path: &Path, metadata: &Metadata, rows: &[Row]) -> Result<(), Box<dyn Error>> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.write_all(metadata.header().as_bytes())?;
+    for row in rows {
+        file.write_all(&row_line(row)?)?;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Copies the legacy data in `old` to `new` with a header saying it was
+/// computed with `metadata`, and returns how many rows it kept.
+pub fn migrate(old: &Path, new: &Path, metadata: &Metadata) -> Result<usize, Box<dyn Error>> {
+    if read_metadata(old)?.is_some() {
+        return Err(format!("{} already has a header", old.display()).into());
+    }
+    let rows = read_rows(old)?;
+    write_dataset(new, metadata, &rows)?;
+    Ok(rows.len())
+}
+
+/// The rows of every input, each deck once, if they were all computed with
+/// the same metadata.
+pub fn merge(inputs: &[&Path]) -> Result<(Metadata, Vec<Row>), Box<dyn Error>> {
+    let mut merged: Option<Metadata> = None;
+    let mut seen = HashSet::new();
+    let mut rows = vec![];
+    for &input in inputs {
+        let metadata = read_metadata(input)?
+            .ok_or_else(|| format!("{} has no header, migrate it first", input.display()))?;
+        match merged {
+            Some(ref merged) => merged.check_compatible(&metadata)?,
+            None => merged = Some(metadata),
+        }
+        for row in read_rows(input)? {
+            if seen.insert(row.deck) {
+                rows.push(row);
+            }
+        }
+    }
+    Ok((merged.ok_or("Nothing to merge")?, rows))
+}
+
+#[test]
+fn test_metadata() {
+    let metadata = Metadata::current(&super::parse_rules("h17,6:5"), 6);
+    let header = metadata.header();
+    assert!(header.starts_with("# blackjack_sim advantage data\n# version=1\n\
+                                # rules=h17,ls,das,6:5\n"));
+    assert!(header.ends_with("\nA,2,3,4,5,6,7,8,9,10,advantage,seconds\n"));
+    assert!(Some(metadata.clone()) == parse_metadata(&header).unwrap());
+    assert!(parse_metadata("A,2,3,4,5,6,7,8,9,10,advantage\n").unwrap().is_none());
+    let newer = header.replace("version=1", "version=2");
+    assert!(parse_metadata(&newer).is_err());
+    let unknown_rule = header.replace("rules=h17,ls,das,6:5", "rules=h17,rsa");
+    assert!(parse_metadata(&unknown_rule).is_err());
+
+    assert!(metadata.check_compatible(&metadata.clone()).is_ok());
+    let other_rules = Metadata { rules: DEFAULT_RULES, ..metadata.clone() };
+    assert!(metadata.check_compatible(&other_rules).is_err());
+    let other_engine = Metadata { engine: "0.0.1".to_string(), ..metadata.clone() };
+    assert!(metadata.check_compatible(&other_engine).is_err());
+}
+
+#[test]
+fn test_migrate_and_merge() {
+    let dir = test_dir("dataset");
+    let legacy = dir.join("legacy.csv");
+    // A duplicate, then a line cut short by a crash.
+    fs::write(&legacy,
+              "A,2,3,4,5,6,7,8,9,10,advantage\n32,32,32,32,32,32,32,32,32,128,0.5\n\
+               32,32,32,32,32,32,32,32,32,128,0.25\n31,32,32,32,32,32,32,32")
+        .unwrap();
+    let full = Row {
+        deck: FULL_DECK,
+        advantage: 0.5,
+        seconds: None,
+    };
+    assert!(vec![full] == read_rows(&legacy).unwrap());
+    let metadata = Metadata::current(&DEFAULT_RULES, 8);
+    assert!(prepare(&legacy, &metadata).is_err());
+
+    let migrated = dir.join("migrated.csv");
+    assert!(1 == migrate(&legacy, &migrated, &metadata).unwrap());
+    assert!(Some(metadata.clone()) == read_metadata(&migrated).unwrap());
+    assert!(vec![full] == read_rows(&migrated).unwrap());
+    assert!(migrate(&migrated, &dir.join("again.csv"), &metadata).is_err());
+    assert!(migrate(&legacy, &migrated, &metadata).is_err());
+    assert!(full_shoe(6) == read_shoe(&legacy, 6).unwrap());
+    assert!(FULL_DECK == read_shoe(&migrated, 6).unwrap());
+
+    let mut less_an_ace = FULL_DECK;
+    less_an_ace.draw(1);
+    let timed = Row {
+        deck: less_an_ace,
+        advantage: -0.25,
+        seconds: Some(12.5),
+    };
+    let new = dir.join("new.csv");
+    write_dataset(&new, &metadata, &[timed, Row { advantage: 0.0, ..full }]).unwrap();
+    assert!(vec![timed, Row { advantage: 0.0, ..full }] == read_rows(&new).unwrap());
+    let (merged, rows) = merge(&[&migrated, &new]).unwrap();
+    assert!(metadata == merged);
+    assert!(vec![full, timed] == rows);
+
+    let h17 = dir.join("h17.csv");
+    write_dataset(&h17, &Metadata::current(&super::parse_rules("h17"), 8), &[timed]).unwrap();
+    assert!(merge(&[&migrated, &h17]).is_err());
+    assert!(merge(&[&migrated, &legacy]).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,127 @@
+// Compares the strategy charts for two shoes or two rule sets, listing the
+// cells whose best action changes.
+
+use std::cmp::Ordering;
+
+use super::{Action, Deck, Rules};
+use super::strategy::{HandKind, StrategyChart, strategy_chart};
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct CellChange {
+    pub hand: HandKind,
+    pub upcard: u16,
+    pub before: Action,
+    pub after: Action,
+    /// The change in the best expectation from the first chart to the second.
+    pub expectation_delta: f32,
+    /// What playing the new action gains over the old one under the second
+    /// chart's conditions. None if the old action isn't allowed any more.
+    pub gain: Option<f32>,
+}
+
+// Changes that are forced by the rules come first, then the rest from the
+// most to the least valuable.
+fn by_impact(a: &CellChange, b: &CellChange) -> Ordering {
+    match (a.gain, b.gain) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+        (Some(a), Some(b)) => b.partial_cmp(&a).unwrap_or(Ordering::Equal),
+    }
+}
+
+/// The cells present in both charts whose best action differs, sorted by
+/// impact.
+pub fn chart_diff(before: &StrategyChart, after: &StrategyChart) -> Vec<CellChange> {
+    let mut changes = vec![];
+    for old in before.cells.iter() {
+        let new = match after.cell(old.hand, old.upcard) {
+            Some(new) if new.report.best != old.report.best => new,
+            _ => continue,
+        };
+        changes.push(CellChange {
+            hand: old.hand,
+            upcard: old.upcard,
+            before: old.report.best,
+            after: new.report.best,
+            expectation_delta: new.report.best_expectation() - old.report.best_expectation(),
+            gain: new.report
+                .expectation(old.report.best)
+                .map(|exp| new.report.best_expectation() - exp),
+        });
+    }
+    changes.sort_by(by_impact);
+    changes
+}
+
+/// Generates a chart for each deck and rule set and compares them, with both
+/// decks dealt from `shoe`.
+pub fn strategy_diff(shoe: &Deck,
+                     before_deck: Deck,
+                     before_rules: Rules,
+                     after_deck: Deck,
+                     after_rules: Rules)
+                     -> Vec<CellChange> {
+    chart_diff(&strategy_chart(shoe, before_deck, before_rules),
+               &strategy_chart(shoe, after_deck, after_rules))
+}
+
+// A hand, an upcard and the expectation of each action.
+#[cfg(test)]
+type TestCell = (HandKind, u16, Vec<(Action, f32)>);
+
+#[cfg(test)]
+fn test_chart(cells: Vec<TestCell>) -> StrategyChart {
+    use super::{ActionReport, DEFAULT_RULES, FULL_DECK};
+    use super::strategy::ChartCell;
+    StrategyChart {
+        deck: FULL_DECK,
+        rules: DEFAULT_RULES,
+        cells: cells.into_iter()
+            .map(|(hand, upcard, expectations)| {
+                ChartCell {
+                    hand,
+                    upcard,
+                    report: ActionReport::new(expectations),
+                }
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_chart_diff() {
+    let before = test_chart(vec![(HandKind::Hard(16),
+                                  10,
+                                  vec![(Action::Stand, -0.54),
+                                       (Action::Hit, -0.55),
+                                       (Action::Surrender, -0.5)]),
+                                 (HandKind::Hard(12),
+                                  4,
+                                  vec![(Action::Stand, -0.21), (Action::Hit, -0.20)]),
+                                 (HandKind::Hard(11),
+                                  10,
+                                  vec![(Action::Hit, 0.12), (Action::Double, 0.10)]),
+                                 (HandKind::Hard(10), 9, vec![(Action::Double, 0.2)])]);
+    let after = test_chart(vec![(HandKind::Hard(16),
+                                 10,
+                                 vec![(Action::Stand, -0.52), (Action::Hit, -0.53)]),
+                                (HandKind::Hard(12),
+                                 4,
+                                 vec![(Action::Stand, -0.19), (Action::Hit, -0.20)]),
+                                (HandKind::Hard(11),
+                                 10,
+                                 vec![(Action::Hit, 0.12), (Action::Double, 0.18)]),
+                                (HandKind::Hard(9), 9, vec![(Action::Hit, 0.1)])]);
+    let changes = chart_diff(&before, &after);
+    let cells: Vec<(HandKind, u16, Action, Action)> = changes.iter()
+        .map(|change| (change.hand, change.upcard, change.before, change.after))
+        .collect();
+    assert!(vec![(HandKind::Hard(16), 10, Action::Surrender, Action::Stand),
+                 (HandKind::Hard(11), 10, Action::Hit, Action::Double),
+                 (HandKind::Hard(12), 4, Action::Hit, Action::Stand)] == cells);
+    assert!(changes[0].gain.is_none());
+    assert!((changes[0].expectation_delta + 0.02).abs() < 1e-6);
+    assert!((changes[1].gain.unwrap() - 0.06).abs() < 1e-6);
+    assert!((changes[2].gain.unwrap() - 0.01).abs() < 1e-6);
+}
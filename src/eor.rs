@@ -0,0 +1,320 @@
+// Effects of removal: how the player's edge, and the value of individual
+// playing decisions, move as a card of each rank leaves the shoe. These are
+// what count systems are built from.
+
+use std::error::Error;
+use std::fmt;
+use std::thread;
+
+use csv;
+
+use super::{Action, Deck, GameState, Rules, action_expectations, card_index, deck_expectation,
+            hand_value};
+#[cfg(test)]
+use super::{DEFAULT_RULES, parse_rules};
+
+/// A playing decision, valued as the expectation of `first` less that of
+/// `second`, e.g. standing rather than hitting 10,6 against a 10.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Decision {
+    pub player: Vec<u16>,
+    pub upcard: u16,
+    pub first: Action,
+    pub second: Action,
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let upcard = if self.upcard == 1 {
+            String::from("A")
+        } else {
+            self.upcard.to_string()
+        };
+        write!(f,
+               "{} v {} {} over {}",
+               hand_value(&self.player),
+               upcard,
+               self.first,
+               self.second)
+    }
+}
+
+impl Decision {
+    /// Whether both actions can be taken on the decision's first two cards
+    /// under `rules`.
+    pub fn is_allowed(&self, rules: &Rules) -> bool {
+        let allowed = |action| match action {
+            Action::Surrender => rules.surrender,
+            Action::Split => self.player.len() == 2 && self.player[0] == self.player[1],
+            Action::Insurance => self.upcard == 1,
+            Action::Stand | Action::Hit | Action::Double => true,
+        };
+        allowed(self.first) && allowed(self.second)
+    }
+}
+
+fn decision(player: [u16; 2], upcard: u16, first: Action, second: Action) -> Decision {
+    Decision {
+        player: player.to_vec(),
+        upcard,
+        first,
+        second,
+    }
+}
+
+/// The close calls that count systems are usually judged on.
+pub fn standard_decisions() -> Vec<Decision> {
+    vec![decision([10, 6], 10, Action::Stand, Action::Hit),
+         decision([10, 5], 10, Action::Stand, Action::Hit),
+         decision([10, 6], 9, Action::Stand, Action::Hit),
+         decision([10, 2], 2, Action::Stand, Action::Hit),
+         decision([10, 2], 3, Action::Stand, Action::Hit),
+         decision([10, 2], 4, Action::Stand, Action::Hit),
+         decision([10, 3], 2, Action::Stand, Action::Hit),
+         decision([6, 5], 1, Action::Double, Action::Hit),
+         decision([6, 4], 10, Action::Double, Action::Hit),
+         decision([6, 4], 1, Action::Double, Action::Hit),
+         decision([5, 4], 2, Action::Double, Action::Hit),
+         decision([5, 4], 7, Action::Double, Action::Hit),
+         decision([10, 5], 10, Action::Surrender, Action::Hit)]
+}
+
+/// The effect on some quantity of removing one card of each rank, indexed like
+/// `Deck::cards`. Ranks the shoe has run out of have no effect.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Effects {
+    pub name: String,
+    /// The quantity for the shoe as given.
+    pub base: f32,
+    pub effects: [f32; 10],
+}
+
+// Removing one card from a shoe moves things less the bigger the shoe is. This
+// scales the change to what removing one card from a single deck would do, so
+// effects from shoes of any size can be compared.
+fn per_card(delta: f32, deck: &Deck) -> f32 {
+    delta * (deck.size - 1) as f32 / 51.0
+}
+
+#[test]
+fn test_per_card() {
+    let deck = Deck {
+        cards: [4, 4, 4, 4, 4, 4, 4, 4, 4, 16],
+        size: 52,
+    };
+    assert!(0.5 == per_card(0.5, &deck));
+    assert!((per_card(0.01, &super::FULL_DECK) - 0.0814).abs() < 1e-4);
+}
+
+// Works out `value` for the shoe and for the shoe less each rank, each on its
+// own thread.
+fn removal_effects<F>(name: String, deck: Deck, value: F) -> Effects
+    where F: Fn(Deck) -> f32 + Sync
+{
+    let (base, deltas) = thread::scope(|scope| {
+        let value = &value;
+        let handles: Vec<_> = (1..11)
+            .map(|card| {
+                scope.spawn(move || {
+                    if deck.cards[card_index(card)] == 0 {
+                        return None;
+                    }
+                    let mut removed = deck;
+                    removed.draw(card);
+                    Some(value(removed))
+                })
+            })
+            .collect();
+        let base = value(deck);
+        let deltas: Vec<Option<f32>> =
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        (base, deltas)
+    });
+    let mut effects = [0.0; 10];
+    for (effect, delta) in effects.iter_mut().zip(deltas) {
+        if let Some(exp) = delta {
+            *effect = per_card(exp - base, &deck);
+        }
+    }
+    Effects {
+        name,
+        base,
+        effects,
+    }
+}
+
+/// How removing each rank changes the player's expectation for a round. A
+/// reshuffle deals from the whole of `deck`.
+pub fn game_effects(deck: Deck, rules: Rules) -> Effects {
+    removal_effects(String::from("Game"),
+                    deck,
+                    |removed| deck_expectation(&deck, removed, &rules))
+}
+
+/// The expectation of an insurance bet, which pays 2 to 1 when the dealer's
+/// hole card is a ten.
+pub fn insurance_value(deck: Deck) -> f32 {
+    3.0 * deck.card_prob(10, false) - 1.0
+}
+
+/// How removing each rank changes the expectation of an insurance bet against
+/// a dealer ace, which is already out of `deck`.
+pub fn insurance_effects(deck: Deck) -> Effects {
+    removal_effects(String::from("Insurance"), deck, insurance_value)
+}
+
+#[test]
+fn test_insurance_effects() {
+    let deck = Deck {
+        cards: [3, 4, 4, 4, 4, 4, 4, 4, 4, 16],
+        size: 51,
+    };
+    let effects = insurance_effects(deck);
+    assert!((effects.base - (48.0 / 51.0 - 1.0)).abs() < 1e-6);
+    assert!((effects.effects[card_index(10)] - (45.0 / 50.0 - 48.0 / 51.0) * 50.0 / 51.0).abs() <
+            1e-6);
+    assert!((effects.effects[card_index(2)] - (48.0 / 50.0 - 48.0 / 51.0) * 50.0 / 51.0).abs() <
+            1e-6);
+}
+
+/// The expectation of the decision's first action less that of its second,
+/// with its cards already out of `deck`, what's left of `shoe`.
+pub fn decision_value(decision: &Decision, shoe: &Deck, deck: Deck, rules: Rules) -> f32 {
+    let mut state = GameState {
+        player: decision.player.clone(),
+        dealer: vec![decision.upcard],
+        deck,
+        shoe: *shoe,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules,
+    };
+    let expectations = action_expectations(&mut state);
+    let value = |action| {
+        expectations.iter()
+            .find(|&&(a, _)| a == action)
+            .map(|&(_, exp)| exp)
+            .unwrap_or_else(|| panic!("{} isn't allowed for {}", action, decision))
+    };
+    value(decision.first) - value(decision.second)
+}
+
+/// How removing each rank changes the value of a decision. None if the
+/// decision's own cards can't be dealt from `deck`, or `rules` don't allow
+/// its actions. A reshuffle deals from the whole of `deck`.
+pub fn decision_effects(deck: Deck, rules: Rules, decision: &Decision) -> Option<Effects> {
+    if !decision.is_allowed(&rules) {
+        return None;
+    }
+    let shoe = deck;
+    let mut deck = deck;
+    for &card in decision.player.iter().chain(Some(&decision.upcard)) {
+        if deck.cards[card_index(card)] == 0 {
+            return None;
+        }
+        deck.draw(card);
+    }
+    Some(removal_effects(decision.to_string(),
+                         deck,
+                         |deck| decision_value(decision, &shoe, deck, rules)))
+}
+
+#[test]
+fn test_decision_effects() {
+    let deck = Deck {
+        cards: [1, 1, 1, 1, 1, 1, 1, 1, 1, 3],
+        size: 12,
+    };
+    let stiff = decision([10, 6], 10, Action::Stand, Action::Hit);
+    assert!("16 v 10 Stand over Hit" == stiff.to_string());
+    let effects = decision_effects(deck, DEFAULT_RULES, &stiff).unwrap();
+    let remaining = Deck {
+        cards: [1, 1, 1, 1, 1, 0, 1, 1, 1, 1],
+        size: 9,
+    };
+    assert!((decision_value(&stiff, &deck, remaining, DEFAULT_RULES) - effects.base).abs() <
+            1e-6);
+    let mut without_five = remaining;
+    without_five.draw(5);
+    let effect = per_card(decision_value(&stiff, &deck, without_five, DEFAULT_RULES) -
+                          effects.base,
+                          &remaining);
+    assert!((effect - effects.effects[card_index(5)]).abs() < 1e-6);
+    // Taking out the five that makes 21 favours standing.
+    assert!(effects.effects[card_index(5)] > 0.0);
+    // There's no six left to take out.
+    assert!(0.0 == effects.effects[card_index(6)]);
+
+    assert!(decision_effects(deck, DEFAULT_RULES, &decision([6, 6], 10, Action::Stand, Action::Hit))
+        .is_none());
+}
+
+#[test]
+fn test_is_allowed() {
+    let surrender = decision([10, 5], 10, Action::Surrender, Action::Hit);
+    let no_surrender = parse_rules("nols");
+    assert!(surrender.is_allowed(&DEFAULT_RULES) && !surrender.is_allowed(&no_surrender));
+    assert!(standard_decisions().iter().filter(|decision| decision.is_allowed(&no_surrender))
+        .all(|decision| decision.first != Action::Surrender));
+    assert!(decision([8, 8], 10, Action::Split, Action::Hit).is_allowed(&DEFAULT_RULES));
+    assert!(!decision([8, 7], 10, Action::Split, Action::Hit).is_allowed(&DEFAULT_RULES));
+    assert!(!decision([10, 6], 10, Action::Insurance, Action::Hit).is_allowed(&DEFAULT_RULES));
+    // Nothing is worked out for a decision the rules don't allow.
+    let deck = Deck {
+        cards: [1, 1, 1, 1, 1, 1, 1, 1, 1, 3],
+        size: 12,
+    };
+    assert!(decision_effects(deck, no_surrender, &surrender).is_none());
+}
+
+/// One row per set of effects, giving the base value and then the effect of
+/// each rank from ace to ten.
+pub fn eor_csv(effects: &[Effects]) -> Result<String, Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    let mut header = vec![String::from("name"), String::from("base"), String::from("A")];
+    header.extend((2..11).map(|card: u16| card.to_string()));
+    writer.write_record(&header)?;
+    for row in effects {
+        let mut record = vec![row.name.clone(), row.base.to_string()];
+        record.extend(row.effects.iter().map(|effect| effect.to_string()));
+        writer.write_record(&record)?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+#[test]
+fn test_eor_csv() {
+    let mut effects = [0.0; 10];
+    effects[card_index(5)] = 0.5;
+    effects[card_index(10)] = -0.25;
+    let csv = eor_csv(&[Effects {
+                            name: String::from("Game"),
+                            base: -0.005,
+                            effects,
+                        }])
+        .unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert!("name,base,A,2,3,4,5,6,7,8,9,10" == lines[0]);
+    assert!("Game,-0.005,0,0,0,0,0.5,0,0,0,0,-0.25" == lines[1]);
+    assert!(2 == lines.len());
+}
+
+/// Reads effects written by `eor_csv`.
+pub fn read_eor_csv(path: &str) -> Result<Vec<Effects>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let mut rows = vec![];
+    for record in reader.records() {
+        let record = record?;
+        let mut effects = [0.0; 10];
+        for (effect, field) in effects.iter_mut().zip(record.iter().skip(2)) {
+            *effect = field.parse()?;
+        }
+        rows.push(Effects {
+            name: String::from(&record[0]),
+            base: record[1].parse()?,
+            effects,
+        });
+    }
+    Ok(rows)
+}
@@ -0,0 +1,21 @@
+// Data the tests of the modules that fit advantages share.
+
+use rand::{Rng, SeedableRng, StdRng};
+
+use super::{Deck, FULL_DECK};
+
+/// Shoes dealt to random depths, with advantages worked out from `advantage`.
+pub fn test_data<F: Fn(&Deck) -> f32>(advantage: F) -> Vec<(Deck, f32)> {
+    let seed: &[_] = &[1, 2, 3, 4];
+    let mut rng: StdRng = SeedableRng::from_seed(seed);
+    (0..200)
+        .map(|_| {
+            let mut deck = FULL_DECK;
+            let depth = rng.gen_range(50, 350);
+            for _ in 0..depth {
+                deck.draw_random(&mut rng);
+            }
+            (deck, advantage(&deck))
+        })
+        .collect()
+}
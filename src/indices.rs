@@ -0,0 +1,320 @@
+// Playing deviation indices: the true count at which a decision flips from
+// one action to the other. Shoes are built to sit at a range of true counts
+// for a count system, the decision is valued in each, and the index is where
+// its value crosses zero.
+
+use std::fmt;
+use std::thread;
+
+use super::{Deck, GameState, Rules, card_index};
+use super::counts::CountSystem;
+use super::eor::{Decision, decision_value, insurance_value};
+use super::variance::action_moments;
+#[cfg(test)]
+use super::{Action, FULL_DECK};
+
+/// When to play a decision's first action rather than its second.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum Play {
+    Always,
+    Never,
+    /// At this true count or above.
+    Above(f32),
+    /// At this true count or below.
+    Below(f32),
+}
+
+impl fmt::Display for Play {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Play::Always => write!(f, "always"),
+            Play::Never => write!(f, "never"),
+            Play::Above(index) => write!(f, "at {:+.1} or above", index),
+            Play::Below(index) => write!(f, "at {:+.1} or below", index),
+        }
+    }
+}
+
+/// Deals `shoe` down to `remaining` cards, choosing the cards dealt so that
+/// `system`'s true count is as close to `true_count` as whole cards allow.
+/// Each rank is dealt in proportion to its share of the shoe, leaning towards
+/// the ranks whose tags move the count the right way. `remaining` can't be
+/// more than the shoe holds.
+pub fn shoe_at_count(shoe: &Deck, system: &CountSystem, true_count: f32, remaining: u16) -> Deck {
+    let seen = (shoe.size - remaining) as f32;
+    if seen == 0.0 {
+        return *shoe;
+    }
+    let share = |card: u16| shoe.cards[card_index(card)] as f32 / shoe.size as f32;
+    let tag = |card: u16| system.tags[card_index(card)];
+    let mean: f32 = (1..11).map(|card| share(card) * tag(card)).sum();
+    let variance: f32 = (1..11).map(|card| share(card) * (tag(card) - mean).powi(2)).sum();
+    let running_count = true_count * remaining as f32 / 52.0;
+    let lean = if variance == 0.0 {
+        0.0
+    } else {
+        (running_count - seen * mean) / (seen * variance)
+    };
+    let mut ideal = [0.0f32; 10];
+    for card in 1..11 {
+        ideal[card_index(card)] = (share(card) * seen * (1.0 + lean * (tag(card) - mean)))
+            .max(0.0)
+            .min(shoe.cards[card_index(card)] as f32);
+    }
+
+    // Round to whole cards, then deal or put back the cards furthest from
+    // their ideal until the right number have been dealt.
+    let to_deal = shoe.size - remaining;
+    let mut dealt = [0u16; 10];
+    for (count, ideal) in dealt.iter_mut().zip(ideal.iter()) {
+        *count = ideal.round() as u16;
+    }
+    let shortfall = |dealt: &[u16; 10], i: usize| ideal[i] - dealt[i] as f32;
+    loop {
+        let total: u16 = dealt.iter().sum();
+        if total == to_deal {
+            break;
+        }
+        let candidates = (0..10).filter(|&i| if total < to_deal {
+            dealt[i] < shoe.cards[i]
+        } else {
+            dealt[i] > 0
+        });
+        let by_shortfall = |&a: &usize, &b: &usize| {
+            shortfall(&dealt, a).partial_cmp(&shortfall(&dealt, b)).unwrap()
+        };
+        if total < to_deal {
+            let i = candidates.max_by(by_shortfall).unwrap();
+            dealt[i] += 1;
+        } else {
+            let i = candidates.min_by(by_shortfall).unwrap();
+            dealt[i] -= 1;
+        }
+    }
+
+    // Rounding can leave the count a few cards out, so swap dealt cards for
+    // ones that bring it closer, preferring the swaps that stay nearest the
+    // ideal.
+    let dealt_count = |dealt: &[u16; 10]| -> f32 {
+        dealt.iter().zip(system.tags.iter()).map(|(&count, tag)| count as f32 * tag).sum()
+    };
+    loop {
+        let error = (dealt_count(&dealt) - running_count).abs();
+        let mut best: Option<(f32, f32, usize, usize)> = None;
+        for more in 0..10 {
+            for less in 0..10 {
+                if more == less || dealt[more] == shoe.cards[more] || dealt[less] == 0 {
+                    continue;
+                }
+                let new_error = (dealt_count(&dealt) + system.tags[more] - system.tags[less] -
+                                 running_count)
+                    .abs();
+                let drift = shortfall(&dealt, less) - shortfall(&dealt, more);
+                if new_error < error - 1e-4 &&
+                   best.is_none_or(|(best_error, best_drift, _, _)| {
+                    (new_error, drift) < (best_error, best_drift)
+                }) {
+                    best = Some((new_error, drift, more, less));
+                }
+            }
+        }
+        match best {
+            Some((_, _, more, less)) => {
+                dealt[more] += 1;
+                dealt[less] -= 1;
+            }
+            None => break,
+        }
+    }
+    let mut deck = *shoe;
+    for (count, dealt) in deck.cards.iter_mut().zip(dealt.iter()) {
+        *count -= dealt;
+    }
+    deck.size = remaining;
+    deck
+}
+
+#[test]
+fn test_shoe_at_count() {
+    let hi_lo = &super::counts::systems()[0];
+    for &true_count in [-4.0, 0.0, 2.0, 6.0].iter() {
+        let deck = shoe_at_count(&FULL_DECK, hi_lo, true_count, 208);
+        assert!(208 == deck.size);
+        assert!(208 == deck.cards.iter().sum::<u16>());
+        assert!((hi_lo.true_count(&FULL_DECK, &deck) - true_count).abs() < 0.01);
+    }
+    assert!(FULL_DECK == shoe_at_count(&FULL_DECK, hi_lo, 3.0, 416));
+}
+
+// Where the value of a decision crosses zero, from its value at a range of
+// true counts, working outwards from the lowest count.
+fn crossover(points: &[(f32, f32)]) -> Play {
+    let first = match points.first() {
+        Some(&(_, value)) => value,
+        None => return Play::Never,
+    };
+    for pair in points.windows(2) {
+        let ((count_a, value_a), (count_b, value_b)) = (pair[0], pair[1]);
+        if (value_a > 0.0) != (value_b > 0.0) {
+            let index = count_a + (count_b - count_a) * value_a / (value_a - value_b);
+            return if value_b > 0.0 {
+                Play::Above(index)
+            } else {
+                Play::Below(index)
+            };
+        }
+    }
+    if first > 0.0 { Play::Always } else { Play::Never }
+}
+
+#[test]
+fn test_crossover() {
+    assert!(Play::Above(0.5) == crossover(&[(-1.0, -0.3), (0.0, -0.1), (1.0, 0.1), (2.0, 0.3)]));
+    assert!(Play::Below(-2.0) == crossover(&[(-4.0, 0.2), (-2.0, 0.0), (0.0, -0.2)]));
+    assert!(Play::Always == crossover(&[(-1.0, 0.1), (1.0, 0.2)]));
+    assert!(Play::Never == crossover(&[(-1.0, -0.1), (1.0, -0.2)]));
+    assert!(Play::Never == crossover(&[]));
+    assert!("at +0.5 or above" == Play::Above(0.5).to_string());
+}
+
+// Values a decision in a shoe at each true count, each on its own thread. The
+// true count of the shoe actually built is used, and counts the decision's
+// cards can't be dealt at are left out.
+fn index<F>(shoe: &Deck, system: &CountSystem, remaining: u16, true_counts: &[f32], value: F) -> Play
+    where F: Fn(Deck) -> Option<f32> + Sync
+{
+    let points: Vec<(f32, f32)> = thread::scope(|scope| {
+        let value = &value;
+        let handles: Vec<_> = true_counts.iter()
+            .map(|&true_count| {
+                scope.spawn(move || {
+                    let deck = shoe_at_count(shoe, system, true_count, remaining);
+                    value(deck).map(|value| (system.true_count(shoe, &deck), value))
+                })
+            })
+            .collect();
+        handles.into_iter().filter_map(|handle| handle.join().unwrap()).collect()
+    });
+    let mut points = points;
+    points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    crossover(&points)
+}
+
+// Takes `cards` out of `deck`, or returns None if they aren't all there.
+fn deal(mut deck: Deck, cards: &[u16]) -> Option<Deck> {
+    for &card in cards {
+        if deck.cards[card_index(card)] == 0 {
+            return None;
+        }
+        deck.draw(card);
+    }
+    Some(deck)
+}
+
+// The decision's value penalized by the variance each action adds, in units
+// of `risk_aversion` per unit of variance.
+fn risk_averse_value(decision: &Decision,
+                     shoe: &Deck,
+                     deck: Deck,
+                     rules: Rules,
+                     risk_aversion: f32)
+                     -> f32 {
+    let mut state = GameState {
+        player: decision.player.clone(),
+        dealer: vec![decision.upcard],
+        deck,
+        shoe: *shoe,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules,
+    };
+    let mut utility = |action| {
+        let moments = action_moments(&mut state, action);
+        moments.expectation - risk_aversion * moments.variance
+    };
+    utility(decision.first) - utility(decision.second)
+}
+
+/// The index for a playing decision. With a positive `risk_aversion` each
+/// action's variance counts against it, which gives risk-averse indices.
+pub fn decision_index(shoe: &Deck,
+                      rules: Rules,
+                      system: &CountSystem,
+                      decision: &Decision,
+                      remaining: u16,
+                      true_counts: &[f32],
+                      risk_aversion: f32)
+                      -> Play {
+    let mut cards = decision.player.clone();
+    cards.push(decision.upcard);
+    index(shoe, system, remaining, true_counts, |deck| {
+        deal(deck, &cards).map(|deck| if risk_aversion == 0.0 {
+            decision_value(decision, shoe, deck, rules)
+        } else {
+            risk_averse_value(decision, shoe, deck, rules, risk_aversion)
+        })
+    })
+}
+
+/// The index for taking insurance against a dealer ace.
+pub fn insurance_index(shoe: &Deck,
+                       system: &CountSystem,
+                       remaining: u16,
+                       true_counts: &[f32])
+                       -> Play {
+    index(shoe,
+          system,
+          remaining,
+          true_counts,
+          |deck| deal(deck, &[1]).map(insurance_value))
+}
+
+#[test]
+fn test_insurance_index() {
+    let hi_lo = &super::counts::systems()[0];
+    let true_counts: Vec<f32> = (-10..11).map(|count| count as f32).collect();
+    match insurance_index(&FULL_DECK, hi_lo, 208, &true_counts) {
+        Play::Above(index) => assert!(index > 2.5 && index < 3.5),
+        play => panic!("Unexpected insurance play {}", play),
+    }
+}
+
+#[test]
+fn test_decision_index() {
+    let hi_lo = &super::counts::systems()[0];
+    let decisions = super::eor::standard_decisions();
+    let true_counts: Vec<f32> = (-2..4).map(|count| count as f32).collect();
+    // Standing on 16 against a ten flips near the usual index of zero. A
+    // two-card 10,6 comes out a little higher, as the six it holds is one of
+    // the cards a hit wants.
+    let sixteen = &decisions[0];
+    match decision_index(&FULL_DECK, super::DEFAULT_RULES, hi_lo, sixteen, 208, &true_counts, 0.0) {
+        Play::Above(index) => assert!(index > 0.0 && index < 2.0),
+        play => panic!("Unexpected 16 v 10 play {}", play),
+    }
+
+    // Soft 19 doubles against a 6 from about +1. Doubling adds variance, so a
+    // risk-averse player needs a higher count to do it.
+    let soft_nineteen = Decision {
+        player: vec![1, 8],
+        upcard: 6,
+        first: Action::Double,
+        second: Action::Stand,
+    };
+    let index_at = |risk_aversion: f32| {
+        match decision_index(&FULL_DECK,
+                             super::DEFAULT_RULES,
+                             hi_lo,
+                             &soft_nineteen,
+                             208,
+                             &true_counts,
+                             risk_aversion) {
+            Play::Above(index) => index,
+            play => panic!("Unexpected A,8 v 6 play {}", play),
+        }
+    };
+    let neutral = index_at(0.0);
+    assert!(neutral > 0.0 && neutral < 2.0);
+    assert!(index_at(0.002) > neutral);
+}
@@ -6,23 +6,139 @@ extern crate rand;
 // #[macro_use]
 // extern crate rocket;
 
-use std::path::Path;
 use std::env;
+use std::fmt;
+use std::net::TcpListener;
+use std::panic;
+use std::process;
+use std::path::Path;
 use std::thread;
+use std::time::Duration;
 use time::PreciseTime;
-use std::fs::OpenOptions;
+use std::fs;
 use std::error::Error;
-use std::iter::FromIterator;
-use rand::distributions::{IndependentSample, Range};
+use rand::Rng;
 use std::collections::HashMap;
-use std::collections::HashSet;
 // use rocket::http::RawStr;
 
+mod basic;
+mod binary;
+mod coordinator;
+mod counts;
+mod csm;
+mod curve;
+mod cutcard;
+mod dataset;
+mod diff;
+mod eor;
+#[cfg(test)]
+mod fixtures;
+mod indices;
+mod neighbours;
+mod optimizer;
+mod queue;
+mod ramp;
+mod regression;
+mod render;
+mod round;
+mod samples;
+mod simulator;
+mod strata;
+mod strategy;
+mod table;
+mod variance;
+
+// The default eight deck shoe, which the tests deal from. The tools deal from
+// `full_shoe(decks)`.
+#[cfg(test)]
 static FULL_DECK: Deck = Deck {
     cards: [32, 32, 32, 32, 32, 32, 32, 32, 32, 128],
     size: 416,
 };
 
+static DEFAULT_RULES: Rules = Rules {
+    blackjack_payout: 1.5,
+    dealer_hits_soft_17: false,
+    surrender: true,
+    double_after_split: true,
+};
+
+#[derive(Debug,PartialEq,Clone,Copy)]
+struct Rules {
+    blackjack_payout: f32,
+    dealer_hits_soft_17: bool,
+    surrender: bool,
+    double_after_split: bool,
+}
+
+// Reads a comma separated list of rule changes from the default rules, e.g.
+// "h17,nodas,6:5".
+fn parse_rules(rules_str: &str) -> Rules {
+    try_parse_rules(rules_str).unwrap_or_else(|err| panic!("{}", err))
+}
+
+// Like parse_rules, for rules that come from a file or the network, where an
+// unknown rule is an error rather than a mistake on the command line.
+fn try_parse_rules(rules_str: &str) -> Result<Rules, String> {
+    let mut rules = DEFAULT_RULES;
+    for rule in rules_str.split(',').filter(|rule| !rule.is_empty()) {
+        match rule {
+            "s17" => rules.dealer_hits_soft_17 = false,
+            "h17" => rules.dealer_hits_soft_17 = true,
+            "ls" => rules.surrender = true,
+            "nols" => rules.surrender = false,
+            "das" => rules.double_after_split = true,
+            "nodas" => rules.double_after_split = false,
+            "3:2" => rules.blackjack_payout = 1.5,
+            "6:5" => rules.blackjack_payout = 1.2,
+            "1:1" => rules.blackjack_payout = 1.0,
+            _ => return Err(format!("Unknown rule {}", rule)),
+        }
+    }
+    Ok(rules)
+}
+
+// The rules as parse_rules reads them.
+impl fmt::Display for Rules {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let payout = if self.blackjack_payout == 1.2 {
+            "6:5"
+        } else if self.blackjack_payout == 1.0 {
+            "1:1"
+        } else {
+            "3:2"
+        };
+        write!(f,
+               "{},{},{},{}",
+               if self.dealer_hits_soft_17 { "h17" } else { "s17" },
+               if self.surrender { "ls" } else { "nols" },
+               if self.double_after_split { "das" } else { "nodas" },
+               payout)
+    }
+}
+
+#[test]
+fn test_parse_rules() {
+    assert!(DEFAULT_RULES == parse_rules(""));
+    let rules = parse_rules("h17,nols,nodas,6:5");
+    assert!(rules.dealer_hits_soft_17);
+    assert!(!rules.surrender);
+    assert!(!rules.double_after_split);
+    assert!(1.2 == rules.blackjack_payout);
+    assert!(DEFAULT_RULES == parse_rules("h17,s17"));
+    assert!("h17,nols,nodas,6:5" == rules.to_string());
+    assert!(rules == parse_rules(&rules.to_string()));
+    assert!(DEFAULT_RULES == parse_rules(&DEFAULT_RULES.to_string()));
+    assert!(Ok(rules) == try_parse_rules("h17,nols,nodas,6:5"));
+    assert!(Err(String::from("Unknown rule rsa")) == try_parse_rules("h17,rsa"));
+}
+
+#[test]
+#[should_panic]
+fn test_parse_unknown_rule() {
+    parse_rules("rsa");
+}
+
 #[derive(Debug,PartialOrd,PartialEq,Clone,Copy,Hash,Eq)]
 enum Score {
     Bust,
@@ -71,7 +187,7 @@ fn equal_values_are_equal() {
     assert!(Score::Value(16) == Score::Value(16));
 }
 
-fn hand_value(hand: &Vec<u16>) -> u16 {
+fn hand_value(hand: &[u16]) -> u16 {
     let mut has_ace = false;
     let mut score = 0;
     for &card in hand.iter() {
@@ -88,33 +204,33 @@ fn hand_value(hand: &Vec<u16>) -> u16 {
 
 #[test]
 fn hard_hand_values() {
-    assert!(20 == hand_value(&vec![10, 10]));
-    assert!(21 == hand_value(&vec![10, 10, 1]));
-    assert!(30 == hand_value(&vec![10, 10, 10]));
-    assert!(12 == hand_value(&vec![10, 1, 1]));
-    assert!(0 == hand_value(&vec![]));
+    assert!(20 == hand_value(&[10, 10]));
+    assert!(21 == hand_value(&[10, 10, 1]));
+    assert!(30 == hand_value(&[10, 10, 10]));
+    assert!(12 == hand_value(&[10, 1, 1]));
+    assert!(0 == hand_value(&[]));
 }
 
 #[test]
 fn soft_hand_values() {
-    assert!(11 == hand_value(&vec![1]));
-    assert!(12 == hand_value(&vec![1, 1]));
-    assert!(21 == hand_value(&vec![10, 1]))
+    assert!(11 == hand_value(&[1]));
+    assert!(12 == hand_value(&[1, 1]));
+    assert!(21 == hand_value(&[10, 1]))
 }
 
-fn min_hand_value(hand: &Vec<u16>) -> u16 {
+fn min_hand_value(hand: &[u16]) -> u16 {
     hand.iter().sum()
 }
 
 #[test]
 fn min_hand_values() {
-    assert!(1 == min_hand_value(&vec![1]));
-    assert!(0 == min_hand_value(&vec![]));
-    assert!(15 == min_hand_value(&vec![10, 5]));
-    assert!(11 == min_hand_value(&vec![10, 1]));
+    assert!(1 == min_hand_value(&[1]));
+    assert!(0 == min_hand_value(&[]));
+    assert!(15 == min_hand_value(&[10, 5]));
+    assert!(11 == min_hand_value(&[10, 1]));
 }
 
-fn score(hand: &Vec<u16>) -> Score {
+fn score(hand: &[u16]) -> Score {
     let hand_total = hand_value(hand);
     if hand_total > 21 {
         return Score::Bust;
@@ -128,23 +244,23 @@ fn score(hand: &Vec<u16>) -> Score {
 
 #[test]
 fn test_score() {
-    assert!(Score::Natural == score(&vec![1, 10]));
-    assert!(Score::Natural == score(&vec![10, 1]));
-    assert!(Score::SixCardCharlie(20) == score(&vec![2, 2, 2, 2, 1, 1]));
-    assert!(Score::SixCardCharlie(19) == score(&vec![2, 3, 3, 3, 3, 5]));
-    assert!(Score::Bust == score(&vec![10, 10, 2]));
-    assert!(Score::Value(21) == score(&vec![10, 10, 1]));
-    assert!(Score::Value(16) == score(&vec![4, 4, 4, 4]));
+    assert!(Score::Natural == score(&[1, 10]));
+    assert!(Score::Natural == score(&[10, 1]));
+    assert!(Score::SixCardCharlie(20) == score(&[2, 2, 2, 2, 1, 1]));
+    assert!(Score::SixCardCharlie(19) == score(&[2, 3, 3, 3, 3, 5]));
+    assert!(Score::Bust == score(&[10, 10, 2]));
+    assert!(Score::Value(21) == score(&[10, 10, 1]));
+    assert!(Score::Value(16) == score(&[4, 4, 4, 4]));
 }
 
-fn hand_expectation(player_score: Score, dealer_score: Score) -> f32 {
+fn hand_expectation(player_score: Score, dealer_score: Score, rules: &Rules) -> f32 {
     match player_score {
         Score::Bust => -1.0,
         Score::Natural => {
             if dealer_score == Score::Natural {
                 0.0
             } else {
-                1.5
+                rules.blackjack_payout
             }
         }
         _ => {
@@ -161,17 +277,18 @@ fn hand_expectation(player_score: Score, dealer_score: Score) -> f32 {
 
 #[test]
 fn test_hand_expectation() {
-    assert!(-1.0 == hand_expectation(Score::Bust, Score::Bust));
-    assert!(-1.0 == hand_expectation(Score::Bust, Score::Natural));
-    assert!(-1.0 == hand_expectation(Score::Value(10), Score::Value(11)));
-    assert!(0.0 == hand_expectation(Score::Value(10), Score::Value(10)));
-    assert!(1.0 == hand_expectation(Score::Value(11), Score::Value(10)));
-    assert!(-1.0 == hand_expectation(Score::Value(21), Score::SixCardCharlie(16)));
-    assert!(-1.0 == hand_expectation(Score::SixCardCharlie(10), Score::SixCardCharlie(11)));
-    assert!(0.0 == hand_expectation(Score::SixCardCharlie(10), Score::SixCardCharlie(10)));
-    assert!(1.0 == hand_expectation(Score::SixCardCharlie(11), Score::SixCardCharlie(10)));
-    assert!(1.5 == hand_expectation(Score::Natural, Score::SixCardCharlie(21)));
-    assert!(0.0 == hand_expectation(Score::Natural, Score::Natural));
+    assert!(-1.0 == hand_expectation(Score::Bust, Score::Bust, &DEFAULT_RULES));
+    assert!(-1.0 == hand_expectation(Score::Bust, Score::Natural, &DEFAULT_RULES));
+    assert!(-1.0 == hand_expectation(Score::Value(10), Score::Value(11), &DEFAULT_RULES));
+    assert!(0.0 == hand_expectation(Score::Value(10), Score::Value(10), &DEFAULT_RULES));
+    assert!(1.0 == hand_expectation(Score::Value(11), Score::Value(10), &DEFAULT_RULES));
+    assert!(-1.0 == hand_expectation(Score::Value(21), Score::SixCardCharlie(16), &DEFAULT_RULES));
+    assert!(-1.0 == hand_expectation(Score::SixCardCharlie(10), Score::SixCardCharlie(11), &DEFAULT_RULES));
+    assert!(0.0 == hand_expectation(Score::SixCardCharlie(10), Score::SixCardCharlie(10), &DEFAULT_RULES));
+    assert!(1.0 == hand_expectation(Score::SixCardCharlie(11), Score::SixCardCharlie(10), &DEFAULT_RULES));
+    assert!(1.5 == hand_expectation(Score::Natural, Score::SixCardCharlie(21), &DEFAULT_RULES));
+    assert!(0.0 == hand_expectation(Score::Natural, Score::Natural, &DEFAULT_RULES));
+    assert!(1.2 == hand_expectation(Score::Natural, Score::Value(20), &parse_rules("6:5")));
 }
 
 fn card_index(card: u16) -> usize {
@@ -187,9 +304,14 @@ struct GameState {
     player: Vec<u16>,
     dealer: Vec<u16>,
     deck: Deck,
+    // The cards a reshuffle deals from: the whole shoe, less any cards on the
+    // table that aren't in `player` or `dealer`, such as the other half of a
+    // split or other seats' hands.
+    shoe: Deck,
     failed_insurance: bool,
     is_split: bool,
     first_split_hand: bool,
+    rules: Rules,
 }
 
 #[derive(Eq,PartialEq,Hash,Debug,Clone,Copy)]
@@ -208,6 +330,11 @@ impl Deck {
         self.cards[card_index(card)] += 1;
         self.size += 1;
     }
+    // Whether every card of `other` is in this deck, as when `other` is what's
+    // left of it after some cards have been dealt.
+    fn contains(&self, other: &Deck) -> bool {
+        self.cards.iter().zip(other.cards.iter()).all(|(cards, others)| cards >= others)
+    }
     fn draw_to(&mut self, hand: &mut Vec<u16>, card: u16) {
         self.draw(card);
         hand.push(card);
@@ -230,6 +357,17 @@ impl Deck {
             self.cards[card_index(card)] as f32 / self.size as f32
         }
     }
+    // Draws a card at random, each card in the deck being equally likely.
+    fn draw_random<R: Rng>(&mut self, rng: &mut R) -> u16 {
+        let mut position = rng.gen_range(0, self.size);
+        let mut card = 1;
+        while position >= self.cards[card_index(card)] {
+            position -= self.cards[card_index(card)];
+            card += 1;
+        }
+        self.draw(card);
+        card
+    }
 }
 
 #[test]
@@ -264,6 +402,19 @@ fn test_replace() {
     assert!(49 == deck.size);
 }
 
+#[test]
+fn test_contains() {
+    let mut deck = Deck {
+        cards: [0, 4, 4, 4, 4, 4, 4, 4, 4, 16],
+        size: 48,
+    };
+    assert!(FULL_DECK.contains(&deck) && deck.contains(&deck));
+    deck.replace(1);
+    assert!(!deck.contains(&FULL_DECK) && FULL_DECK.contains(&deck));
+    deck.cards[9] = 129;
+    assert!(!FULL_DECK.contains(&deck));
+}
+
 #[test]
 fn test_valid_draw_to() {
     let mut hand = vec![];
@@ -329,42 +480,72 @@ fn test_card_prob() {
     assert!(16.0 / 48.0 == deck.card_prob(10, false));
 }
 
-fn dealer_stands(hand: &Vec<u16>) -> bool {
-    hand.len() == 6 || hand_value(hand) >= 17
+#[test]
+fn test_draw_random() {
+    let mut rng = rand::thread_rng();
+    let mut deck = Deck {
+        cards: [0, 0, 2, 0, 0, 0, 0, 0, 1, 0],
+        size: 3,
+    };
+    let mut cards = vec![];
+    for _ in 0..3 {
+        cards.push(deck.draw_random(&mut rng));
+    }
+    cards.sort();
+    assert!(vec![3, 3, 9] == cards);
+    assert!(0 == deck.size);
+}
+
+fn dealer_stands(hand: &[u16], rules: &Rules) -> bool {
+    let value = hand_value(hand);
+    let soft_17 = value == 17 && min_hand_value(hand) != value;
+    hand.len() == 6 || (value >= 17 && !(soft_17 && rules.dealer_hits_soft_17))
 }
 
 #[test]
 fn test_dealer_stands() {
-    assert!(dealer_stands(&vec![10, 7]));
-    assert!(dealer_stands(&vec![10, 6, 6]));
-    assert!(dealer_stands(&vec![6, 1]));
-    assert!(dealer_stands(&vec![1, 1, 1, 1, 2, 2]));
-    assert!(!dealer_stands(&vec![10, 6]));
-}
-
-fn next_card_isnt_ten(hand: &Vec<u16>, failed_insurance: bool) -> bool {
+    assert!(dealer_stands(&[10, 7], &DEFAULT_RULES));
+    assert!(dealer_stands(&[10, 6, 6], &DEFAULT_RULES));
+    assert!(dealer_stands(&[6, 1], &DEFAULT_RULES));
+    assert!(dealer_stands(&[1, 1, 1, 1, 2, 2], &DEFAULT_RULES));
+    assert!(!dealer_stands(&[10, 6], &DEFAULT_RULES));
+    assert!(!dealer_stands(&[6, 1], &parse_rules("h17")));
+    assert!(!dealer_stands(&[1, 3, 3], &parse_rules("h17")));
+    assert!(dealer_stands(&[10, 6, 1], &parse_rules("h17")));
+    assert!(dealer_stands(&[7, 1], &parse_rules("h17")));
+}
+
+fn next_card_isnt_ten(hand: &[u16], failed_insurance: bool) -> bool {
     hand.len() == 1 && failed_insurance
 }
 
-fn dealer_scores(mut deck: &mut Deck,
-                 mut hand: &mut Vec<u16>,
-                 failed_insurance: bool)
+// The chance of each final score for the dealer, drawing from `deck`. `shoe`
+// is only needed if the deck runs out: it's what a reshuffle deals from, less
+// every card on the table but the dealer's.
+fn dealer_scores(deck: &mut Deck,
+                 hand: &mut Vec<u16>,
+                 shoe: &Deck,
+                 failed_insurance: bool,
+                 rules: &Rules)
                  -> HashMap<Score, f32> {
     let mut score_probabilities = HashMap::new();
-    if dealer_stands(&hand) {
-        score_probabilities.insert(score(&hand), 1.0);
+    if dealer_stands(hand, rules) {
+        score_probabilities.insert(score(hand), 1.0);
         return score_probabilities;
     }
-    let cant_be_ten = next_card_isnt_ten(&hand, failed_insurance);
+    if will_reshuffle(deck) {
+        return dealer_scores(&mut reshuffled(shoe, &[], hand), hand, shoe, failed_insurance, rules);
+    }
+    let cant_be_ten = next_card_isnt_ten(hand, failed_insurance);
     let max_card = if cant_be_ten { 9 } else { 10 };
     for card in 1..(max_card + 1) {
         let draw_prob = deck.card_prob(card, cant_be_ten);
         if draw_prob == 0.0 {
             continue;
         }
-        deck.draw_to(&mut hand, card);
-        let draw_scores = dealer_scores(&mut deck, &mut hand, failed_insurance);
-        deck.replace_from(&mut hand, card);
+        deck.draw_to(hand, card);
+        let draw_scores = dealer_scores(deck, hand, shoe, failed_insurance, rules);
+        deck.replace_from(hand, card);
         for (score, prob) in draw_scores.iter() {
             let current_prob = score_probabilities.entry(*score).or_insert(0.0);
             *current_prob += *prob * draw_prob;
@@ -377,15 +558,15 @@ fn dealer_scores(mut deck: &mut Deck,
 fn test_dealer_scores() {
     let mut result_map = HashMap::new();
     result_map.insert(Score::Natural, 1.0);
-    assert!(result_map == dealer_scores(&mut FULL_DECK.clone(), &mut vec![1, 10], false));
+    assert!(result_map == dealer_scores(&mut FULL_DECK.clone(), &mut vec![1, 10], &FULL_DECK, false, &DEFAULT_RULES));
 
     result_map = HashMap::new();
     result_map.insert(Score::SixCardCharlie(18), 1.0);
-    assert!(result_map == dealer_scores(&mut FULL_DECK.clone(), &mut vec![1, 1, 1, 1, 2, 2], false));
+    assert!(result_map == dealer_scores(&mut FULL_DECK.clone(), &mut vec![1, 1, 1, 1, 2, 2], &FULL_DECK, false, &DEFAULT_RULES));
 
     result_map = HashMap::new();
     result_map.insert(Score::Value(17), 1.0);
-    assert!(result_map == dealer_scores(&mut FULL_DECK.clone(), &mut vec![10, 7], false));
+    assert!(result_map == dealer_scores(&mut FULL_DECK.clone(), &mut vec![10, 7], &FULL_DECK, false, &DEFAULT_RULES));
 
     result_map = HashMap::new();
     result_map.insert(Score::Bust, 1.0);
@@ -395,7 +576,9 @@ fn test_dealer_scores() {
                               size: 10,
                           },
                           &mut vec![10, 6],
-                          false));
+                          &FULL_DECK,
+                          false,
+                          &DEFAULT_RULES));
 
     result_map = HashMap::new();
     result_map.insert(Score::Bust, 0.5);
@@ -406,7 +589,9 @@ fn test_dealer_scores() {
                               size: 2,
                           },
                           &mut vec![10, 5],
-                          false));
+                          &FULL_DECK,
+                          false,
+                          &DEFAULT_RULES));
 
     result_map = HashMap::new();
     result_map.insert(Score::Value(19), 1.0);
@@ -416,7 +601,9 @@ fn test_dealer_scores() {
                               size: 2,
                           },
                           &mut vec![10],
-                          true));
+                          &FULL_DECK,
+                          true,
+                          &DEFAULT_RULES));
 
     result_map = HashMap::new();
     result_map.insert(Score::Value(19), 1.0);
@@ -426,7 +613,9 @@ fn test_dealer_scores() {
                               size: 1,
                           },
                           &mut vec![10],
-                          true));
+                          &FULL_DECK,
+                          true,
+                          &DEFAULT_RULES));
 }
 
 fn stand_expectation(state: &mut GameState) -> f32 {
@@ -435,10 +624,16 @@ fn stand_expectation(state: &mut GameState) -> f32 {
         return -1.0;
     }
     let score_probabilities =
-        dealer_scores(&mut state.deck, &mut state.dealer, state.failed_insurance);
+        dealer_scores(&mut state.deck,
+                      &mut state.dealer,
+                      &reshuffled(&state.shoe, &state.player, &[]),
+                      state.failed_insurance,
+                      &state.rules);
 
     score_probabilities.iter()
-        .map(|(&dealer_score, prob)| hand_expectation(player_score, dealer_score) * prob)
+        .map(|(&dealer_score, prob)| {
+            hand_expectation(player_score, dealer_score, &state.rules) * prob
+        })
         .sum()
 }
 
@@ -447,30 +642,36 @@ fn test_stand_expectation() {
     let mut state = GameState {
         player: vec![1, 10],
         dealer: vec![10, 10],
-        deck: FULL_DECK.clone(),
+        deck: FULL_DECK,
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(1.5 == stand_expectation(&mut state));
 
     state = GameState {
         player: vec![10, 10],
         dealer: vec![10, 10],
-        deck: FULL_DECK.clone(),
+        deck: FULL_DECK,
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(0.0 == stand_expectation(&mut state));
 
     state = GameState {
         player: vec![10, 10, 10],
         dealer: vec![10, 10],
-        deck: FULL_DECK.clone(),
+        deck: FULL_DECK,
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(-1.0 == stand_expectation(&mut state));
 
@@ -481,9 +682,11 @@ fn test_stand_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
             size: 1,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(1.5 == stand_expectation(&mut state));
 
@@ -494,9 +697,11 @@ fn test_stand_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 1, 1],
             size: 2,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(0.5 == stand_expectation(&mut state));
 
@@ -507,9 +712,11 @@ fn test_stand_expectation() {
             cards: [0, 0, 0, 0, 3, 0, 0, 0, 0, 1],
             size: 4,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(0.25 == stand_expectation(&mut state));
 
@@ -520,9 +727,11 @@ fn test_stand_expectation() {
             cards: [4, 1, 0, 0, 0, 0, 0, 0, 0, 0],
             size: 5,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     println!("Stand expectation: {}", stand_expectation(&mut state));
     assert!(-1.0 == stand_expectation(&mut state));
@@ -534,14 +743,19 @@ fn test_stand_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 1, 0, 1],
             size: 2,
         },
+        shoe: FULL_DECK,
         failed_insurance: true,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(1.0 == stand_expectation(&mut state));
 }
 
-fn double_expectation(mut state: &mut GameState) -> f32 {
+fn double_expectation(state: &mut GameState) -> f32 {
+    if will_reshuffle(&state.deck) {
+        return reshuffled_expectation(state, double_expectation);
+    }
     let mut total_expectation = 0.0;
     for card in 1..11 {
         let draw_prob = state.deck.card_prob(card, false);
@@ -549,7 +763,7 @@ fn double_expectation(mut state: &mut GameState) -> f32 {
             continue;
         }
         state.deck.draw_to(&mut state.player, card);
-        total_expectation += draw_prob * stand_expectation(&mut state);
+        total_expectation += draw_prob * stand_expectation(state);
         state.deck.replace_from(&mut state.player, card);
     }
     2.0 * total_expectation
@@ -564,9 +778,11 @@ fn test_double_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
             size: 1,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(-2.0 == double_expectation(&mut state));
 
@@ -577,9 +793,11 @@ fn test_double_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
             size: 1,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(2.0 == double_expectation(&mut state));
 
@@ -590,9 +808,11 @@ fn test_double_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 1, 1],
             size: 2,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(0.0 == double_expectation(&mut state));
 }
@@ -614,8 +834,11 @@ fn test_double_expectation() {
 //     double_expectation(&mut state);
 // }
 
-fn hit_expectation(mut state: &mut GameState) -> f32 {
+fn hit_expectation(state: &mut GameState) -> f32 {
     assert!(Score::Bust != score(&state.player));
+    if will_reshuffle(&state.deck) {
+        return reshuffled_expectation(state, hit_expectation);
+    }
     let mut total_expectation = 0.0;
     for card in 1..11 {
         let draw_prob = state.deck.card_prob(card, false);
@@ -623,7 +846,7 @@ fn hit_expectation(mut state: &mut GameState) -> f32 {
             continue;
         }
         state.deck.draw_to(&mut state.player, card);
-        total_expectation += draw_prob * expectation(&mut state);
+        total_expectation += draw_prob * expectation(state);
         state.deck.replace_from(&mut state.player, card);
     }
     total_expectation
@@ -638,9 +861,11 @@ fn test_hit_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
             size: 1,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(-1.0 == hit_expectation(&mut state));
 
@@ -651,9 +876,11 @@ fn test_hit_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
             size: 10,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(1.0 == hit_expectation(&mut state));
 
@@ -664,9 +891,11 @@ fn test_hit_expectation() {
             cards: [0, 0, 0, 0, 3, 0, 0, 0, 0, 0],
             size: 3,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(1.0 == hit_expectation(&mut state));
 
@@ -677,9 +906,11 @@ fn test_hit_expectation() {
             cards: [2, 4, 4, 4, 4, 4, 4, 4, 4, 15],
             size: 49,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: true,
         first_split_hand: true,
+        rules: DEFAULT_RULES,
     };
     assert!(3.5 > hit_expectation(&mut state));
 
@@ -695,14 +926,16 @@ fn test_invalid_hit_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
             size: 1,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     hit_expectation(&mut state);
 }
 
-fn insurance_expectation(mut state: &mut GameState) -> f32 {
+fn insurance_expectation(state: &mut GameState) -> f32 {
     assert!(state.dealer == vec![1] && state.player.len() == 2 && !state.is_split);
     let mut total_expectation = 0.0;
     if score(&state.player) == Score::Natural {
@@ -726,19 +959,23 @@ fn test_insurance_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
             size: 10,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(0.0 == insurance_expectation(&mut state));
 
     state = GameState {
         player: vec![1, 10],
         dealer: vec![1],
-        deck: FULL_DECK.clone(),
+        deck: FULL_DECK,
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(1.0 == insurance_expectation(&mut state));
 
@@ -749,9 +986,11 @@ fn test_insurance_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 4, 0],
             size: 4,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(-1.5 == insurance_expectation(&mut state));
 
@@ -762,9 +1001,11 @@ fn test_insurance_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 2, 0, 2],
             size: 4,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     assert!(-0.25 == insurance_expectation(&mut state));
 }
@@ -779,9 +1020,11 @@ fn test_non_ace_insurance_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
             size: 1,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     insurance_expectation(&mut state);
 }
@@ -796,9 +1039,11 @@ fn test_non_starting_insurance_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
             size: 10,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     insurance_expectation(&mut state);
 }
@@ -813,9 +1058,11 @@ fn test_first_split_hand_insurance_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
             size: 10,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: true,
         first_split_hand: true,
+        rules: DEFAULT_RULES,
     };
     insurance_expectation(&mut state);
 }
@@ -830,25 +1077,45 @@ fn test_is_split_insurance_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
             size: 10,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: true,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     insurance_expectation(&mut state);
 }
 
+// The cut card only ends the shoe between rounds. During a round the dealer
+// keeps dealing until the shoe is empty, then shuffles the discards.
 fn will_reshuffle(deck: &Deck) -> bool {
-    false
+    deck.size == 0
 }
 
-fn reshuffle_deck(mut state: &mut GameState) {
-    state.deck = FULL_DECK.clone();
-    state.deck.draw(state.player[0]);
-    state.deck.draw(state.player[0]);
-    state.deck.draw(state.dealer[0]);
-    if state.dealer.len() == 2 {
-        state.deck.draw(state.dealer[1]);
+// A shoe made of the discards: every card of `shoe` but the ones in `player`
+// and `dealer`.
+fn reshuffled(shoe: &Deck, player: &[u16], dealer: &[u16]) -> Deck {
+    let mut deck = *shoe;
+    for &card in player.iter().chain(dealer.iter()) {
+        deck.draw(card);
     }
+    deck
+}
+
+fn reshuffle_deck(state: &mut GameState) {
+    state.deck = reshuffled(&state.shoe, &state.player, &state.dealer);
+}
+
+// Works out `expectation` with the discards shuffled back into the shoe, and
+// puts the shoe back as it was.
+fn reshuffled_expectation<T, F>(state: &mut GameState, expectation: F) -> T
+    where F: Fn(&mut GameState) -> T
+{
+    let deck = state.deck;
+    reshuffle_deck(state);
+    let value = expectation(state);
+    state.deck = deck;
+    value
 }
 
 #[test]
@@ -857,51 +1124,107 @@ fn test_reshuffle_deck() {
         player: vec![10, 3],
         dealer: vec![1],
         deck: Deck {
-            cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
-            size: 10,
+            cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            size: 0,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
+    assert!(will_reshuffle(&state.deck));
+    reshuffle_deck(&mut state);
+    assert!(!will_reshuffle(&state.deck));
+    assert!(413 == state.deck.size);
+    assert!(31 == state.deck.cards[0]);
+    assert!(31 == state.deck.cards[2]);
+    assert!(127 == state.deck.cards[9]);
+
+    state.player = vec![10, 3, 3];
+    state.dealer = vec![1, 4];
     reshuffle_deck(&mut state);
-    assert!(49 == state.deck.size);
-    assert!(3 == state.deck.cards[0]);
+    assert!(411 == state.deck.size);
+    assert!(31 == state.deck.cards[0]);
+    assert!(30 == state.deck.cards[2]);
+    assert!(31 == state.deck.cards[3]);
+    assert!(127 == state.deck.cards[9]);
+}
 
-    state = GameState {
-        player: vec![10, 3],
-        dealer: vec![1, 4],
+#[test]
+fn test_mid_round_reshuffle() {
+    // The dealer's hole card is the last card in the shoe, and with 16 the
+    // dealer has to draw from the discards.
+    let mut state = GameState {
+        player: vec![10, 6],
+        dealer: vec![10],
         deck: Deck {
-            cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
-            size: 10,
+            cards: [0, 0, 0, 0, 0, 1, 0, 0, 0, 0],
+            size: 1,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
-    reshuffle_deck(&mut state);
-    assert!(48 == state.deck.size);
-    assert!(3 == state.deck.cards[0]);
-    assert!(3 == state.deck.cards[3])
-}
-
-fn split_expectation(mut state: &mut GameState) -> f32 {
+    let mut discards = reshuffled(&FULL_DECK, &[10, 6], &[10, 6]);
+    let rest = reshuffled(&FULL_DECK, &[10, 6], &[]);
+    let bust: f32 = dealer_scores(&mut discards, &mut vec![10, 6], &rest, false, &DEFAULT_RULES)
+        .iter()
+        .filter(|&(&score, _)| score == Score::Bust)
+        .map(|(_, prob)| prob)
+        .sum();
+    assert!((stand_expectation(&mut state) - (2.0 * bust - 1.0)).abs() < 1e-6);
+    assert!(1 == state.deck.size);
+
+    // Hitting 12 takes the last card, and the rest of the round is dealt from
+    // the discards. The shoe is put back afterwards.
+    state.player = vec![10, 2];
+    let hit = hit_expectation(&mut state);
+    assert!(hit.is_finite() && hit > -1.0 && hit < 1.0);
+    assert!(1 == state.deck.size && vec![10, 2] == state.player);
+}
+
+// Each hand of a split is played from the deck as it stands, with the other
+// hand's card on the table and out of any reshuffle.
+fn split_expectation(state: &mut GameState) -> f32 {
     assert!(state.player.len() == 2 && state.player[0] == state.player[1] && !state.is_split &&
             !state.failed_insurance);
     let mut total_expectation = 0.0;
     state.is_split = true;
-    state.player.pop();
-    let original_deck = state.deck.clone();
-    state.deck = FULL_DECK.clone();
-    total_expectation += 2.0 * hit_expectation(&mut state);
+    let player_card = state.player.pop().unwrap();
+    state.shoe.draw(player_card);
+    total_expectation += 2.0 * hit_expectation(state);
     // println!("Expectation after first hand: {}", total_expectation);
     state.is_split = false;
-    let player_card = state.player[0];
+    state.shoe.replace(player_card);
     state.player.push(player_card);
-    state.deck = original_deck;
     total_expectation
 }
 
+#[test]
+fn test_split_keeps_deck() {
+    // Nothing but tens left, so each eight makes 18 against the dealer's 20
+    // and is surrendered.
+    let tens = Deck {
+        cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 30],
+        size: 30,
+    };
+    let mut state = GameState {
+        player: vec![8, 8],
+        dealer: vec![10],
+        deck: tens,
+        shoe: FULL_DECK,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules: DEFAULT_RULES,
+    };
+    assert!(-1.0 == split_expectation(&mut state));
+    assert!(tens == state.deck && FULL_DECK == state.shoe && vec![8, 8] == state.player);
+}
+
 // #[test]
 // fn test_split_expectation() {
 //     let mut state = GameState {
@@ -915,7 +1238,7 @@ fn split_expectation(mut state: &mut GameState) -> f32 {
 //         is_split: false,
 //         first_split_hand: false,
 //     };
-//     assert!(3.5 == split_expectation(&mut state));
+//     assert!(3.5 == split_expectation(state));
 
 //     state = GameState {
 //         player: vec![10, 10],
@@ -928,7 +1251,7 @@ fn split_expectation(mut state: &mut GameState) -> f32 {
 //         is_split: false,
 //         first_split_hand: false,
 //     };
-//     assert!(-1.5 == split_expectation(&mut state));
+//     assert!(-1.5 == split_expectation(state));
 
 //     state = GameState {
 //         player: vec![1, 1],
@@ -941,7 +1264,7 @@ fn split_expectation(mut state: &mut GameState) -> f32 {
 //         is_split: false,
 //         first_split_hand: false,
 //     };
-//     assert!(0.0 == split_expectation(&mut state));
+//     assert!(0.0 == split_expectation(state));
 
 //     state = GameState {
 //         player: vec![1, 1],
@@ -954,7 +1277,7 @@ fn split_expectation(mut state: &mut GameState) -> f32 {
 //         is_split: false,
 //         first_split_hand: false,
 //     };
-//     assert!(3.0 > split_expectation(&mut state));
+//     assert!(3.0 > split_expectation(state));
 // }
 
 #[test]
@@ -967,9 +1290,11 @@ fn test_not_two_cards_split_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
             size: 10,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     split_expectation(&mut state);
 }
@@ -984,9 +1309,11 @@ fn test_not_same_cards_split_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
             size: 10,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     split_expectation(&mut state);
 }
@@ -1001,9 +1328,11 @@ fn test_not_is_split_split_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
             size: 10,
         },
+        shoe: FULL_DECK,
         failed_insurance: false,
         is_split: true,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     split_expectation(&mut state);
 }
@@ -1018,14 +1347,16 @@ fn test_not_failed_insurance_split_expectation() {
             cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
             size: 10,
         },
+        shoe: FULL_DECK,
         failed_insurance: true,
         is_split: false,
         first_split_hand: false,
+        rules: DEFAULT_RULES,
     };
     split_expectation(&mut state);
 }
 
-fn cannot_hit(player: &Vec<u16>) -> bool {
+fn cannot_hit(player: &[u16]) -> bool {
     player.len() == 6 || min_hand_value(player) >= 21
 }
 
@@ -1035,11 +1366,13 @@ fn can_insurance(state: &GameState) -> bool {
 }
 
 fn can_surrender(state: &GameState) -> bool {
-    state.player.len() == 2 && !state.first_split_hand && !state.failed_insurance
+    state.rules.surrender && state.player.len() == 2 && !state.first_split_hand &&
+    !state.failed_insurance
 }
 
 fn can_double(state: &GameState) -> bool {
-    state.player.len() == 2 && !state.failed_insurance
+    state.player.len() == 2 && !state.failed_insurance &&
+    (!state.is_split || state.rules.double_after_split)
 }
 
 fn can_split(state: &GameState) -> bool {
@@ -1051,30 +1384,117 @@ fn max(m: f32, n: f32) -> f32 {
     if m > n { m } else { n }
 }
 
-fn expectation(mut state: &mut GameState) -> f32 {
-    let mut max_expectation = stand_expectation(&mut state);
+fn expectation(state: &mut GameState) -> f32 {
+    let mut max_expectation = stand_expectation(state);
     if cannot_hit(&state.player) {
         return max_expectation;
     }
     if can_insurance(state) {
-        max_expectation = max(max_expectation, insurance_expectation(&mut state));
+        max_expectation = max(max_expectation, insurance_expectation(state));
     }
     if can_surrender(state) {
         max_expectation = max(max_expectation, -0.5);
     }
     if can_double(state) {
-        max_expectation = max(max_expectation, double_expectation(&mut state));
+        max_expectation = max(max_expectation, double_expectation(state));
     }
     if can_split(state) {
-        max_expectation = max(max_expectation, split_expectation(&mut state));
+        max_expectation = max(max_expectation, split_expectation(state));
     }
-    let best_expectation = max(max_expectation, hit_expectation(&mut state));
+    let best_expectation = max(max_expectation, hit_expectation(state));
     // println!("Game state: {:?}", &state);
     // println!("Expectation: {}", best_expectation);
     best_expectation
 }
 
-fn player_hand_expectation(mut state: &mut GameState) -> f32 {
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash,PartialOrd,Ord)]
+enum Action {
+    Stand,
+    Hit,
+    Double,
+    Split,
+    Surrender,
+    Insurance,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match *self {
+            Action::Stand => "Stand",
+            Action::Hit => "Hit",
+            Action::Double => "Double",
+            Action::Split => "Split",
+            Action::Surrender => "Surrender",
+            Action::Insurance => "Insurance",
+        })
+    }
+}
+
+fn action_expectations(state: &mut GameState) -> Vec<(Action, f32)> {
+    let mut expectations = vec![(Action::Stand, stand_expectation(state))];
+    if cannot_hit(&state.player) {
+        return expectations;
+    }
+    expectations.push((Action::Hit, hit_expectation(state)));
+    if can_double(state) {
+        expectations.push((Action::Double, double_expectation(state)));
+    }
+    if can_split(state) {
+        expectations.push((Action::Split, split_expectation(state)));
+    }
+    if can_surrender(state) {
+        expectations.push((Action::Surrender, -0.5));
+    }
+    if can_insurance(state) {
+        expectations.push((Action::Insurance, insurance_expectation(state)));
+    }
+    expectations
+}
+
+#[test]
+fn test_action_expectations() {
+    let mut state = GameState {
+        player: vec![10, 9],
+        dealer: vec![1],
+        deck: Deck {
+            cards: [0, 0, 0, 0, 0, 0, 0, 0, 2, 2],
+            size: 4,
+        },
+        shoe: FULL_DECK,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules: DEFAULT_RULES,
+    };
+    let expectations = action_expectations(&mut state);
+    let actions: Vec<Action> = expectations.iter().map(|&(action, _)| action).collect();
+    assert!(vec![Action::Stand,
+                 Action::Hit,
+                 Action::Double,
+                 Action::Surrender,
+                 Action::Insurance] == actions);
+    assert!(expectations[0].1 == stand_expectation(&mut state));
+    assert!(-0.5 == expectations[3].1);
+
+    state = GameState {
+        player: vec![10, 10, 1],
+        dealer: vec![10],
+        deck: FULL_DECK,
+        shoe: FULL_DECK,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules: DEFAULT_RULES,
+    };
+    let expectations = action_expectations(&mut state);
+    assert!(1 == expectations.len());
+    assert!(Action::Stand == expectations[0].0);
+}
+
+fn player_hand_expectation(state: &mut GameState) -> f32 {
+    if will_reshuffle(&state.deck) {
+        return reshuffled_expectation(state, player_hand_expectation);
+    }
     let mut total_expectation = 0.0;
     for card in 1..11 {
         let draw_prob = state.deck.card_prob(card, false);
@@ -1082,21 +1502,25 @@ fn player_hand_expectation(mut state: &mut GameState) -> f32 {
             continue;
         }
         state.deck.draw_to(&mut state.dealer, card);
-        total_expectation += draw_prob * expectation(&mut state);
+        total_expectation += draw_prob * expectation(state);
         state.deck.replace_from(&mut state.dealer, card);
     }
     total_expectation
 }
 
-fn deck_expectation(deck: Deck) -> f32 {
+// The player's expectation for a round dealt from `deck`, what's left of
+// `shoe`.
+fn deck_expectation(shoe: &Deck, deck: Deck, rules: &Rules) -> f32 {
     let mut total_expectation = 0.0;
     let mut state = GameState {
         player: vec![],
         dealer: vec![],
-        deck: deck,
+        deck,
+        shoe: *shoe,
         failed_insurance: false,
         is_split: false,
         first_split_hand: false,
+        rules: *rules,
     };
     for card1 in 1..11 {
         let draw_prob1 = state.deck.card_prob(card1, false);
@@ -1111,7 +1535,6 @@ fn deck_expectation(deck: Deck) -> f32 {
             }
             state.deck.draw_to(&mut state.player, card2);
             let hand_expectation = player_hand_expectation(&mut state);
-            println!("Expectation for {},{}: {}", card1, card2, hand_expectation);
             if card1 == card2 {
                 total_expectation += draw_prob1 * draw_prob2 * hand_expectation;
             } else {
@@ -1124,26 +1547,18 @@ fn deck_expectation(deck: Deck) -> f32 {
     total_expectation
 }
 
-fn all_deck_expectations() -> () {
-    let mut deck = FULL_DECK.clone();
-    println!("Full deck expectation: {}", deck_expectation(deck));
+// Prints the expectation of `shoe`, and of it with one card of each rank out.
+fn all_deck_expectations(shoe: &Deck, rules: &Rules) {
+    let mut deck = *shoe;
+    println!("Full deck expectation: {}", deck_expectation(shoe, deck, rules));
     for card in 1..11 {
         deck.draw(card);
-        println!("Expectation without {}: {}", card, deck_expectation(deck));
+        println!("Expectation without {}: {}", card, deck_expectation(shoe, deck, rules));
         deck.replace(card);
     }
 }
 
-// Want to make getting samples cleaner, but not worth the time atm
-// fn stringrecord_to_deck(record: &csv::StringRecord) -> Deck {
-//     let card_iter = record.iter().take(10);
-//     return Deck {
-//         cards: card_iter.collect()::Vec<u16>.to_slice(),
-//         size: card_iter.sum(),
-//     };
-// }
-
-fn deck_samples(filename: &str) -> Result<Vec<Deck>, Box<Error>> {
+fn deck_samples(filename: &str) -> Result<Vec<Deck>, Box<dyn Error>> {
     let mut reader = csv::Reader::from_path(filename)?;
     let mut decks = vec![];
     for record in reader.deserialize() {
@@ -1160,97 +1575,204 @@ fn deck_samples(filename: &str) -> Result<Vec<Deck>, Box<Error>> {
                                                                                      u16) = record?;
         let cards = [aces, twos, threes, fours, fives, sixes, sevens, eights, nines, tens];
         decks.push(Deck {
-            cards: cards,
+            cards,
             size: cards.iter().sum(),
         });
     }
-    return Ok(decks);
+    Ok(decks)
 }
 
-fn computed_decks(filename: &str) -> Result<HashSet<Deck>, Box<Error>> {
-    let mut reader = csv::Reader::from_path(filename)?;
-    let mut decks = HashSet::new();
-    for record in reader.deserialize() {
-        // ew
-        let (aces, twos, threes, fours, fives, sixes, sevens, eights, nines, tens, _): (u16,
-                                                                                        u16,
-                                                                                        u16,
-                                                                                        u16,
-                                                                                        u16,
-                                                                                        u16,
-                                                                                        u16,
-                                                                                        u16,
-                                                                                        u16,
-                                                                                        u16,
-                                                                                        f32) =
-            record?;
-        let cards = [aces, twos, threes, fours, fives, sixes, sevens, eights, nines, tens];
-        decks.insert(Deck {
-            cards: cards,
-            size: cards.iter().sum(),
-        });
-    }
-    return Ok(decks);
-}
-
-fn random_deck(samples_path: &str, data_path: &str) -> Result<Deck, Box<Error>> {
-    let decks = deck_samples(samples_path)?;
-    let computed_decks = computed_decks(data_path)?;
-    // println!("Computed decks: {:?}", computed_decks);
-    let mut rng = rand::thread_rng();
-    let indices = Range::new(0, decks.len());
-    loop {
-        let index = indices.ind_sample(&mut rng);
-        if computed_decks.contains(&decks[index]) {
-            continue;
-        }
-        return Ok(decks[index]);
-    }
-}
-
-fn append_advantage_data(data_path: &str, deck: Deck, advantage: f32) -> Result<(), Box<Error>> {
-    let mut writer =
-        csv::Writer::from_writer(OpenOptions::new().append(true).open(data_path).unwrap());
-    let mut record =
-        csv::StringRecord::from_iter(deck.cards.iter().map(|card: &u16| card.to_string()));
-    record.push_field(&advantage.to_string());
-    writer.write_record(record.iter())?;
-    Ok(())
-}
-
-fn continuously_compute_deck_advantages(samples_path: &str, data_path: &str) -> () {
-    loop {
-        let deck = random_deck(samples_path, data_path).unwrap();
+// Computes advantages of decks from the queue, with the rules of its dataset,
+// until there are none left.
+fn continuously_compute_deck_advantages(queue: &queue::WorkQueue) -> Result<(), Box<dyn Error>> {
+    let rules = queue.metadata().rules;
+    let shoe = full_shoe(queue.metadata().decks);
+    while let Some(deck) = queue.claim()? {
         println!("Computing the advantage of {:?}", deck);
         let start = PreciseTime::now();
-        let advantage = deck_expectation(deck);
+        let advantage = match panic::catch_unwind(|| deck_expectation(&shoe, deck, &rules)) {
+            Ok(advantage) => advantage,
+            Err(_) => {
+                queue.release(&deck)?;
+                return Err(format!("Computing the advantage of {:?} panicked", deck).into());
+            }
+        };
         let end = PreciseTime::now();
         println!("The advantage of {:?} is {}, ({} seconds)",
                  deck,
                  advantage,
                  start.to(end).num_seconds());
-        append_advantage_data(data_path, deck, advantage).unwrap();
+        let seconds = start.to(end).num_milliseconds() as f32 / 1000.0;
+        queue.complete(deck, advantage, seconds)?;
     }
+    Ok(())
 }
 
+// A shoe of `decks` decks.
+fn full_shoe(decks: u16) -> Deck {
+    let mut deck = Deck {
+        cards: [4 * decks; 10],
+        size: 52 * decks,
+    };
+    deck.cards[card_index(10)] = 16 * decks;
+    deck
+}
+
+// The shoe the decks in a data file were dealt from, or `decks` decks for
+// legacy data. Exits if some of `data` couldn't have been dealt from it.
+fn data_shoe(path: &Path, decks: u16, data: &[(Deck, f32)]) -> Deck {
+    let shoe = dataset::read_shoe(path, decks).unwrap();
+    if let Some(&(deck, _)) = data.iter().find(|&&(deck, _)| !shoe.contains(&deck)) {
+        eprintln!("{} can't have been dealt from a {} deck shoe",
+                  dataset::format_counts(&deck),
+                  shoe.size / 52);
+        process::exit(1);
+    }
+    shoe
+}
 
 #[derive(Eq,PartialEq,Hash,Debug,Clone)]
 struct OrderedDeck {
     cards: Vec<u16>,
     deck: Deck,
+    // Cards played since the last shuffle, waiting to be shuffled back in.
+    discards: Vec<u16>,
+    // Cards burned since the last shuffle, which go face down with the
+    // discards and so are still unseen.
+    burned: Vec<u16>,
+    // How many times the discards have been shuffled back in.
+    shuffles: usize,
 }
 
 impl OrderedDeck {
+    // A shoe of `decks` decks in random order.
+    fn shuffled<R: Rng>(decks: u16, rng: &mut R) -> OrderedDeck {
+        let deck = full_shoe(decks);
+        let mut cards = vec![];
+        for card in 1..11 {
+            cards.extend(std::iter::repeat_n(card, deck.cards[card_index(card)] as usize));
+        }
+        rng.shuffle(&mut cards);
+        OrderedDeck {
+            cards,
+            deck,
+            discards: vec![],
+            burned: vec![],
+            shuffles: 0,
+        }
+    }
     fn draw(&mut self) -> u16 {
         let card = self.cards.pop().unwrap();
         self.deck.draw(card);
         card
     }
+    // Burns the next card without showing it.
+    fn burn(&mut self) {
+        let card = self.draw();
+        self.burned.push(card);
+    }
+    // The cards nobody has seen: what's left of the shoe and the burned cards.
+    fn unseen(&self) -> Deck {
+        let mut unseen = self.deck;
+        for &card in self.burned.iter() {
+            unseen.replace(card);
+        }
+        unseen
+    }
+    // Draws a card, first shuffling the discards into the shoe if it's empty.
+    fn deal<R: Rng>(&mut self, rng: &mut R) -> u16 {
+        if self.cards.is_empty() {
+            self.reshuffle(rng);
+        }
+        self.draw()
+    }
+    fn discard(&mut self, cards: &[u16]) {
+        self.discards.extend_from_slice(cards);
+    }
+    // Shuffles `cards` into the shoe, anywhere but among the `ahead` cards
+    // that will be dealt next.
+    fn insert<R: Rng>(&mut self, cards: &[u16], ahead: usize, rng: &mut R) {
+        for &card in cards {
+            let end = self.cards.len().saturating_sub(ahead);
+            self.cards.insert(rng.gen_range(0, end + 1), card);
+            self.deck.replace(card);
+        }
+    }
+    // Shuffles the discards and burned cards in with what's left of the shoe.
+    fn reshuffle<R: Rng>(&mut self, rng: &mut R) {
+        for &card in self.discards.iter().chain(self.burned.iter()) {
+            self.deck.replace(card);
+        }
+        self.cards.append(&mut self.discards);
+        self.cards.append(&mut self.burned);
+        rng.shuffle(&mut self.cards);
+        self.shuffles += 1;
+    }
+}
+
+#[test]
+fn test_shuffled() {
+    let mut shoe = OrderedDeck::shuffled(8, &mut rand::thread_rng());
+    assert!(FULL_DECK == shoe.deck);
+    assert!(416 == shoe.cards.len());
+    let card = shoe.draw();
+    assert!(415 == shoe.deck.size);
+    assert!(FULL_DECK.cards[card_index(card)] - 1 == shoe.deck.cards[card_index(card)]);
+    let mut counts = [0; 10];
+    for &card in shoe.cards.iter() {
+        counts[card_index(card)] += 1;
+    }
+    assert!(counts == shoe.deck.cards);
+
+    let mut rng = rand::thread_rng();
+    // Burned cards come out of the shoe but stay unseen, until a reshuffle
+    // puts them back.
+    let mut shoe = OrderedDeck::shuffled(1, &mut rng);
+    shoe.burn();
+    let card = shoe.draw();
+    shoe.discard(&[card]);
+    assert!(50 == shoe.deck.size && 51 == shoe.unseen().size);
+    assert!(full_shoe(1).cards[card_index(card)] == shoe.unseen().cards[card_index(card)] + 1);
+    shoe.reshuffle(&mut rng);
+    assert!(shoe.burned.is_empty() && full_shoe(1) == shoe.unseen() && 52 == shoe.cards.len());
+
+    let mut shoe = OrderedDeck {
+        cards: vec![5],
+        deck: Deck {
+            cards: [0, 0, 0, 0, 1, 0, 0, 0, 0, 0],
+            size: 1,
+        },
+        discards: vec![],
+        burned: vec![],
+        shuffles: 0,
+    };
+    shoe.discard(&[2, 3]);
+    assert!(5 == shoe.deal(&mut rng));
+    let card = shoe.deal(&mut rng);
+    assert!((card == 2 || card == 3) && shoe.discards.is_empty() && 1 == shoe.shuffles);
+    assert!(1 == shoe.deck.size && 1 == shoe.cards.len());
+    assert!(1 == shoe.deck.cards[card_index(5 - card)]);
+
+    let mut shoe = OrderedDeck {
+        cards: vec![2, 3, 4],
+        deck: Deck {
+            cards: [0, 1, 1, 1, 0, 0, 0, 0, 0, 0],
+            size: 3,
+        },
+        discards: vec![],
+        burned: vec![],
+        shuffles: 0,
+    };
+    shoe.insert(&[10, 10, 10], 2, &mut rng);
+    assert!(6 == shoe.deck.size && 3 == shoe.deck.cards[9]);
+    assert!(4 == shoe.deal(&mut rng) && 3 == shoe.deal(&mut rng));
+    shoe.insert(&[1], 10, &mut rng);
+    assert!(1 == shoe.cards[0]);
 }
 
 fn parse_deck(deck_str: &str) -> Deck {
     let mut cards: Vec<u16> = deck_str.chars().map(|letter| letter.to_digit(10).unwrap() as u16).collect();
-    if (cards.len() == 11) {
+    if cards.len() == 11 {
         cards[9] = 10 + cards[10];
         cards.pop();
     }
@@ -1269,7 +1791,7 @@ fn parse_hand(hand_str: &str) -> Vec<u16> {
         .map(|letter| letter.to_digit(10).unwrap())
         .map(|num| {
             if num == 0 {
-                10 as u16
+                10
             } else {
                 num as u16
             }
@@ -1277,89 +1799,747 @@ fn parse_hand(hand_str: &str) -> Vec<u16> {
         .collect()
 }
 
-fn best_action(mut state: &mut GameState) -> String {
-    let mut best_expectation = stand_expectation(&mut state);
-    let mut best_action = "Stand";
-    let hit_exp = hit_expectation(&mut state);
-    if hit_exp > best_expectation {
-        best_expectation = hit_exp;
-        best_action = "Hit";
-    }
-    if can_double(state) {
-        let double_exp = double_expectation(&mut state);
-        if double_exp > best_expectation {
-            best_expectation = double_exp;
-            best_action = "Double";
+#[derive(Debug,PartialEq,Clone)]
+struct ActionReport {
+    expectations: Vec<(Action, f32)>,
+    best: Action,
+}
+
+impl ActionReport {
+    // The first action with the highest expectation is the best one.
+    fn new(expectations: Vec<(Action, f32)>) -> ActionReport {
+        let mut best = expectations[0];
+        for &(action, exp) in expectations.iter() {
+            if exp > best.1 {
+                best = (action, exp);
+            }
+        }
+        ActionReport {
+            expectations,
+            best: best.0,
         }
     }
-    if can_split(state) {
-        let split_exp = split_expectation(&mut state);
-        if split_exp > best_expectation {
-            best_expectation = split_exp;
-            best_action = "Split";
+    fn expectation(&self, action: Action) -> Option<f32> {
+        self.expectations.iter().find(|&&(a, _)| a == action).map(|&(_, exp)| exp)
+    }
+    fn best_expectation(&self) -> f32 {
+        self.expectation(self.best).unwrap()
+    }
+    // How much better the best action is than the runner-up, or zero if it's
+    // the only legal action.
+    fn margin(&self) -> f32 {
+        let runner_up = self.expectations
+            .iter()
+            .filter(|&&(action, _)| action != self.best)
+            .map(|&(_, exp)| exp)
+            .fold(f32::NEG_INFINITY, f32::max);
+        if runner_up == f32::NEG_INFINITY {
+            0.0
+        } else {
+            self.best_expectation() - runner_up
         }
     }
-    String::from(best_action)
+}
+
+#[test]
+fn test_action_report() {
+    let report = ActionReport::new(vec![(Action::Stand, -0.54),
+                                        (Action::Hit, -0.53),
+                                        (Action::Surrender, -0.5)]);
+    assert!(Action::Surrender == report.best);
+    assert!(-0.5 == report.best_expectation());
+    assert!(Some(-0.54) == report.expectation(Action::Stand));
+    assert!(report.expectation(Action::Double).is_none());
+    assert!((report.margin() - 0.03).abs() < 1e-6);
+
+    let report = ActionReport::new(vec![(Action::Stand, 0.5), (Action::Hit, 0.5)]);
+    assert!(Action::Stand == report.best);
+    assert!(0.0 == report.margin());
+
+    let report = ActionReport::new(vec![(Action::Stand, 1.0)]);
+    assert!(0.0 == report.margin());
+}
+
+fn best_action(state: &mut GameState) -> ActionReport {
+    ActionReport::new(action_expectations(state))
+}
+
+#[test]
+fn test_best_action() {
+    let mut state = GameState {
+        player: vec![10, 6],
+        dealer: vec![10, 9],
+        deck: Deck {
+            cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
+            size: 10,
+        },
+        shoe: FULL_DECK,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules: DEFAULT_RULES,
+    };
+    let report = best_action(&mut state);
+    assert!(Action::Surrender == report.best);
+    assert!(0.5 == report.margin());
+    assert!(Some(-1.0) == report.expectation(Action::Stand));
+    assert!(Some(-1.0) == report.expectation(Action::Hit));
+    assert!(Some(-2.0) == report.expectation(Action::Double));
+    assert!(report.expectation(Action::Split).is_none());
+    assert!(report.expectation(Action::Insurance).is_none());
+
+    state = GameState {
+        player: vec![10, 10, 1],
+        dealer: vec![10],
+        deck: FULL_DECK,
+        shoe: FULL_DECK,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules: DEFAULT_RULES,
+    };
+    let report = best_action(&mut state);
+    assert!(Action::Stand == report.best);
+    assert!(1 == report.expectations.len());
+}
+
+// Plays the action with the best expectation for the cards it hasn't seen,
+// for simulations.
+fn optimal_strategy(state: &mut GameState) -> Action {
+    best_action(state).best
+}
+
+#[test]
+fn test_optimal_strategy() {
+    let mut state = GameState {
+        player: vec![10, 2],
+        dealer: vec![2],
+        deck: Deck {
+            cards: [0, 0, 0, 0, 0, 0, 0, 0, 1, 4],
+            size: 5,
+        },
+        shoe: FULL_DECK,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules: DEFAULT_RULES,
+    };
+    assert!(Action::Hit == basic::basic_strategy(&mut state));
+    assert!(Action::Stand == optimal_strategy(&mut state));
+    state.deck = Deck {
+        cards: [0, 0, 0, 0, 0, 0, 0, 4, 1, 0],
+        size: 5,
+    };
+    assert!(Action::Double == optimal_strategy(&mut state));
 }
 
 fn main() {
-    let exp = deck_expectation(FULL_DECK.clone());
-    println!("Deck expectation: {}", exp);
-    // PROFILER.lock().unwrap().start("./baseline-1,1v10.profile").unwrap();
-    // let mut state = GameState {
-    //     player: vec![10, 2],
-    //     dealer: vec![6],
-    //     deck: Deck {
-    //         cards: [4, 3, 4, 4, 4, 3, 4, 4, 4, 15],
-    //         size: 49,
-    //     },
-    //     failed_insurance: false,
-    //     is_split: false,
-    //     first_split_hand: false,
-    // };
-    // println!("Stand Expectation: {}", stand_expectation(&mut state));
-    // println!("Double Expectation: {}", double_expectation(&mut state));
-    // println!("Hit Expectation: {}", hit_expectation(&mut state));
-    // println!("Best action: {}", best_action(&mut state));
-    // all_deck_expectations();
-    // println!("Number of decks: {}",
-    //          deck_samples("/home/chris/coding/advantage_calculator/large_decks.csv")
-    //              .unwrap()
-    //              .len());
-    // println!("Number of decks: {}",
-    //          computed_decks("/home/chris/coding/advantage_calculator/data.csv").unwrap().len());
-    // println!("Random deck: {:?}",
-    //          random_deck("/home/chris/coding/advantage_calculator/large_decks.csv",
-    //                      "/home/chris/coding/advantage_calculator/data.csv")
-    //              .unwrap());
-    // println!("Path: {:?}", Path::new("./decks.csv"));
-    // println!("The current directory is {}",
-    //          env::current_dir().unwrap().display());
-    // let args: Vec<String> = env::args().collect();
-    // let num_threads = args[1].parse::<i32>().unwrap();
-    // let mut handles = vec![];
-    // for _ in 1..(num_threads + 1) {
-    //     handles.push(thread::spawn(move || {
-    //         continuously_compute_deck_advantages("./decks.csv", "./data.csv");
-    //     }));
-    // }
-    // for handle in handles {
-    //     let _ = handle.join();
-    // }
-
-    // continuously_compute_deck_advantages("/home/chris/coding/advantage_calculator/large_decks.csv",
-    //                                      "/home/chris/coding/blackjack_sim/data.csv");
-    // println!("{:?}",
-    //          computed_decks("/home/chris/coding/blackjack_sim/data.csv")
-    //              .unwrap()
-    //              .contains(&FULL_DECK));
-
-    // let decks = deck_set("/home/chris/coding/advantage_calculator/large_decks.csv");
-    // for _ in 1..100 {
-    //     println!("Random deck: {:?}",
-    //              random_deck("/home/chris/coding/advantage_calculator/large_decks.csv",
-    //                          "/home/chris/coding/blackjack_sim/data.csv"));
-    // }
-    // Code you want to sample goes here!
-    // PROFILER.lock().unwrap().stop().unwrap();
+    let mut rules = DEFAULT_RULES;
+    // The number of decks in the shoe, for the tools that aren't told it
+    // otherwise.
+    let mut decks = 8;
+    // How many cards the simulations burn after each shuffle.
+    let mut burn = 1;
+    let args: Vec<String> = env::args()
+        .filter(|arg| if let Some(rules_str) = arg.strip_prefix("--rules=") {
+            rules = parse_rules(rules_str);
+            false
+        } else if let Some(decks_str) = arg.strip_prefix("--decks=") {
+            decks = decks_str.parse::<u16>().unwrap();
+            false
+        } else if let Some(burn_str) = arg.strip_prefix("--burn=") {
+            burn = burn_str.parse::<usize>().unwrap();
+            false
+        } else {
+            true
+        })
+        .collect();
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("all-deck-expectations") => all_deck_expectations(&full_shoe(decks), &rules),
+        Some("best-action") => {
+            let mut state = GameState {
+                player: parse_hand(&args[3]),
+                dealer: parse_hand(&args[4]),
+                deck: parse_deck(&args[2]),
+                shoe: full_shoe(decks),
+                failed_insurance: false,
+                is_split: false,
+                first_split_hand: false,
+                rules,
+            };
+            let report = best_action(&mut state);
+            for &(action, exp) in report.expectations.iter() {
+                println!("{}: {}", action, exp);
+            }
+            println!("Best action: {} (by {})", report.best, report.margin());
+            let moments = variance::hand_moments(&mut state);
+            println!("Expectation: {}, variance: {}",
+                     moments.expectation,
+                     moments.variance);
+        }
+        Some("compute") => {
+            let num_threads = args[2].parse::<i32>().unwrap();
+            // With a count system, samples are stratified by its true count
+            // and by penetration.
+            let strata = args.get(5).map(|system| {
+                let mut strata = strata::Strata {
+                    system: counts::parse_system(system),
+                    shoe: full_shoe(decks),
+                    true_count_width: 1.0,
+                    penetration_width: 0.1,
+                    target: 10,
+                    targets: HashMap::new(),
+                };
+                if let Some(targets) = args.get(6) {
+                    if let Err(err) = strata.parse_targets(targets) {
+                        eprintln!("Bad targets {}: {}", targets, err);
+                        process::exit(1);
+                    }
+                }
+                strata
+            });
+            // A claim left this long without a result is from a worker that
+            // died.
+            let stale_after = Duration::from_secs(24 * 60 * 60);
+            let metadata = dataset::Metadata::current(&rules, decks);
+            let queue = queue::WorkQueue::open(&args[3], &args[4], &metadata, strata, stale_after)
+                .unwrap();
+            thread::scope(|scope| {
+                for _ in 0..num_threads {
+                    scope.spawn(|| if let Err(err) = continuously_compute_deck_advantages(&queue) {
+                        eprintln!("Worker stopped: {}", err);
+                    });
+                }
+            });
+        }
+        Some("coordinate") => {
+            let timeout = args.get(5).map_or(600, |arg| arg.parse::<u64>().unwrap());
+            let timeout = Duration::from_secs(timeout);
+            // The coordinator owns the data file, so its claims from before a
+            // restart are stale as soon as their workers would have been.
+            let metadata = dataset::Metadata::current(&rules, decks);
+            let queue = queue::WorkQueue::open(&args[3], &args[4], &metadata, None, timeout)
+                .unwrap();
+            let listener = TcpListener::bind(&args[2]).unwrap();
+            coordinator::Coordinator::new(queue, timeout).serve(&listener).unwrap();
+        }
+        Some("work") => {
+            let num_threads = args.get(3).map_or(1, |arg| arg.parse::<i32>().unwrap());
+            let connection = coordinator::Connection {
+                address: args[2].as_str(),
+                interval: Duration::from_secs(60),
+                attempts: 20,
+            };
+            thread::scope(|scope| {
+                for _ in 0..num_threads {
+                    scope.spawn(|| {
+                        let advantage = |shoe: &Deck, deck, rules: &Rules| {
+                            deck_expectation(shoe, deck, rules)
+                        };
+                        match coordinator::work(&connection, advantage) {
+                            Ok(computed) => println!("Computed {} advantages", computed),
+                            Err(err) => eprintln!("Worker stopped: {}", err),
+                        }
+                    });
+                }
+            });
+        }
+        Some("migrate") => {
+            // Legacy data was computed for an eight deck shoe unless told
+            // otherwise.
+            let decks = args.get(4).map_or(8, |arg| arg.parse::<u16>().unwrap());
+            let mut metadata = dataset::Metadata::current(&rules, decks);
+            if let Some(engine) = args.get(5) {
+                metadata.engine = engine.clone();
+            }
+            let rows = dataset::migrate(Path::new(&args[2]), Path::new(&args[3]), &metadata)
+                .unwrap();
+            println!("Migrated {} rows", rows);
+        }
+        Some("merge") => {
+            let inputs: Vec<&Path> = args[3..].iter().map(Path::new).collect();
+            match dataset::merge(&inputs) {
+                Ok((metadata, rows)) => {
+                    dataset::write_dataset(Path::new(&args[2]), &metadata, &rows).unwrap();
+                    println!("Merged {} rows", rows.len());
+                }
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        Some("to-binary") => {
+            let rows = binary::csv_to_binary(Path::new(&args[2]), Path::new(&args[3])).unwrap();
+            println!("Converted {} rows", rows);
+        }
+        Some("to-csv") => {
+            let rows = binary::binary_to_csv(Path::new(&args[2]), Path::new(&args[3])).unwrap();
+            println!("Converted {} rows", rows);
+        }
+        Some("lookup") => {
+            let mut dataset = binary::BinaryDataset::open(Path::new(&args[2])).unwrap();
+            let deck = dataset::parse_counts(&args[3]).unwrap();
+            match dataset.lookup(&deck).unwrap() {
+                Some(row) => println!("{}", row.advantage),
+                None => {
+                    eprintln!("{} hasn't been computed", args[3]);
+                    process::exit(1);
+                }
+            }
+        }
+        Some("chart") => {
+            let chart = strategy::strategy_chart(&full_shoe(decks), parse_deck(&args[2]), rules);
+            match args.get(3).map(|arg| arg.as_str()) {
+                Some("csv") => print!("{}", render::chart_csv(&chart).unwrap()),
+                Some("markdown") => print!("{}", render::chart_markdown(&chart)),
+                Some("html") => print!("{}", render::chart_html(&chart)),
+                Some("svg") => print!("{}", render::chart_svg(&chart)),
+                _ => {
+                    for hand in strategy::chart_hands() {
+                        let actions: Vec<String> = strategy::UPCARDS.iter()
+                            .map(|&upcard| match chart.cell(hand, upcard) {
+                                Some(cell) => {
+                                    format!("{:>9} {:+.3}", cell.report.best, cell.report.best_expectation())
+                                }
+                                None => format!("{:>16}", "-"),
+                            })
+                            .collect();
+                        println!("{:<5} {}", hand, actions.join(" "));
+                    }
+                }
+            }
+        }
+        Some("counts") => {
+            let effects = eor::read_eor_csv(&args[2]).unwrap();
+            let find = |name: &str| {
+                effects.iter()
+                    .find(|row| row.name == name)
+                    .unwrap_or_else(|| panic!("No {} effects in {}", name, args[2]))
+            };
+            let (game, insurance) = (find("Game"), find("Insurance"));
+            let decisions: Vec<eor::Effects> = effects.iter()
+                .filter(|row| row.name != "Game" && row.name != "Insurance")
+                .cloned()
+                .collect();
+            let mut systems = counts::systems();
+            if let Some(tags) = args.get(3) {
+                systems.push(counts::parse_tags("Custom", tags));
+            }
+            println!("{:<12} {:>8} {:>6} {:>6} {:>6}", "System", "Balanced", "BC", "PE", "IC");
+            for system in systems {
+                let metrics = counts::metrics(&system, game, insurance, &decisions);
+                println!("{:<12} {:>8} {:>6.3} {:>6.3} {:>6.3}",
+                         system.name,
+                         if system.is_balanced() { "yes" } else { "no" },
+                         metrics.betting_correlation,
+                         metrics.playing_efficiency,
+                         metrics.insurance_correlation);
+            }
+        }
+        Some("indices") => {
+            let system = counts::parse_system(&args[2]);
+            let shoe = full_shoe(decks);
+            let remaining = args[3].parse::<u16>().unwrap();
+            if remaining == 0 || remaining > shoe.size {
+                eprintln!("Can't leave {} cards of a {} card shoe", remaining, shoe.size);
+                process::exit(1);
+            }
+            let risk_aversion = args.get(4).map_or(0.0, |arg| arg.parse::<f32>().unwrap());
+            let true_counts: Vec<f32> = (-10..11).map(|count| count as f32).collect();
+            println!("{} indices with {} cards left", system.name, remaining);
+            println!("Insurance: {}",
+                     indices::insurance_index(&shoe, &system, remaining, &true_counts));
+            for decision in eor::standard_decisions() {
+                if !decision.is_allowed(&rules) {
+                    continue;
+                }
+                let play = indices::decision_index(&shoe,
+                                                   rules,
+                                                   &system,
+                                                   &decision,
+                                                   remaining,
+                                                   &true_counts,
+                                                   risk_aversion);
+                println!("{}: {}", decision, play);
+            }
+        }
+        Some("optimize") => {
+            let data = dataset::read_data(Path::new(&args[2])).unwrap();
+            let shoe = data_shoe(Path::new(&args[2]), decks, &data);
+            let level = args[3].parse::<i32>().unwrap();
+            let ace_side_count = args.get(4).is_some_and(|arg| arg == "ace");
+            for found in optimizer::optimize(&shoe, &data, level, ace_side_count, 10) {
+                println!("{}{}: {:.4}",
+                         found.system.name,
+                         if found.ace_side_count { " with an ace side count" } else { "" },
+                         found.correlation);
+            }
+        }
+        Some("regress") => {
+            let data = dataset::read_data(Path::new(&args[2])).unwrap();
+            let kind = match args[3].as_str() {
+                "linear" => regression::Kind::Linear,
+                "quadratic" => regression::Kind::Quadratic,
+                kind => panic!("Unknown model {}", kind),
+            };
+            let shoe = data_shoe(Path::new(&args[2]), decks, &data);
+            let fit = regression::fit(kind, &shoe, &data).unwrap();
+            for (name, coefficient) in regression::term_names(kind)
+                .iter()
+                .zip(fit.model.coefficients.iter()) {
+                println!("{}: {}", name, coefficient);
+            }
+            println!("R squared: {}", fit.r_squared);
+            for band in fit.residuals.iter() {
+                println!("Penetration {:.0}%: {} shoes, mean residual {:+.5}, RMS {:.5}",
+                         band.penetration * 100.0,
+                         band.count,
+                         band.mean,
+                         band.root_mean_square);
+            }
+            fs::write(&args[4], regression::model_csv(&fit.model).unwrap()).unwrap();
+        }
+        Some("estimate") => {
+            let model = regression::parse_model(&fs::read_to_string(&args[2]).unwrap()).unwrap();
+            println!("Estimated advantage: {}", model.predict(&parse_deck(&args[3])));
+        }
+        Some("neighbours") => {
+            let data = dataset::read_data(Path::new(&args[2])).unwrap();
+            let effects = eor::read_eor_csv(&args[3]).unwrap();
+            let game = effects.iter().find(|effects| effects.name == "Game").unwrap();
+            let deck = dataset::parse_counts(&args[4]).unwrap();
+            let k = args.get(5).map_or(10, |arg| arg.parse::<usize>().unwrap());
+            match neighbours::Neighbours::new(game, &data).estimate(&deck, k) {
+                Some(estimate) => {
+                    println!("Estimated advantage: {} ± {} (nearest computed shoe {} away)",
+                             estimate.advantage,
+                             estimate.error,
+                             estimate.nearest)
+                }
+                None if deck.size == 0 => println!("An empty shoe has no advantage"),
+                None => println!("No advantages have been computed"),
+            }
+        }
+        Some("samples") => {
+            let samples = samples::generate_samples(args[3].parse::<u16>().unwrap(),
+                                                    args[4].parse::<f32>().unwrap(),
+                                                    args[2].parse::<usize>().unwrap(),
+                                                    args[5].parse::<usize>().unwrap(),
+                                                    &rules);
+            fs::write(&args[6], samples::samples_csv(&samples).unwrap()).unwrap();
+        }
+        Some("simulate") => {
+            let shoe = simulator::Shoe {
+                decks,
+                cut: simulator::parse_cut(&args[3]),
+                burn,
+            };
+            let mut summary = simulator::Summary::default();
+            simulator::simulate(&shoe,
+                                &rules,
+                                args[2].parse::<usize>().unwrap(),
+                                args[4].parse::<usize>().unwrap(),
+                                &basic::basic_strategy,
+                                |round| summary.add(round.result));
+            println!("{} rounds, expectation {} ± {} per round, standard deviation {}",
+                     summary.rounds,
+                     summary.mean(),
+                     summary.std_error(),
+                     summary.variance().sqrt());
+        }
+        Some("cut-card") => {
+            let shoe = simulator::Shoe {
+                decks,
+                cut: simulator::parse_cut(&args[3]),
+                burn,
+            };
+            let model = args.get(5).map(|model_path| {
+                regression::parse_model(&fs::read_to_string(model_path).unwrap()).unwrap()
+            });
+            let predict = |deck: &Deck| model.as_ref().unwrap().predict(deck);
+            let advantage = model.as_ref().map(|_| &predict as &dyn Fn(&Deck) -> f32);
+            let effect = cutcard::cut_card_effect(&shoe,
+                                                  &rules,
+                                                  args[2].parse::<usize>().unwrap(),
+                                                  args[4].parse::<usize>().unwrap(),
+                                                  advantage);
+            eprintln!("{:.2} rounds per shoe, cut card effect {:+.5} per round",
+                      effect.rounds_per_shoe(),
+                      effect.effect());
+            print!("{}", cutcard::cut_card_csv(&effect).unwrap());
+        }
+        Some("csm") => {
+            let csm = csm::Csm {
+                decks,
+                lag: args[3].parse::<usize>().unwrap(),
+                ahead: args[4].parse::<usize>().unwrap(),
+            };
+            let system = counts::parse_system(args.get(6).map_or("hi-lo", |arg| arg));
+            let variability = csm::csm_variability(&csm,
+                                                   &rules,
+                                                   args[2].parse::<usize>().unwrap(),
+                                                   args[5].parse::<usize>().unwrap(),
+                                                   &system);
+            let results = &variability.results;
+            let true_counts = &variability.true_counts;
+            println!("{} rounds, expectation {} ± {} per round",
+                     results.rounds,
+                     results.mean(),
+                     results.std_error());
+            println!("{} cards out of the machine on average",
+                     variability.missing.mean());
+            println!("{} true count: mean {:.3}, standard deviation {:.3}, highest {:.2}",
+                     system.name,
+                     true_counts.mean(),
+                     true_counts.variance().sqrt(),
+                     variability.highest_true_count);
+            println!("{:.2}% of rounds at a true count of +1 or more",
+                     100.0 * variability.favourable as f64 / results.rounds as f64);
+        }
+        Some("csm-expectation") => {
+            let shoe = full_shoe(decks);
+            let waiting = parse_hand(&args[2]);
+            let table = args.get(3).map_or(vec![], |arg| parse_hand(arg));
+            let deck = match csm::machine_deck(&shoe, &waiting, &table) {
+                Some(deck) => deck,
+                None => {
+                    eprintln!("Those cards can't all have come from a {} deck shoe", decks);
+                    process::exit(1);
+                }
+            };
+            println!("Machine: {}", dataset::format_counts(&deck));
+            println!("Deck expectation: {}", deck_expectation(&shoe, deck, &rules));
+        }
+        Some("table") => {
+            let shoe = simulator::Shoe {
+                decks,
+                cut: simulator::parse_cut(&args[3]),
+                burn,
+            };
+            let seats = args[5].parse::<usize>().unwrap();
+            let seat = args[6].parse::<usize>().unwrap();
+            let system = counts::parse_system(args.get(7).map_or("hi-lo", |arg| arg));
+            let mut progressions = vec![];
+            for seats in 1..seats + 1 {
+                let table = round::Table {
+                    seats,
+                    seat: seat.min(seats - 1),
+                    spots: 1,
+                };
+                let progression = table::seat_progression(&shoe,
+                                                          &table,
+                                                          &rules,
+                                                          args[2].parse::<usize>().unwrap(),
+                                                          args[4].parse::<usize>().unwrap(),
+                                                          &system);
+                eprintln!("{} seats: {:.2} rounds per shoe, expectation {} ± {} per round",
+                          seats,
+                          progression.rounds_per_shoe.mean(),
+                          progression.results.mean(),
+                          progression.results.std_error());
+                progressions.push(progression);
+            }
+            print!("{}", table::progression_csv(&progressions).unwrap());
+        }
+        Some("spots") => {
+            let shoe = simulator::Shoe {
+                decks,
+                cut: simulator::parse_cut(&args[3]),
+                burn,
+            };
+            let spots = args[5].parse::<usize>().unwrap();
+            let table = round::Table {
+                seats: args.get(6).map_or(spots, |arg| arg.parse::<usize>().unwrap()),
+                seat: 0,
+                spots,
+            };
+            if spots == 0 || table.seats < spots {
+                eprintln!("Can't play {} spots at a table of {} seats", spots, table.seats);
+                process::exit(1);
+            }
+            let shoes = args[2].parse::<usize>().unwrap();
+            let seed = args[4].parse::<usize>().unwrap();
+            let mut summary = simulator::Summary::default();
+            let record = |round: simulator::Round| summary.add(round.result);
+            match args.get(7).map(|arg| &arg[..]) {
+                Some("optimal") => {
+                    simulator::simulate_table(&shoe,
+                                              &table,
+                                              &rules,
+                                              shoes,
+                                              seed,
+                                              &optimal_strategy,
+                                              record)
+                }
+                _ => {
+                    simulator::simulate_table(&shoe,
+                                              &table,
+                                              &rules,
+                                              shoes,
+                                              seed,
+                                              &basic::basic_strategy,
+                                              record)
+                }
+            }
+            println!("{} rounds of {} spots, expectation {} ± {} per round ({} per spot), \
+                      variance {} per round",
+                     summary.rounds,
+                     spots,
+                     summary.mean(),
+                     summary.std_error(),
+                     summary.mean() / spots as f64,
+                     summary.variance());
+        }
+        Some("spots-ev") => {
+            let shoe = full_shoe(decks);
+            let deck = parse_deck(&args[2]);
+            let spots = args[3].parse::<usize>().unwrap();
+            if spots == 0 {
+                eprintln!("Can't play no spots");
+                process::exit(1);
+            }
+            let moments = variance::round_moments(&shoe, deck, &rules, spots);
+            println!("{} spots, expectation {} per round ({} per spot), variance {} per round",
+                     spots,
+                     moments.total_expectation(),
+                     moments.total_expectation() / spots as f32,
+                     moments.total_variance());
+            println!("The spots draw from the shoe as dealt, independently given the dealer's \
+                      final score, rather than from what the spots before them left");
+        }
+        Some("ramp") => {
+            let round_variance = args.get(6).map(|arg| arg.parse::<f32>().unwrap());
+            let table = fs::read_to_string(&args[2]).unwrap();
+            let rows = match ramp::parse_table(&table, round_variance) {
+                Ok(rows) => rows,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            };
+            let limits = ramp::Limits {
+                min_bet: args[3].parse::<f32>().unwrap(),
+                spread: args[4].parse::<f32>().unwrap(),
+                unit: args.get(5).map_or(1.0, |arg| arg.parse::<f32>().unwrap()),
+            };
+            let ramp = ramp::optimal_ramp(&rows, &limits);
+            print!("{}", ramp::ramp_csv(&rows, &ramp).unwrap());
+            eprintln!("Win rate {:.2} per 100 rounds, standard deviation {:.2} per 100 rounds",
+                      ramp.win_rate,
+                      ramp.std_dev);
+            eprintln!("DI {:.2}, SCORE {:.2}", ramp.di, ramp.score);
+        }
+        Some("true-count") => {
+            let shoe = parse_deck(&args[2]);
+            let remaining = parse_deck(&args[3]);
+            if !shoe.contains(&remaining) {
+                eprintln!("{} has cards that aren't in the shoe {}", args[3], args[2]);
+                process::exit(1);
+            }
+            for system in counts::systems() {
+                println!("{}: running count {}, true count {:.2}",
+                         system.name,
+                         system.running_count(&shoe, &remaining),
+                         system.true_count(&shoe, &remaining));
+            }
+        }
+        Some("curve") => {
+            let system = counts::parse_system(&args[2]);
+            let samples = args[3].parse::<usize>().unwrap();
+            let penetration = args[4].parse::<f32>().unwrap();
+            let seed = args[5].parse::<usize>().unwrap();
+            let shoe = full_shoe(decks);
+            let bins = match args.get(6) {
+                Some(model_path) => {
+                    let model = regression::parse_model(&fs::read_to_string(model_path).unwrap())
+                        .unwrap();
+                    curve::advantage_curve(&shoe,
+                                           &system,
+                                           samples,
+                                           penetration,
+                                           seed,
+                                           |deck| model.predict(deck))
+                }
+                None => {
+                    curve::advantage_curve(&shoe,
+                                           &system,
+                                           samples,
+                                           penetration,
+                                           seed,
+                                           |&deck| deck_expectation(&shoe, deck, &rules))
+                }
+            };
+            print!("{}", curve::curve_csv(&bins).unwrap());
+        }
+        Some("diff") => {
+            let before_rules = args.get(4).map_or(rules, |rules_str| parse_rules(rules_str));
+            let after_rules = args.get(5).map_or(before_rules, |rules_str| parse_rules(rules_str));
+            let changes = diff::strategy_diff(&full_shoe(decks),
+                                              parse_deck(&args[2]),
+                                              before_rules,
+                                              parse_deck(&args[3]),
+                                              after_rules);
+            for change in changes {
+                let gain = match change.gain {
+                    Some(gain) => format!("{:+.4}", gain),
+                    None => String::from("forced"),
+                };
+                println!("{} v {}: {} -> {}, gain {}, EV change {:+.4}",
+                         change.hand,
+                         change.upcard,
+                         change.before,
+                         change.after,
+                         gain,
+                         change.expectation_delta);
+            }
+        }
+        Some("eor") => {
+            let deck = parse_deck(&args[2]);
+            let mut effects = vec![eor::game_effects(deck, rules)];
+            if deck.cards[card_index(1)] > 0 {
+                let mut without_ace = deck;
+                without_ace.draw(1);
+                effects.push(eor::insurance_effects(without_ace));
+            }
+            for decision in eor::standard_decisions() {
+                effects.extend(eor::decision_effects(deck, rules, &decision));
+            }
+            fs::write(&args[3], eor::eor_csv(&effects).unwrap()).unwrap();
+        }
+        Some("variance") => {
+            let shoe = full_shoe(decks);
+            let deck = parse_deck(&args[2]);
+            let moments = if args.len() > 4 {
+                let upcard = parse_hand(&args[3])[0];
+                let hands: Vec<Vec<u16>> = args[4..].iter().map(|hand| parse_hand(hand)).collect();
+                variance::dealt_round_moments(&shoe, deck, &rules, &hands, upcard)
+            } else {
+                variance::round_moments(&shoe, deck, &rules, args[3].parse::<usize>().unwrap())
+            };
+            let hands = moments.expectations.len();
+            for (hand, exp) in moments.expectations.iter().enumerate() {
+                println!("Hand {}: expectation {}, variance {}",
+                         hand + 1,
+                         exp,
+                         moments.covariances[hand][hand]);
+            }
+            for i in 0..hands {
+                for j in (i + 1)..hands {
+                    println!("Covariance of hands {} and {}: {}",
+                             i + 1,
+                             j + 1,
+                             moments.covariances[i][j]);
+                }
+            }
+            println!("Round expectation: {}, variance: {}",
+                     moments.total_expectation(),
+                     moments.total_variance());
+        }
+        _ => {
+            let shoe = full_shoe(decks);
+            let exp = deck_expectation(&shoe, shoe, &rules);
+            println!("Deck expectation: {}", exp);
+        }
+    }
 }
@@ -0,0 +1,173 @@
+// Estimates the advantage of a shoe that hasn't been computed from the ones
+// nearest to it that have. Shoes are compared by how their make-up differs,
+// rank by rank, weighted by the game's effects of removal, so a difference in
+// fives counts for far more than one in nines. Each neighbour's advantage is
+// moved by the effects to account for its difference from the shoe, and the
+// estimate is the mean of these weighted by closeness.
+
+use super::Deck;
+use super::eor::Effects;
+#[cfg(test)]
+use super::FULL_DECK;
+#[cfg(test)]
+use super::fixtures::test_data;
+
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Estimate {
+    pub advantage: f32,
+    /// A rough standard error: the spread of the neighbours' adjusted
+    /// advantages, with the distance to the nearest as a floor, since the
+    /// adjustment is only right to first order.
+    pub error: f32,
+    /// The weighted distance to the nearest computed shoe.
+    pub nearest: f32,
+}
+
+pub struct Neighbours {
+    // The change in advantage for each rank per unit change in the share of
+    // the shoe it makes up.
+    weights: [f32; 10],
+    // The shares of each rank in the computed shoes, and their advantages.
+    data: Vec<([f32; 10], f32)>,
+}
+
+fn shares(deck: &Deck) -> [f32; 10] {
+    let mut shares = [0.0; 10];
+    for (share, &count) in shares.iter_mut().zip(deck.cards.iter()) {
+        *share = count as f32 / deck.size as f32;
+    }
+    shares
+}
+
+impl Neighbours {
+    /// Neighbours from the computed `data`, weighted by `effects` of removal
+    /// per card from a single deck, as `eor` works them out. Empty shoes have
+    /// no shares, so they're left out.
+    pub fn new(effects: &Effects, data: &[(Deck, f32)]) -> Neighbours {
+        // Removing a card of a rank from a deck lowers its share by about
+        // 1/52, so a unit fall in share is 52 removals.
+        let mut weights = [0.0; 10];
+        for (weight, &effect) in weights.iter_mut().zip(effects.effects.iter()) {
+            *weight = -52.0 * effect;
+        }
+        Neighbours {
+            weights,
+            data: data.iter()
+                .filter(|&&(deck, _)| deck.size > 0)
+                .map(|&(deck, advantage)| (shares(&deck), advantage))
+                .collect(),
+        }
+    }
+
+    // The change in advantage the effects predict going from shares `from`
+    // to shares `to`, and the weighted distance between them.
+    fn difference(&self, from: &[f32; 10], to: &[f32; 10]) -> (f32, f32) {
+        let mut change = 0.0;
+        let mut squares = 0.0;
+        for ((&weight, &a), &b) in self.weights.iter().zip(from.iter()).zip(to.iter()) {
+            let step = weight * (b - a);
+            change += step;
+            squares += step * step;
+        }
+        (change, squares.sqrt())
+    }
+
+    /// The advantage of `deck` from its `k` nearest computed shoes, or None
+    /// if nothing has been computed or `deck` is empty.
+    pub fn estimate(&self, deck: &Deck, k: usize) -> Option<Estimate> {
+        if deck.size == 0 {
+            return None;
+        }
+        let target = shares(deck);
+        // The adjusted advantage of each shoe, and its distance.
+        let mut nearest: Vec<(f32, f32)> = self.data
+            .iter()
+            .map(|&(ref from, advantage)| {
+                let (change, distance) = self.difference(from, &target);
+                (advantage + change, distance)
+            })
+            .collect();
+        nearest.sort_by(|a, b| a.1.total_cmp(&b.1));
+        nearest.truncate(k.max(1));
+        let closest = *nearest.first()?;
+        if closest.1 == 0.0 {
+            return Some(Estimate {
+                advantage: closest.0,
+                error: 0.0,
+                nearest: 0.0,
+            });
+        }
+        let weights: Vec<f32> = nearest.iter().map(|&(_, distance)| 1.0 / distance).collect();
+        let total: f32 = weights.iter().sum();
+        let mean = nearest.iter()
+            .zip(weights.iter())
+            .map(|(&(value, _), weight)| value * weight)
+            .sum::<f32>() / total;
+        let variance = nearest.iter()
+            .zip(weights.iter())
+            .map(|(&(value, _), weight)| weight * (value - mean) * (value - mean))
+            .sum::<f32>() / total;
+        Some(Estimate {
+            advantage: mean,
+            error: variance.sqrt().max(closest.1),
+            nearest: closest.1,
+        })
+    }
+}
+
+#[test]
+fn test_estimate() {
+    let effects = Effects {
+        name: String::from("Game"),
+        base: -0.005,
+        effects: [-0.006, 0.004, 0.005, 0.006, 0.007, 0.004, 0.003, 0.0, -0.002, -0.0053],
+    };
+    let full = shares(&FULL_DECK);
+    // An advantage that's linear in the shares, as the effects have it.
+    let linear = |deck: &Deck| {
+        effects.base +
+        shares(deck)
+            .iter()
+            .zip(full.iter())
+            .zip(effects.effects.iter())
+            .map(|((share, full), effect)| -52.0 * effect * (share - full))
+            .sum::<f32>()
+    };
+    let data = test_data(linear);
+    let neighbours = Neighbours::new(&effects, &data[1..]);
+    let unseen = data[0].0;
+    let estimate = neighbours.estimate(&unseen, 5).unwrap();
+    assert!((estimate.advantage - linear(&unseen)).abs() < 1e-4);
+    assert!(estimate.nearest > 0.0 && estimate.error == estimate.nearest);
+    let computed = data[1].0;
+    let exact = neighbours.estimate(&computed, 5).unwrap();
+    assert!(exact.advantage == data[1].1 && exact.error == 0.0);
+
+    // Fives matter more than nines, so a shoe missing fives is further away.
+    let mut less_fives = FULL_DECK;
+    let mut less_nines = FULL_DECK;
+    for _ in 0..8 {
+        less_fives.draw(5);
+        less_nines.draw(9);
+    }
+    let distance = |a: &Deck, b: &Deck| neighbours.difference(&shares(a), &shares(b)).1;
+    assert!(distance(&FULL_DECK, &less_fives) > distance(&FULL_DECK, &less_nines));
+    assert!(0.0 == distance(&less_fives, &less_fives));
+
+    // Neighbours that disagree widen the error bar.
+    let noisy: Vec<(Deck, f32)> = data.iter()
+        .enumerate()
+        .map(|(i, &(deck, advantage))| (deck, advantage + if i % 2 == 0 { 0.02 } else { -0.02 }))
+        .collect();
+    let noisy = Neighbours::new(&effects, &noisy[1..]).estimate(&unseen, 10).unwrap();
+    assert!(noisy.error > estimate.error && noisy.error > 0.01);
+    assert!(Neighbours::new(&effects, &[]).estimate(&unseen, 5).is_none());
+
+    let empty = Deck {
+        cards: [0; 10],
+        size: 0,
+    };
+    assert!(neighbours.estimate(&empty, 5).is_none());
+    let with_empty = Neighbours::new(&effects, &[(empty, 1.0), data[1]]);
+    assert!(data[1].1 == with_empty.estimate(&computed, 5).unwrap().advantage);
+}
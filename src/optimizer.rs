@@ -0,0 +1,250 @@
+// Searches for the count tags that best track the advantages computed for a
+// set of shoes. A system's true count is a weighted sum of how many cards of
+// each rank have been seen per deck left, so the correlation of any set of
+// tags with the advantage follows from the covariances of those per-rank
+// densities, which are worked out once up front.
+
+use std::thread;
+
+use super::{Deck, card_index};
+use super::counts::CountSystem;
+#[cfg(test)]
+use super::FULL_DECK;
+#[cfg(test)]
+use super::fixtures::test_data;
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct Found {
+    pub system: CountSystem,
+    /// Whether aces are side counted, in which case the system tags them 0 and
+    /// the correlation is that of the main count and the aces together.
+    pub ace_side_count: bool,
+    pub correlation: f32,
+}
+
+// Covariances of each rank's cards seen per deck remaining, with each other
+// and with the advantage.
+struct Statistics {
+    covariance: [[f64; 10]; 10],
+    with_advantage: [f64; 10],
+    advantage_variance: f64,
+}
+
+fn densities(shoe: &Deck, deck: &Deck) -> [f64; 10] {
+    let mut densities = [0.0; 10];
+    for card in 1..11 {
+        let seen = shoe.cards[card_index(card)] - deck.cards[card_index(card)];
+        densities[card_index(card)] = seen as f64 * 52.0 / deck.size as f64;
+    }
+    densities
+}
+
+fn statistics(shoe: &Deck, data: &[(Deck, f32)]) -> Statistics {
+    let rows: Vec<([f64; 10], f64)> =
+        data.iter().map(|&(deck, advantage)| (densities(shoe, &deck), advantage as f64)).collect();
+    let n = rows.len() as f64;
+    let mut means = [0.0; 10];
+    let mut advantage_mean = 0.0;
+    for &(ref x, a) in rows.iter() {
+        for i in 0..10 {
+            means[i] += x[i] / n;
+        }
+        advantage_mean += a / n;
+    }
+    let mut stats = Statistics {
+        covariance: [[0.0; 10]; 10],
+        with_advantage: [0.0; 10],
+        advantage_variance: 0.0,
+    };
+    for &(ref x, a) in rows.iter() {
+        let a = a - advantage_mean;
+        for i in 0..10 {
+            let xi = x[i] - means[i];
+            stats.with_advantage[i] += xi * a / n;
+            for j in 0..10 {
+                stats.covariance[i][j] += xi * (x[j] - means[j]) / n;
+            }
+        }
+        stats.advantage_variance += a * a / n;
+    }
+    stats
+}
+
+fn dot(a: &[f64; 10], b: &[f64; 10]) -> f64 {
+    a.iter().zip(b.iter()).map(|(a, b)| a * b).sum()
+}
+
+impl Statistics {
+    fn count_variance(&self, tags: &[f64; 10]) -> f64 {
+        (0..10).map(|i| tags[i] * dot(&self.covariance[i], tags)).sum()
+    }
+
+    fn correlation(&self, tags: &[f64; 10]) -> f64 {
+        let variance = self.count_variance(tags) * self.advantage_variance;
+        if variance <= 0.0 {
+            return 0.0;
+        }
+        dot(tags, &self.with_advantage) / variance.sqrt()
+    }
+
+    // The multiple correlation of the advantage with the main count and the
+    // aces seen per deck together. Counts that move against the advantage
+    // score zero, so a system and its negation aren't both reported.
+    fn side_count_correlation(&self, tags: &[f64; 10]) -> f64 {
+        let ace = card_index(1);
+        let count_variance = self.count_variance(tags);
+        let ace_variance = self.covariance[ace][ace];
+        let with_advantage = dot(tags, &self.with_advantage);
+        if count_variance <= 0.0 || ace_variance <= 0.0 || self.advantage_variance <= 0.0 ||
+           with_advantage <= 0.0 {
+            return 0.0;
+        }
+        let count_advantage = with_advantage / (count_variance * self.advantage_variance).sqrt();
+        let ace_advantage = self.with_advantage[ace] /
+                            (ace_variance * self.advantage_variance).sqrt();
+        let count_ace = dot(tags, &self.covariance[ace]) / (count_variance * ace_variance).sqrt();
+        if count_ace.abs() >= 1.0 {
+            return count_advantage;
+        }
+        ((count_advantage.powi(2) + ace_advantage.powi(2) -
+          2.0 * count_advantage * ace_advantage * count_ace) /
+         (1.0 - count_ace.powi(2)))
+            .max(0.0)
+            .sqrt()
+    }
+}
+
+fn gcd(a: i32, b: i32) -> i32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn tags_name(tags: &[i32; 10]) -> String {
+    let tags: Vec<String> = tags.iter().map(|tag| tag.to_string()).collect();
+    tags.join(",")
+}
+
+fn makes_the_cut(best: &[Found], keep: usize, correlation: f32) -> bool {
+    best.len() < keep || best.last().is_some_and(|worst| worst.correlation < correlation)
+}
+
+// Keeps the `keep` best systems seen, best first.
+fn consider(best: &mut Vec<Found>, keep: usize, found: Found) {
+    if !makes_the_cut(best, keep, found.correlation) {
+        return;
+    }
+    let position = best.iter()
+        .position(|other| other.correlation < found.correlation)
+        .unwrap_or(best.len());
+    best.insert(position, found);
+    best.truncate(keep);
+}
+
+// Tries every set of tags with the given ten tag. Tags that are all a multiple
+// of a smaller set are skipped, as they count the same way.
+fn search_ten_tag(stats: &Statistics,
+                  level: i32,
+                  ace_side_count: bool,
+                  ten_tag: i32,
+                  keep: usize)
+                  -> Vec<Found> {
+    let mut best = vec![];
+    let first = if ace_side_count { 1 } else { 0 };
+    let mut tags = [-level; 10];
+    tags[card_index(10)] = ten_tag;
+    if ace_side_count {
+        tags[card_index(1)] = 0;
+    }
+    loop {
+        let divisor = tags.iter().fold(0, |divisor, &tag| gcd(divisor, tag.abs()));
+        if divisor == 1 {
+            let mut weights = [0.0; 10];
+            for (weight, &tag) in weights.iter_mut().zip(tags.iter()) {
+                *weight = tag as f64;
+            }
+            let correlation = if ace_side_count {
+                stats.side_count_correlation(&weights)
+            } else {
+                stats.correlation(&weights)
+            } as f32;
+            if makes_the_cut(&best, keep, correlation) {
+                let mut system_tags = [0.0; 10];
+                for (system_tag, &tag) in system_tags.iter_mut().zip(tags.iter()) {
+                    *system_tag = tag as f32;
+                }
+                let system = CountSystem {
+                    name: tags_name(&tags),
+                    tags: system_tags,
+                };
+                consider(&mut best,
+                         keep,
+                         Found {
+                             system,
+                             ace_side_count,
+                             correlation,
+                         });
+            }
+        }
+        // Step to the next set of tags, leaving the ten (and a side counted
+        // ace) alone.
+        let mut i = first;
+        while i < card_index(10) && tags[i] == level {
+            tags[i] = -level;
+            i += 1;
+        }
+        if i == card_index(10) {
+            return best;
+        }
+        tags[i] += 1;
+    }
+}
+
+/// The `keep` systems with tags between -`level` and `level` whose true
+/// counts correlate best with the advantages of shoes dealt from `shoe`.
+pub fn optimize(shoe: &Deck,
+                data: &[(Deck, f32)],
+                level: i32,
+                ace_side_count: bool,
+                keep: usize)
+                -> Vec<Found> {
+    let stats = statistics(shoe, data);
+    let results: Vec<Vec<Found>> = thread::scope(|scope| {
+        let stats = &stats;
+        let handles: Vec<_> = (-level..level + 1)
+            .map(|ten_tag| {
+                scope.spawn(move || search_ten_tag(stats, level, ace_side_count, ten_tag, keep))
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+    let mut best = vec![];
+    for found in results.into_iter().flatten() {
+        consider(&mut best, keep, found);
+    }
+    best
+}
+
+#[test]
+fn test_optimize() {
+    let systems = super::counts::systems();
+    let hi_lo = systems[0].clone();
+    let data = test_data(|deck| 0.005 * hi_lo.true_count(&FULL_DECK, deck));
+    let best = optimize(&FULL_DECK, &data, 1, false, 3);
+    assert!(3 == best.len());
+    assert!(hi_lo.tags == best[0].system.tags);
+    assert!("-1,1,1,1,1,1,0,0,0,-1" == best[0].system.name);
+    assert!((best[0].correlation - 1.0).abs() < 1e-4);
+    assert!(best[1].correlation < best[0].correlation);
+
+    // Hi-Opt I leaves the aces to a side count.
+    let hi_opt = systems[2].clone();
+    let data = test_data(|deck| {
+        0.005 * hi_opt.true_count(&FULL_DECK, deck) -
+        0.002 * densities(&FULL_DECK, deck)[card_index(1)] as f32
+    });
+    let best = optimize(&FULL_DECK, &data, 1, true, 1);
+    assert!(hi_opt.tags == best[0].system.tags);
+    assert!(best[0].ace_side_count);
+    assert!((best[0].correlation - 1.0).abs() < 1e-4);
+    let without = optimize(&FULL_DECK, &data, 1, false, 1);
+    assert!(without[0].correlation < best[0].correlation);
+}
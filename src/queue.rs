@@ -0,0 +1,399 @@
+// The work queue behind `compute`. Workers in any number of threads or
+// processes share one samples file and one data file. A worker claims a deck
+// by creating a file named after it in the claims directory next to the data
+// file, which only one worker can do. Results are appended to the data file a
+// whole line at a time, and the claim is kept as a mark that the deck is done.
+// A claim with no result that hasn't been made or refreshed for `stale_after`
+// belonged to a worker that died, and is taken over, so a stopped run resumes
+// where it left off. Only one worker at a time takes over claims.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use rand::{self, Rng};
+
+use super::{Deck, deck_samples, full_shoe};
+use super::dataset::{self, Metadata, Row, read_data};
+use super::strata::Strata;
+#[cfg(test)]
+use super::DEFAULT_RULES;
+
+struct Progress {
+    // Decks with a result, in the data file when the queue was opened or
+    // written since.
+    computed: HashSet<Deck>,
+    // Decks claimed by other workers.
+    taken: HashSet<Deck>,
+}
+
+pub struct WorkQueue {
+    samples: Vec<Deck>,
+    metadata: Metadata,
+    data_path: PathBuf,
+    claims_path: PathBuf,
+    strata: Option<Strata>,
+    stale_after: Duration,
+    progress: Mutex<Progress>,
+}
+
+// The lock taken while a stale claim is replaced. Claims are named by their
+// counts, so it can't clash with one.
+const TAKEOVER_LOCK: &str = "takeover.lock";
+
+fn claim_name(deck: &Deck) -> String {
+    deck.cards.iter().map(|count| count.to_string()).collect::<Vec<_>>().join("-")
+}
+
+impl WorkQueue {
+    /// Opens the queue for the samples in `samples_path`, creating the data
+    /// file and its claims directory if they don't exist yet. Fails if the
+    /// data file holds results computed with other metadata, or if a sample
+    /// has more cards than a shoe of the metadata's decks.
+    pub fn open(samples_path: &str,
+                data_path: &str,
+                metadata: &Metadata,
+                strata: Option<Strata>,
+                stale_after: Duration)
+                -> Result<WorkQueue, Box<dyn Error>> {
+        let samples = deck_samples(samples_path)?;
+        let shoe = full_shoe(metadata.decks);
+        if let Some(deck) = samples.iter().find(|deck| !shoe.contains(deck)) {
+            return Err(format!("The sample {} can't have been dealt from a {} deck shoe",
+                               dataset::format_counts(deck),
+                               metadata.decks)
+                .into());
+        }
+        let data_path = PathBuf::from(data_path);
+        dataset::prepare(&data_path, metadata)?;
+        let mut claims_path = data_path.clone().into_os_string();
+        claims_path.push(".claims");
+        let claims_path = PathBuf::from(claims_path);
+        fs::create_dir_all(&claims_path)?;
+        let computed = read_data(&data_path)?.into_iter().map(|(deck, _)| deck).collect();
+        Ok(WorkQueue {
+            samples,
+            metadata: metadata.clone(),
+            data_path,
+            claims_path,
+            strata,
+            stale_after,
+            progress: Mutex::new(Progress {
+                computed,
+                taken: HashSet::new(),
+            }),
+        })
+    }
+
+    fn is_stale(&self, claim: &Path) -> Result<bool, Box<dyn Error>> {
+        let age = fs::metadata(claim)?.modified()?.elapsed().unwrap_or_default();
+        Ok(age >= self.stale_after)
+    }
+
+    // Tries to claim `deck`, taking over a claim that has gone stale unless
+    // the deck turns out to be done.
+    fn try_claim(&self, deck: &Deck, progress: &mut Progress) -> Result<bool, Box<dyn Error>> {
+        let path = self.claims_path.join(claim_name(deck));
+        let create = || OpenOptions::new().write(true).create_new(true).open(&path);
+        let mut file = match create() {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                if !self.is_stale(&path)? {
+                    return Ok(false);
+                }
+                // Takeovers hold a lock on the claims directory, so a claim
+                // another worker has just taken over can't be mistaken for
+                // the stale one it replaced. The lock goes with its process
+                // if that dies. While another worker holds it, the deck is
+                // left to them.
+                let lock = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(self.claims_path.join(TAKEOVER_LOCK))?;
+                match lock.try_lock() {
+                    Ok(()) => {}
+                    Err(TryLockError::WouldBlock) => return Ok(false),
+                    Err(TryLockError::Error(err)) => return Err(err.into()),
+                }
+                match fs::metadata(&path) {
+                    Ok(_) => {
+                        if !self.is_stale(&path)? {
+                            return Ok(false);
+                        }
+                    }
+                    // Released since, so it's free to claim.
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.into()),
+                }
+                // Another process may have finished it since the data file
+                // was read.
+                let data = read_data(&self.data_path)?;
+                progress.computed.extend(data.into_iter().map(|(deck, _)| deck));
+                if progress.computed.contains(deck) {
+                    return Ok(false);
+                }
+                if let Err(err) = fs::remove_file(&path) {
+                    if err.kind() != io::ErrorKind::NotFound {
+                        return Err(err.into());
+                    }
+                }
+                // A worker that isn't taking over can still get in first.
+                match create() {
+                    Ok(file) => file,
+                    Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                        return Ok(false)
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            Err(err) => return Err(err.into()),
+        };
+        file.write_all(process::id().to_string().as_bytes())?;
+        Ok(true)
+    }
+
+    // Reads the results other workers have written, and forgets the decks
+    // taken by others that are done, released or stale.
+    fn recheck_taken(&self, progress: &mut Progress) -> Result<(), Box<dyn Error>> {
+        let data = read_data(&self.data_path)?;
+        progress.computed.extend(data.into_iter().map(|(deck, _)| deck));
+        let mut held = HashSet::new();
+        for deck in progress.taken.iter() {
+            let path = self.claims_path.join(claim_name(deck));
+            if !progress.computed.contains(deck) && path.exists() && !self.is_stale(&path)? {
+                held.insert(*deck);
+            }
+        }
+        progress.taken = held;
+        Ok(())
+    }
+
+    /// Claims a deck nobody else is computing, chosen by the strata if there
+    /// are any and uniformly otherwise. None once every sample is computed or
+    /// claimed.
+    pub fn claim(&self) -> Result<Option<Deck>, Box<dyn Error>> {
+        let mut progress = self.progress.lock().unwrap();
+        let mut rng = rand::thread_rng();
+        let mut rechecked = false;
+        loop {
+            // Decks other workers hold are left out as if they were done, so
+            // that strata fill up with everyone's work counted.
+            let unavailable: HashSet<Deck> =
+                progress.computed.union(&progress.taken).cloned().collect();
+            let deck = match self.strata {
+                Some(ref strata) => strata.choose(&self.samples, &unavailable, &mut rng),
+                None => {
+                    let available: Vec<&Deck> =
+                        self.samples.iter().filter(|deck| !unavailable.contains(deck)).collect();
+                    rng.choose(&available).map(|&&deck| deck)
+                }
+            };
+            let deck = match deck {
+                Some(deck) => deck,
+                // Other workers may have finished or abandoned their decks
+                // since, so they're looked at again once before giving up.
+                None if !rechecked && !progress.taken.is_empty() => {
+                    rechecked = true;
+                    self.recheck_taken(&mut progress)?;
+                    continue;
+                }
+                None => return Ok(None),
+            };
+            if self.try_claim(&deck, &mut progress)? {
+                return Ok(Some(deck));
+            }
+            progress.taken.insert(deck);
+        }
+    }
+
+    /// What the results in the data file were computed with.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Whether `deck` is one of the samples and has been claimed, by this
+    /// queue or any other sharing the data file.
+    pub fn is_claimed(&self, deck: &Deck) -> bool {
+        self.samples.contains(deck) && self.claims_path.join(claim_name(deck)).exists()
+    }
+
+    /// Whether every sample has a result.
+    pub fn is_complete(&self) -> bool {
+        let progress = self.progress.lock().unwrap();
+        self.samples.iter().all(|deck| progress.computed.contains(deck))
+    }
+
+    /// Whether `deck` has a result.
+    pub fn is_computed(&self, deck: &Deck) -> bool {
+        self.progress.lock().unwrap().computed.contains(deck)
+    }
+
+    /// Marks the claim on `deck` as fresh, so it isn't taken over while its
+    /// worker is still computing it.
+    pub fn refresh(&self, deck: &Deck) -> Result<(), Box<dyn Error>> {
+        let file = OpenOptions::new().write(true).open(self.claims_path.join(claim_name(deck)))?;
+        file.set_modified(SystemTime::now())?;
+        Ok(())
+    }
+
+    /// Records the advantage of a claimed deck and how many seconds it took.
+    /// The line is written with one append and synced before this returns,
+    /// and a deck that already has a result isn't written again.
+    pub fn complete(&self, deck: Deck, advantage: f32, seconds: f32) -> Result<(), Box<dyn Error>> {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.computed.contains(&deck) {
+            return Ok(());
+        }
+        let line = dataset::row_line(&Row {
+            deck,
+            advantage,
+            seconds: Some(seconds),
+        })?;
+        let mut file = OpenOptions::new().append(true).open(&self.data_path)?;
+        file.write_all(&line)?;
+        file.sync_data()?;
+        progress.computed.insert(deck);
+        Ok(())
+    }
+
+    /// Gives up a claimed deck without a result, so another worker can take
+    /// it.
+    pub fn release(&self, deck: &Deck) -> Result<(), Box<dyn Error>> {
+        fs::remove_file(self.claims_path.join(claim_name(deck)))?;
+        Ok(())
+    }
+}
+
+// A scratch directory for a test, empty at the start.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("blackjack_sim-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+fn test_samples(dir: &Path) -> (String, String, Vec<Deck>) {
+    use super::FULL_DECK;
+    use super::samples::samples_csv;
+    let mut samples = vec![FULL_DECK];
+    for card in 1..11 {
+        let mut deck = FULL_DECK;
+        deck.draw(card);
+        samples.push(deck);
+    }
+    let samples_path = dir.join("samples.csv");
+    fs::write(&samples_path, samples_csv(&samples).unwrap()).unwrap();
+    let data_path = dir.join("data.csv");
+    (samples_path.to_str().unwrap().to_string(), data_path.to_str().unwrap().to_string(), samples)
+}
+
+#[test]
+fn test_work_queue() {
+    let dir = test_dir("queue");
+    let (samples_path, data_path, samples) = test_samples(&dir);
+    let metadata = &Metadata::current(&DEFAULT_RULES, 8);
+    let hour = Duration::from_secs(3600);
+    // Two workers, as if in different processes, never claim the same deck.
+    let first = WorkQueue::open(&samples_path, &data_path, metadata, None, hour).unwrap();
+    let second = WorkQueue::open(&samples_path, &data_path, metadata, None, hour).unwrap();
+    let mut claimed = vec![];
+    for i in 0..samples.len() {
+        let queue = if i % 2 == 0 { &first } else { &second };
+        let deck = queue.claim().unwrap().unwrap();
+        assert!(!claimed.contains(&deck));
+        claimed.push(deck);
+        if i < 6 {
+            queue.complete(deck, i as f32, 1.0).unwrap();
+            queue.complete(deck, -1.0, 1.0).unwrap();
+        }
+    }
+    assert!(first.claim().unwrap().is_none());
+    assert!(second.claim().unwrap().is_none());
+    assert!(!first.is_complete() && first.is_computed(&claimed[0]));
+    // A deck the other worker gives up can be claimed again.
+    first.release(&claimed[6]).unwrap();
+    assert!(Some(claimed[6]) == second.claim().unwrap());
+    let data = read_data(Path::new(&data_path)).unwrap();
+    assert!(6 == data.len());
+    assert!(data.iter().all(|&(_, advantage)| advantage >= 0.0));
+
+    // After a restart the finished decks stay done, and the unfinished ones
+    // come back once their claims are stale.
+    let restarted = WorkQueue::open(&samples_path, &data_path, metadata, None, hour).unwrap();
+    assert!(restarted.claim().unwrap().is_none());
+    let zero = Duration::from_secs(0);
+    let restarted = WorkQueue::open(&samples_path, &data_path, metadata, None, zero).unwrap();
+    let mut resumed = HashSet::new();
+    while let Some(deck) = restarted.claim().unwrap() {
+        assert!(resumed.insert(deck));
+        restarted.complete(deck, 1.0, 1.0).unwrap();
+    }
+    assert!(5 == resumed.len());
+    let data = read_data(Path::new(&data_path)).unwrap();
+    assert!(samples.len() == data.len());
+    let decks: HashSet<Deck> = data.iter().map(|&(deck, _)| deck).collect();
+    assert!(decks == samples.iter().cloned().collect());
+    let six_decks = Metadata::current(&DEFAULT_RULES, 6);
+    let other_data = dir.join("six.csv");
+    let other_data = other_data.to_str().unwrap();
+    assert!(WorkQueue::open(&samples_path, other_data, &six_decks, None, hour).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_crashed_data() {
+    let dir = test_dir("crashed-data");
+    let (samples_path, data_path, samples) = test_samples(&dir);
+    let metadata = &Metadata::current(&DEFAULT_RULES, 8);
+    // A duplicate, then a line cut short by a crash.
+    let contents = format!("{}32,32,32,32,32,32,32,32,32,128,0.5,1\n\
+                            32,32,32,32,32,32,32,32,32,128,0.25,1\n31,32,32,32,32,32,32,32",
+                           metadata.header());
+    fs::write(&data_path, contents).unwrap();
+    let zero = Duration::from_secs(0);
+    let queue = WorkQueue::open(&samples_path, &data_path, metadata, None, zero).unwrap();
+    assert!(vec![(samples[0], 0.5)] == read_data(Path::new(&data_path)).unwrap());
+    // A released deck can be claimed again.
+    let released = queue.claim().unwrap().unwrap();
+    queue.release(&released).unwrap();
+    while let Some(deck) = queue.claim().unwrap() {
+        queue.complete(deck, 0.0, 1.0).unwrap();
+    }
+    assert!(samples.len() == read_data(Path::new(&data_path)).unwrap().len());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_takeover_lock() {
+    let dir = test_dir("takeover");
+    let (samples_path, data_path, samples) = test_samples(&dir);
+    let metadata = &Metadata::current(&DEFAULT_RULES, 8);
+    let hour = Duration::from_secs(3600);
+    let first = WorkQueue::open(&samples_path, &data_path, metadata, None, hour).unwrap();
+    while first.claim().unwrap().is_some() {}
+
+    // Every claim is stale at once, but none can be taken over while another
+    // worker holds the lock.
+    let zero = Duration::from_secs(0);
+    let second = WorkQueue::open(&samples_path, &data_path, metadata, None, zero).unwrap();
+    let lock = fs::File::create(second.claims_path.join(TAKEOVER_LOCK)).unwrap();
+    lock.lock().unwrap();
+    assert!(second.claim().unwrap().is_none());
+    drop(lock);
+    let third = WorkQueue::open(&samples_path, &data_path, metadata, None, zero).unwrap();
+    let mut taken_over = HashSet::new();
+    while let Some(deck) = third.claim().unwrap() {
+        assert!(taken_over.insert(deck));
+        third.complete(deck, 0.0, 1.0).unwrap();
+    }
+    assert!(samples.len() == taken_over.len());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,277 @@
+// Bet ramps. Given how often each true count comes up and the player's
+// advantage and variance there, the ramp that maximizes SCORE, the win rate
+// per 100 rounds for a bankroll of 10,000 bet at optimal (Kelly) sizes, is the
+// one that maximizes the ratio of expectation to standard deviation. Without
+// limits that means betting in proportion to advantage over variance, so the
+// ramp is that, held between the minimum and maximum bets and rounded, at the
+// scale that does best, then moved a unit at a time while that helps.
+
+use std::error::Error;
+
+use csv;
+
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Row {
+    pub true_count: i32,
+    pub frequency: f32,
+    pub advantage: f32,
+    /// The variance of a round's result, in squared bets.
+    pub variance: f32,
+}
+
+/// Reads a table with columns true_count, frequency, mean_advantage and
+/// variance. A table without a variance column, as `curve` writes it, needs
+/// `round_variance` given for every row instead; a round's is about 1.3 at any
+/// count. Any other columns, such as `curve`'s std_dev, are ignored.
+pub fn parse_table(table_csv: &str,
+                   round_variance: Option<f32>)
+                   -> Result<Vec<Row>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(table_csv.as_bytes());
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let true_count = column("true_count").ok_or("The table has no true_count column")?;
+    let frequency = column("frequency").ok_or("The table has no frequency column")?;
+    let advantage = column("mean_advantage").ok_or("The table has no mean_advantage column")?;
+    let variance = column("variance");
+    if variance.is_none() && round_variance.is_none() {
+        return Err("The table has no variance column, and no round variance was given".into());
+    }
+    let mut rows = vec![];
+    for record in reader.records() {
+        let record = record?;
+        rows.push(Row {
+            true_count: record[true_count].parse()?,
+            frequency: record[frequency].parse()?,
+            advantage: record[advantage].parse()?,
+            variance: match (variance, round_variance) {
+                (Some(variance), _) => record[variance].parse()?,
+                (None, Some(round_variance)) => round_variance,
+                (None, None) => unreachable!(),
+            },
+        });
+    }
+    Ok(rows)
+}
+
+#[test]
+fn test_parse_table() {
+    let curve = "true_count,frequency,mean_advantage,std_dev\n-1,0.25,-0.01,0.005\n\
+                 2,0.75,0.005,0\n";
+    assert!(parse_table(curve, None).is_err());
+    let rows = parse_table(curve, Some(1.3)).unwrap();
+    assert!(vec![Row {
+                     true_count: -1,
+                     frequency: 0.25,
+                     advantage: -0.01,
+                     variance: 1.3,
+                 },
+                 Row {
+                     true_count: 2,
+                     frequency: 0.75,
+                     advantage: 0.005,
+                     variance: 1.3,
+                 }] == rows);
+    // The table's own variance comes first.
+    let table = "variance,mean_advantage,frequency,true_count\n1.2,0.01,1,3\n";
+    for &round_variance in [None, Some(1.3)].iter() {
+        let rows = parse_table(table, round_variance).unwrap();
+        assert!(1.2 == rows[0].variance && 3 == rows[0].true_count);
+    }
+    assert!(parse_table("true_count,frequency\n1,1\n", Some(1.3)).is_err());
+}
+
+/// The limits on bets.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Limits {
+    pub min_bet: f32,
+    /// The largest bet as a multiple of the smallest.
+    pub spread: f32,
+    /// Bets are rounded to a multiple of this, such as the smallest chip.
+    pub unit: f32,
+}
+
+impl Limits {
+    fn bet(&self, bet: f32) -> f32 {
+        let rounded = (bet / self.unit).round() * self.unit;
+        rounded.max(self.min_bet).min(self.min_bet * self.spread)
+    }
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct Ramp {
+    /// The bet at each row of the table.
+    pub bets: Vec<f32>,
+    /// The expected win per 100 rounds.
+    pub win_rate: f64,
+    /// The standard deviation of the win per 100 rounds.
+    pub std_dev: f64,
+    /// The desirability index, 1000 times the expected win over its standard
+    /// deviation per round.
+    pub di: f64,
+    /// DI squared, taken as negative for a ramp that loses.
+    pub score: f64,
+}
+
+/// How `bets` fare over the table.
+pub fn evaluate(rows: &[Row], bets: &[f32]) -> Ramp {
+    let total: f64 = rows.iter().map(|row| row.frequency as f64).sum();
+    let mut mean = 0.0;
+    let mut square = 0.0;
+    for (row, &bet) in rows.iter().zip(bets.iter()) {
+        let (frequency, bet) = (row.frequency as f64 / total, bet as f64);
+        let advantage = row.advantage as f64;
+        mean += frequency * bet * advantage;
+        square += frequency * bet * bet * (row.variance as f64 + advantage * advantage);
+    }
+    let std_dev = (square - mean * mean).max(0.0).sqrt();
+    let di = 1000.0 * mean / std_dev;
+    Ramp {
+        bets: bets.to_vec(),
+        win_rate: 100.0 * mean,
+        std_dev: 10.0 * std_dev,
+        di,
+        score: di * di.abs(),
+    }
+}
+
+#[test]
+fn test_evaluate() {
+    let rows = [Row {
+                    true_count: 0,
+                    frequency: 3.0,
+                    advantage: -0.005,
+                    variance: 1.0,
+                },
+                Row {
+                    true_count: 4,
+                    frequency: 1.0,
+                    advantage: 0.015,
+                    variance: 1.0,
+                }];
+    // Flat betting a game that's even overall.
+    let flat = evaluate(&rows, &[10.0, 10.0]);
+    assert!(flat.win_rate.abs() < 1e-9 && 0.0 == flat.score);
+    assert!((flat.std_dev - 10.0 * (100.0f64 * (1.0 + 0.75 * 0.000025 + 0.25 * 0.000225)).sqrt())
+        .abs() < 1e-6);
+    let spread = evaluate(&rows, &[10.0, 40.0]);
+    let mean = 0.75 * 10.0 * -0.005 + 0.25 * 40.0 * 0.015;
+    assert!((spread.win_rate - 100.0 * mean).abs() < 1e-6);
+    assert!(spread.di > 0.0 && (spread.score - spread.di * spread.di).abs() < 1e-9);
+}
+
+/// The ramp within `limits` with the highest SCORE.
+pub fn optimal_ramp(rows: &[Row], limits: &Limits) -> Ramp {
+    let max_bet = limits.min_bet * limits.spread;
+    // The scales at which some count's bet reaches the minimum or maximum.
+    let scales: Vec<f32> = rows.iter()
+        .filter(|row| row.advantage > 0.0)
+        .flat_map(|row| {
+            let per_bet = row.advantage / row.variance;
+            vec![limits.min_bet / per_bet, max_bet / per_bet]
+        })
+        .collect();
+    let mut best = evaluate(rows, &vec![limits.min_bet; rows.len()]);
+    if scales.is_empty() {
+        return best;
+    }
+    let low = scales.iter().cloned().fold(f32::INFINITY, f32::min) / 2.0;
+    let high = scales.iter().cloned().fold(0.0, f32::max) * 2.0;
+    let steps = 2000;
+    for step in 0..steps + 1 {
+        let scale = low * (high / low).powf(step as f32 / steps as f32);
+        let bets: Vec<f32> = rows.iter()
+            .map(|row| limits.bet(scale * row.advantage / row.variance))
+            .collect();
+        if bets == best.bets {
+            continue;
+        }
+        let ramp = evaluate(rows, &bets);
+        if ramp.score > best.score {
+            best = ramp;
+        }
+    }
+    // Rounding can leave a bet a unit off its best.
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for i in 0..rows.len() {
+            for &change in [-limits.unit, limits.unit].iter() {
+                let mut bets = best.bets.clone();
+                bets[i] = limits.bet(bets[i] + change);
+                let ramp = evaluate(rows, &bets);
+                if ramp.score > best.score {
+                    best = ramp;
+                    improved = true;
+                }
+            }
+        }
+    }
+    best
+}
+
+#[test]
+fn test_optimal_ramp() {
+    let row = |true_count: i32, frequency: f32, advantage: f32| {
+        Row {
+            true_count,
+            frequency,
+            advantage,
+            variance: 1.3,
+        }
+    };
+    let rows = [row(-2, 0.2, -0.015),
+                row(-1, 0.2, -0.01),
+                row(0, 0.3, -0.005),
+                row(1, 0.15, 0.0),
+                row(2, 0.08, 0.005),
+                row(3, 0.05, 0.01),
+                row(4, 0.02, 0.015)];
+    let limits = Limits {
+        min_bet: 10.0,
+        spread: 8.0,
+        unit: 5.0,
+    };
+    let ramp = optimal_ramp(&rows, &limits);
+    assert!(ramp.bets.iter().all(|&bet| (10.0..=80.0).contains(&bet) && bet % 5.0 == 0.0));
+    for pair in ramp.bets.windows(2) {
+        assert!(pair[0] <= pair[1]);
+    }
+    assert!(10.0 == ramp.bets[0] && 80.0 == ramp.bets[6]);
+    assert!(ramp.score > evaluate(&rows, &[10.0; 7]).score);
+    // Nudging any bet by a unit does no better.
+    for i in 0..rows.len() {
+        for &change in [-5.0, 5.0].iter() {
+            let mut bets = ramp.bets.clone();
+            bets[i] = limits.bet(bets[i] + change);
+            assert!(evaluate(&rows, &bets).score <= ramp.score + 1e-9);
+        }
+    }
+    // A wider spread can only help.
+    let wider = optimal_ramp(&rows, &Limits { spread: 16.0, ..limits });
+    assert!(wider.score > ramp.score);
+    // With no count worth raising on, the minimum is all there is to bet.
+    let losing = optimal_ramp(&rows[..3], &limits);
+    assert!(losing.bets == vec![10.0; 3] && losing.score < 0.0);
+}
+
+/// The ramp as a table with columns true_count, frequency, advantage and bet.
+pub fn ramp_csv(rows: &[Row], ramp: &Ramp) -> Result<String, Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(["true_count", "frequency", "advantage", "bet"])?;
+    for (row, bet) in rows.iter().zip(ramp.bets.iter()) {
+        writer.write_record(&[row.true_count.to_string(),
+                              row.frequency.to_string(),
+                              row.advantage.to_string(),
+                              bet.to_string()])?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+#[test]
+fn test_ramp_csv() {
+    let table = "true_count,frequency,mean_advantage\n0,0.5,-0.005\n3,0.5,0.01\n";
+    let rows = parse_table(table, Some(1.3)).unwrap();
+    let ramp = evaluate(&rows, &[5.0, 25.0]);
+    assert!("true_count,frequency,advantage,bet\n0,0.5,-0.005,5\n3,0.5,0.01,25\n" ==
+            ramp_csv(&rows, &ramp).unwrap());
+}
@@ -0,0 +1,300 @@
+// Regression models of the advantage on the make-up of the shoe, fitted to
+// computed advantages so a new shoe gets an estimate without running
+// deck_expectation. A shoe is described by the share of each rank from ace to
+// nine (tens make up the rest) and the number of decks left. That carries the
+// same information as the ten rank counts and the cards remaining, but the
+// counts add up to the cards remaining, so fitting all eleven next to an
+// intercept would leave the least squares system singular. The quadratic
+// model adds every square and product of those.
+
+use std::error::Error;
+use std::fmt;
+
+use csv;
+
+use super::Deck;
+#[cfg(test)]
+use super::FULL_DECK;
+#[cfg(test)]
+use super::fixtures::test_data;
+
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum Kind {
+    Linear,
+    Quadratic,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Kind::Linear => write!(f, "linear"),
+            Kind::Quadratic => write!(f, "quadratic"),
+        }
+    }
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct Model {
+    pub kind: Kind,
+    /// One per term, in the order `term_names` gives them.
+    pub coefficients: Vec<f64>,
+}
+
+const SHARE_NAMES: [&str; 9] = ["A", "2", "3", "4", "5", "6", "7", "8", "9"];
+
+fn linear_features(deck: &Deck) -> Vec<f64> {
+    let mut features: Vec<f64> = deck.cards[..9]
+        .iter()
+        .map(|&count| count as f64 / deck.size as f64)
+        .collect();
+    features.push(deck.size as f64 / 52.0);
+    features
+}
+
+fn linear_names() -> Vec<String> {
+    let mut names: Vec<String> = SHARE_NAMES.iter().map(|&name| String::from(name)).collect();
+    names.push(String::from("decks"));
+    names
+}
+
+// Every product of two of `items`, squares included.
+fn products<T, F: Fn(&T, &T) -> T>(items: &[T], product: F) -> Vec<T> {
+    let mut products = vec![];
+    for i in 0..items.len() {
+        for j in i..items.len() {
+            products.push(product(&items[i], &items[j]));
+        }
+    }
+    products
+}
+
+fn features(kind: Kind, deck: &Deck) -> Vec<f64> {
+    let linear = linear_features(deck);
+    let mut features = vec![1.0];
+    features.extend(linear.iter().cloned());
+    if kind == Kind::Quadratic {
+        features.extend(products(&linear, |a, b| a * b));
+    }
+    features
+}
+
+/// The names of a model's terms, starting with the intercept.
+pub fn term_names(kind: Kind) -> Vec<String> {
+    let linear = linear_names();
+    let mut names = vec![String::from("intercept")];
+    names.extend(linear.iter().cloned());
+    if kind == Kind::Quadratic {
+        names.extend(products(&linear, |a, b| format!("{}*{}", a, b)));
+    }
+    names
+}
+
+impl Model {
+    pub fn predict(&self, deck: &Deck) -> f32 {
+        features(self.kind, deck)
+            .iter()
+            .zip(self.coefficients.iter())
+            .map(|(feature, coefficient)| feature * coefficient)
+            .sum::<f64>() as f32
+    }
+}
+
+// Solves the square system `a x = b` by Gaussian elimination with partial
+// pivoting. None if the system is singular.
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for column in 0..n {
+        let pivot = (column..n)
+            .max_by(|&i, &j| a[i][column].abs().partial_cmp(&a[j][column].abs()).unwrap())
+            .unwrap();
+        if a[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+        let (done, rest) = a.split_at_mut(column + 1);
+        let (done_b, rest_b) = b.split_at_mut(column + 1);
+        let pivot_row = &done[column];
+        for (row, rhs) in rest.iter_mut().zip(rest_b.iter_mut()) {
+            let factor = row[column] / pivot_row[column];
+            for (entry, pivot_entry) in row[column..].iter_mut().zip(pivot_row[column..].iter()) {
+                *entry -= factor * pivot_entry;
+            }
+            *rhs -= factor * done_b[column];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let rest: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - rest) / a[row][row];
+    }
+    Some(x)
+}
+
+#[test]
+fn test_solve() {
+    let x = solve(vec![vec![0.0, 2.0], vec![1.0, 1.0]], vec![4.0, 3.0]).unwrap();
+    assert!(vec![1.0, 2.0] == x);
+    assert!(solve(vec![vec![1.0, 2.0], vec![2.0, 4.0]], vec![1.0, 2.0]).is_none());
+}
+
+/// How far off the model is for shoes dealt to about the same depth.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Residuals {
+    /// The lower edge of the band of penetrations, as a fraction of the shoe.
+    pub penetration: f32,
+    pub count: usize,
+    pub mean: f32,
+    pub root_mean_square: f32,
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct Fit {
+    pub model: Model,
+    pub r_squared: f32,
+    /// Residuals in tenths of the shoe dealt, leaving out empty bands.
+    pub residuals: Vec<Residuals>,
+}
+
+/// Fits a model to advantages computed for shoes dealt from `shoe`, by least
+/// squares.
+pub fn fit(kind: Kind, shoe: &Deck, data: &[(Deck, f32)]) -> Result<Fit, Box<dyn Error>> {
+    let rows: Vec<(Vec<f64>, f64)> =
+        data.iter().map(|&(deck, advantage)| (features(kind, &deck), advantage as f64)).collect();
+    let terms = term_names(kind).len();
+    let mut normal = vec![vec![0.0; terms]; terms];
+    let mut moments = vec![0.0; terms];
+    for &(ref x, y) in rows.iter() {
+        for i in 0..terms {
+            moments[i] += x[i] * y;
+            for j in 0..terms {
+                normal[i][j] += x[i] * x[j];
+            }
+        }
+    }
+    let coefficients = solve(normal, moments).ok_or_else(|| {
+        format!("{} rows aren't enough to fit the {} {} model terms",
+                rows.len(),
+                terms,
+                kind)
+    })?;
+    let model = Model {
+        kind,
+        coefficients,
+    };
+
+    let mean = rows.iter().map(|&(_, y)| y).sum::<f64>() / rows.len() as f64;
+    let mut residual_squares = 0.0;
+    let mut total_squares = 0.0;
+    let mut bands = [(0usize, 0.0f64, 0.0f64); 10];
+    for (&(deck, _), &(_, y)) in data.iter().zip(rows.iter()) {
+        let residual = y - model.predict(&deck) as f64;
+        residual_squares += residual * residual;
+        total_squares += (y - mean) * (y - mean);
+        let penetration = 1.0 - deck.size as f64 / shoe.size as f64;
+        let band = &mut bands[((penetration * 10.0) as usize).min(9)];
+        band.0 += 1;
+        band.1 += residual;
+        band.2 += residual * residual;
+    }
+    let residuals = bands.iter()
+        .enumerate()
+        .filter(|&(_, &(count, _, _))| count > 0)
+        .map(|(band, &(count, sum, squares))| {
+            Residuals {
+                penetration: band as f32 / 10.0,
+                count,
+                mean: (sum / count as f64) as f32,
+                root_mean_square: (squares / count as f64).sqrt() as f32,
+            }
+        })
+        .collect();
+    let r_squared = if total_squares == 0.0 {
+        1.0
+    } else {
+        1.0 - residual_squares / total_squares
+    };
+    Ok(Fit {
+        model,
+        r_squared: r_squared as f32,
+        residuals,
+    })
+}
+
+#[test]
+fn test_fit() {
+    let linear = |deck: &Deck| {
+        let features = linear_features(deck);
+        (0.01 * features[0] - 0.02 * features[4] + 0.001 * features[9] - 0.005) as f32
+    };
+    let data = test_data(linear);
+    let exact = fit(Kind::Linear, &FULL_DECK, &data).unwrap();
+    assert!(exact.r_squared > 0.9999);
+    assert!((exact.model.coefficients[0] + 0.005).abs() < 1e-4);
+    assert!((exact.model.coefficients[1] - 0.01).abs() < 1e-3);
+    assert!((exact.model.coefficients[5] + 0.02).abs() < 1e-3);
+    assert!(exact.residuals.iter().all(|band| band.root_mean_square < 1e-5));
+    assert!(200 == exact.residuals.iter().map(|band| band.count).sum::<usize>());
+    assert!((exact.model.predict(&FULL_DECK) - linear(&FULL_DECK)).abs() < 1e-5);
+
+    // A curve the linear model can only follow roughly.
+    let curved = |deck: &Deck| {
+        let features = linear_features(deck);
+        (100.0 * (features[4] - 1.0 / 13.0).powi(2)) as f32
+    };
+    let data = test_data(curved);
+    let linear_fit = fit(Kind::Linear, &FULL_DECK, &data).unwrap();
+    let quadratic_fit = fit(Kind::Quadratic, &FULL_DECK, &data).unwrap();
+    assert!(linear_fit.r_squared < 0.9);
+    assert!(quadratic_fit.r_squared > 0.999);
+
+    assert!(fit(Kind::Quadratic, &FULL_DECK, &data[..20]).is_err());
+}
+
+/// The model's terms and coefficients, one per row.
+pub fn model_csv(model: &Model) -> Result<String, Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(["term", "coefficient"])?;
+    for (name, coefficient) in term_names(model.kind).iter().zip(model.coefficients.iter()) {
+        writer.write_record(&[name.clone(), coefficient.to_string()])?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// Reads a model written by `model_csv`, telling the kind of model from its
+/// terms.
+pub fn parse_model(model_csv: &str) -> Result<Model, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(model_csv.as_bytes());
+    let mut names = vec![];
+    let mut coefficients = vec![];
+    for record in reader.records() {
+        let record = record?;
+        names.push(String::from(&record[0]));
+        coefficients.push(record[1].parse()?);
+    }
+    let kind = [Kind::Linear, Kind::Quadratic]
+        .iter()
+        .cloned()
+        .find(|&kind| term_names(kind) == names)
+        .ok_or("The terms don't match a linear or quadratic model")?;
+    Ok(Model {
+        kind,
+        coefficients,
+    })
+}
+
+#[test]
+fn test_model_csv() {
+    let terms = term_names(Kind::Quadratic);
+    assert!(66 == terms.len());
+    assert!("A*A" == terms[11]);
+    assert!("decks*decks" == terms[65]);
+    let model = Model {
+        kind: Kind::Quadratic,
+        coefficients: (0..66).map(|i| i as f64 / 8.0).collect(),
+    };
+    let csv = model_csv(&model).unwrap();
+    assert!(csv.starts_with("term,coefficient\nintercept,0\nA,0.125\n"));
+    assert!(model == parse_model(&csv).unwrap());
+    assert!(parse_model("term,coefficient\nintercept,0\n").is_err());
+}
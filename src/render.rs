@@ -0,0 +1,313 @@
+// Renders strategy charts for sharing: CSV and Markdown tables in the usual
+// hard/soft/pairs layout, and HTML and SVG heatmaps where the colour of a cell
+// is its best action and the shade is how much better that action is than the
+// runner-up.
+
+use std::error::Error;
+
+use csv;
+
+use super::Action;
+use super::strategy::{ChartCell, HandKind, StrategyChart, UPCARDS, chart_hands};
+
+// Margins at or above this many bets are drawn at full strength.
+const FULL_SHADE_MARGIN: f32 = 0.25;
+
+const CELL_WIDTH: usize = 48;
+const CELL_HEIGHT: usize = 24;
+
+fn abbreviation(action: Action) -> &'static str {
+    match action {
+        Action::Stand => "S",
+        Action::Hit => "H",
+        Action::Double => "D",
+        Action::Split => "P",
+        Action::Surrender => "R",
+        Action::Insurance => "I",
+    }
+}
+
+fn colour(action: Action) -> &'static str {
+    match action {
+        Action::Stand => "#d9534f",
+        Action::Hit => "#5cb85c",
+        Action::Double => "#0275d8",
+        Action::Split => "#f0ad4e",
+        Action::Surrender => "#777777",
+        Action::Insurance => "#9b59b6",
+    }
+}
+
+// The opacity of a cell's colour, from faint for a close call to solid for a
+// clear one.
+fn shade(margin: f32) -> f32 {
+    (0.2 + 0.8 * margin / FULL_SHADE_MARGIN).clamp(0.2, 1.0)
+}
+
+#[test]
+fn test_shade() {
+    assert!(0.2 == shade(0.0));
+    assert!(0.6 == shade(FULL_SHADE_MARGIN / 2.0));
+    assert!(1.0 == shade(1.0));
+}
+
+// A `#rrggbb` colour at the given opacity, for shading a background without
+// fading the text over it.
+fn translucent(colour: &str, opacity: f32) -> String {
+    let channel = |i: usize| u8::from_str_radix(&colour[i..i + 2], 16).unwrap();
+    format!("rgba({}, {}, {}, {:.2})", channel(1), channel(3), channel(5), opacity)
+}
+
+#[test]
+fn test_translucent() {
+    assert!("rgba(240, 173, 78, 0.84)" == translucent("#f0ad4e", 0.84));
+}
+
+fn upcard_label(upcard: u16) -> String {
+    if upcard == 1 {
+        String::from("A")
+    } else {
+        upcard.to_string()
+    }
+}
+
+fn section_name(hand: HandKind) -> &'static str {
+    match hand {
+        HandKind::Hard(_) => "Hard totals",
+        HandKind::Soft(_) => "Soft totals",
+        HandKind::Pair(_) => "Pairs",
+    }
+}
+
+// A named section of the chart, with a row of cells (one per upcard) for
+// each hand.
+type Section<'a> = (&'static str, Vec<(HandKind, Vec<Option<&'a ChartCell>>)>);
+
+fn sections<'a>(chart: &'a StrategyChart) -> Vec<Section<'a>> {
+    let mut sections: Vec<Section<'a>> = vec![];
+    for hand in chart_hands() {
+        let row = (hand, UPCARDS.iter().map(|&upcard| chart.cell(hand, upcard)).collect());
+        let name = section_name(hand);
+        if sections.last().is_none_or(|&(last, _)| last != name) {
+            sections.push((name, vec![]));
+        }
+        sections.last_mut().unwrap().1.push(row);
+    }
+    sections
+}
+
+fn cell_text(cell: Option<&ChartCell>) -> &'static str {
+    cell.map_or("", |cell| abbreviation(cell.report.best))
+}
+
+/// One row per hand, with a column giving its section.
+pub fn chart_csv(chart: &StrategyChart) -> Result<String, Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    let mut header = vec![String::from("section"), String::from("hand")];
+    header.extend(UPCARDS.iter().map(|&upcard| upcard_label(upcard)));
+    writer.write_record(&header)?;
+    for (name, rows) in sections(chart) {
+        for (hand, cells) in rows {
+            let mut record = vec![String::from(name), hand.to_string()];
+            record.extend(cells.into_iter().map(|cell| String::from(cell_text(cell))));
+            writer.write_record(&record)?;
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+pub fn chart_markdown(chart: &StrategyChart) -> String {
+    let upcards: Vec<String> = UPCARDS.iter().map(|&upcard| upcard_label(upcard)).collect();
+    let mut markdown = String::new();
+    for (name, rows) in sections(chart) {
+        if !markdown.is_empty() {
+            markdown.push('\n');
+        }
+        markdown.push_str(&format!("### {}\n\n", name));
+        markdown.push_str(&format!("| Hand | {} |\n", upcards.join(" | ")));
+        markdown.push_str(&format!("|---|{}\n", "---|".repeat(upcards.len())));
+        for (hand, cells) in rows {
+            let cells: Vec<&str> = cells.into_iter().map(cell_text).collect();
+            markdown.push_str(&format!("| {} | {} |\n", hand, cells.join(" | ")));
+        }
+    }
+    markdown
+}
+
+fn legend() -> Vec<(Action, &'static str)> {
+    [Action::Stand,
+     Action::Hit,
+     Action::Double,
+     Action::Split,
+     Action::Surrender,
+     Action::Insurance]
+        .iter()
+        .map(|&action| (action, colour(action)))
+        .collect()
+}
+
+pub fn chart_html(chart: &StrategyChart) -> String {
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                                 <title>Strategy chart</title>\n<style>\n\
+                                 table { border-collapse: collapse; margin-bottom: 1em; }\n\
+                                 th, td { border: 1px solid #ccc; padding: 4px 8px; \
+                                 text-align: center; font-family: sans-serif; }\n\
+                                 </style>\n</head>\n<body>\n");
+    html.push_str("<p>");
+    for (action, colour) in legend() {
+        html.push_str(&format!("<span style=\"background: {}; padding: 2px 6px;\">{} {}</span> ",
+                               colour,
+                               abbreviation(action),
+                               action));
+    }
+    html.push_str("</p>\n");
+    for (name, rows) in sections(chart) {
+        html.push_str(&format!("<h3>{}</h3>\n<table>\n<tr><th>Hand</th>", name));
+        for &upcard in UPCARDS.iter() {
+            html.push_str(&format!("<th>{}</th>", upcard_label(upcard)));
+        }
+        html.push_str("</tr>\n");
+        for (hand, cells) in rows {
+            html.push_str(&format!("<tr><th>{}</th>", hand));
+            for cell in cells {
+                match cell {
+                    Some(cell) => {
+                        html.push_str(&format!("<td style=\"background: {};\" \
+                                                title=\"EV {:+.4}, margin {:.4}\">{}</td>",
+                                               translucent(colour(cell.report.best),
+                                                           shade(cell.report.margin())),
+                                               cell.report.best_expectation(),
+                                               cell.report.margin(),
+                                               abbreviation(cell.report.best)))
+                    }
+                    None => html.push_str("<td></td>"),
+                }
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+pub fn chart_svg(chart: &StrategyChart) -> String {
+    let sections = sections(chart);
+    let row_count: usize = sections.iter().map(|(_, rows)| rows.len() + 2).sum();
+    let width = CELL_WIDTH * (UPCARDS.len() + 1);
+    let height = CELL_HEIGHT * row_count;
+    let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" \
+                           font-family=\"sans-serif\" font-size=\"12\">\n",
+                          width,
+                          height);
+    let text = |x: usize, y: usize, content: &str| {
+        format!("<text x=\"{}\" y=\"{}\" text-anchor=\"middle\" \
+                 dominant-baseline=\"central\">{}</text>\n",
+                x + CELL_WIDTH / 2,
+                y + CELL_HEIGHT / 2,
+                content)
+    };
+    let mut y = 0;
+    for (name, rows) in sections {
+        svg.push_str(&format!("<text x=\"0\" y=\"{}\" dominant-baseline=\"central\" \
+                               font-weight=\"bold\">{}</text>\n",
+                              y + CELL_HEIGHT / 2,
+                              name));
+        y += CELL_HEIGHT;
+        for (column, &upcard) in UPCARDS.iter().enumerate() {
+            svg.push_str(&text((column + 1) * CELL_WIDTH, y, &upcard_label(upcard)));
+        }
+        y += CELL_HEIGHT;
+        for (hand, cells) in rows {
+            svg.push_str(&text(0, y, &hand.to_string()));
+            for (column, cell) in cells.into_iter().enumerate() {
+                let x = (column + 1) * CELL_WIDTH;
+                if let Some(cell) = cell {
+                    svg.push_str(&format!("<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" \
+                                           fill=\"{}\" fill-opacity=\"{:.2}\" stroke=\"#ffffff\">\
+                                           <title>{} v {}: {} EV {:+.4}, margin {:.4}</title>\
+                                           </rect>\n",
+                                          x,
+                                          y,
+                                          CELL_WIDTH,
+                                          CELL_HEIGHT,
+                                          colour(cell.report.best),
+                                          shade(cell.report.margin()),
+                                          hand,
+                                          upcard_label(cell.upcard),
+                                          cell.report.best,
+                                          cell.report.best_expectation(),
+                                          cell.report.margin()));
+                    svg.push_str(&text(x, y, abbreviation(cell.report.best)));
+                }
+            }
+            y += CELL_HEIGHT;
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+fn test_chart() -> StrategyChart {
+    use super::{ActionReport, DEFAULT_RULES, FULL_DECK};
+    StrategyChart {
+        deck: FULL_DECK,
+        rules: DEFAULT_RULES,
+        cells: vec![ChartCell {
+                        hand: HandKind::Hard(16),
+                        upcard: 10,
+                        report: ActionReport::new(vec![(Action::Stand, -0.54),
+                                                       (Action::Hit, -0.53),
+                                                       (Action::Surrender, -0.5)]),
+                    },
+                    ChartCell {
+                        hand: HandKind::Pair(8),
+                        upcard: 1,
+                        report: ActionReport::new(vec![(Action::Stand, -0.6),
+                                                       (Action::Split, -0.4)]),
+                    }],
+    }
+}
+
+#[test]
+fn test_chart_csv() {
+    let csv = chart_csv(&test_chart()).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert!("section,hand,2,3,4,5,6,7,8,9,10,A" == lines[0]);
+    assert!("Hard totals,16,,,,,,,,,R," == lines[12]);
+    assert!("Pairs,\"8,8\",,,,,,,,,,P" == lines[30]);
+    assert!(34 == lines.len());
+}
+
+#[test]
+fn test_chart_markdown() {
+    let markdown = chart_markdown(&test_chart());
+    assert!(markdown.starts_with("### Hard totals\n\n| Hand | 2 | 3 | 4 | 5 | 6 | 7 | 8 | 9 | 10 | A \
+                                  |\n|---|---|"));
+    assert!(markdown.contains("| 16 |  |  |  |  |  |  |  |  | R |  |\n"));
+    assert!(markdown.contains("### Soft totals\n"));
+    assert!(markdown.contains("| 8,8 |  |  |  |  |  |  |  |  |  | P |\n"));
+}
+
+#[test]
+fn test_chart_html() {
+    let html = chart_html(&test_chart());
+    assert!(html.contains("<td style=\"background: rgba(119, 119, 119, 0.30);\" \
+                           title=\"EV -0.5000, margin 0.0300\">R</td>"));
+    assert!(html.contains("<td style=\"background: rgba(240, 173, 78, 0.84);\" \
+                           title=\"EV -0.4000, margin 0.2000\">P</td>"));
+    // Only the background is shaded, so the labels stay solid.
+    assert!(!html.contains("opacity"));
+    assert!(3 == html.matches("<table>").count());
+}
+
+#[test]
+fn test_chart_svg() {
+    let svg = chart_svg(&test_chart());
+    assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"528\" \
+                             height=\"936\""));
+    assert!(2 == svg.matches("<rect").count());
+    assert!(svg.contains("fill=\"#f0ad4e\" fill-opacity=\"0.84\""));
+    assert!(svg.ends_with("</svg>\n"));
+}
@@ -0,0 +1,465 @@
+// Plays single rounds out of an ordered shoe, for simulations. The dealer
+// peeks for a natural with an ace or ten up, split hands can't be split again,
+// split aces get one card each, and a two-card 21 after a split is not a
+// natural. Otherwise the rules are the ones the expectation engine uses. If
+// the shoe runs out mid-round, the discards are shuffled and dealing carries on.
+
+use rand::Rng;
+
+use super::{Action, Deck, GameState, OrderedDeck, Rules, Score, can_double, can_insurance,
+            can_split, can_surrender, cannot_hit, dealer_stands, hand_expectation, score};
+#[cfg(test)]
+use super::DEFAULT_RULES;
+#[cfg(test)]
+use super::basic::basic_strategy;
+#[cfg(test)]
+use rand;
+
+struct Hand {
+    cards: Vec<u16>,
+    bet: f32,
+    // Whether the hand is out of the round, having busted or surrendered.
+    settled: bool,
+}
+
+fn hand_score(hand: &Hand, is_split: bool) -> Score {
+    match score(&hand.cards) {
+        Score::Natural if is_split => Score::Value(21),
+        hand_score => hand_score,
+    }
+}
+
+// Deals the player a card. The strategy's deck is what it hasn't seen, so
+// after a reshuffle it's the new shoe and the hole card.
+fn deal<R: Rng>(shoe: &mut OrderedDeck, state: &mut GameState, rng: &mut R) -> u16 {
+    if shoe.cards.is_empty() {
+        // The burned cards go into the new shoe, so aren't counted twice.
+        for &card in shoe.burned.iter() {
+            state.deck.draw(card);
+        }
+        shoe.reshuffle(rng);
+        for (count, &new) in state.deck.cards.iter_mut().zip(shoe.deck.cards.iter()) {
+            *count += new;
+        }
+        state.deck.size += shoe.deck.size;
+    }
+    let card = shoe.draw();
+    state.deck.draw(card);
+    card
+}
+
+// The shoe a reshuffle would deal from as the strategy sees it: what it hasn't
+// seen, the discards, and the cards of its own hand and the upcard. Other
+// seats' cards and the other half of a split stay on the table.
+fn strategy_shoe(shoe: &OrderedDeck, state: &GameState) -> Deck {
+    let mut strategy_shoe = state.deck;
+    for &card in shoe.discards.iter().chain(state.player.iter()).chain(state.dealer.iter()) {
+        strategy_shoe.replace(card);
+    }
+    strategy_shoe
+}
+
+// Plays a hand to the end, returning any hand split off from it, which still
+// needs one more card.
+fn play_hand<S, R>(shoe: &mut OrderedDeck,
+                   hand: &mut Hand,
+                   state: &mut GameState,
+                   strategy: &S,
+                   rng: &mut R)
+                   -> Option<Hand>
+    where S: Fn(&mut GameState) -> Action,
+          R: Rng
+{
+    loop {
+        if cannot_hit(&hand.cards) {
+            return None;
+        }
+        state.player = hand.cards.clone();
+        state.shoe = strategy_shoe(shoe, state);
+        let action = strategy(state);
+        match action {
+            Action::Stand => return None,
+            Action::Hit => {
+                let card = deal(shoe, state, rng);
+                hand.cards.push(card);
+                if score(&hand.cards) == Score::Bust {
+                    hand.settled = true;
+                    return None;
+                }
+            }
+            Action::Double if can_double(state) => {
+                let card = deal(shoe, state, rng);
+                hand.cards.push(card);
+                hand.bet *= 2.0;
+                hand.settled = score(&hand.cards) == Score::Bust;
+                return None;
+            }
+            Action::Split if can_split(state) => {
+                let card = hand.cards.pop().unwrap();
+                state.is_split = true;
+                let next = deal(shoe, state, rng);
+                hand.cards.push(next);
+                let split = Hand {
+                    cards: vec![card],
+                    bet: hand.bet,
+                    settled: false,
+                };
+                if card == 1 {
+                    return Some(split);
+                }
+                let rest = play_hand(shoe, hand, state, strategy, rng);
+                assert!(rest.is_none());
+                return Some(split);
+            }
+            Action::Surrender if can_surrender(state) => {
+                hand.bet *= 0.5;
+                hand.settled = true;
+                return None;
+            }
+            _ => panic!("{} isn't allowed with {:?}", action, state),
+        }
+    }
+}
+
+/// Where the player sits at the table.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Table {
+    /// How many seats are played, the player's included.
+    pub seats: usize,
+    /// The player's first seat, counting from first base, which is played
+    /// first.
+    pub seat: usize,
+    /// How many seats the player plays, from `seat` on.
+    pub spots: usize,
+}
+
+pub const HEADS_UP: Table = Table {
+    seats: 1,
+    seat: 0,
+    spots: 1,
+};
+
+impl Table {
+    fn is_players(&self, seat: usize) -> bool {
+        seat >= self.seat && seat < self.seat + self.spots
+    }
+}
+
+// Plays a seat's hand, and any split from it, to the end.
+fn play_seat<S, R>(shoe: &mut OrderedDeck,
+                   cards: Vec<u16>,
+                   state: &mut GameState,
+                   strategy: &S,
+                   rng: &mut R)
+                   -> Vec<Hand>
+    where S: Fn(&mut GameState) -> Action,
+          R: Rng
+{
+    state.is_split = false;
+    let mut hands = vec![Hand {
+                             cards,
+                             bet: 1.0,
+                             settled: false,
+                         }];
+    let mut i = 0;
+    while i < hands.len() {
+        if i > 0 {
+            let card = deal(shoe, state, rng);
+            hands[i].cards.push(card);
+            if hands[i].cards[0] == 1 {
+                break;
+            }
+        }
+        if let Some(split) = play_hand(shoe, &mut hands[i], state, strategy, rng) {
+            hands.push(split);
+        }
+        i += 1;
+    }
+    hands
+}
+
+// Whether the dealer has to play out their hand for a seat: it has a hand
+// that hasn't busted or surrendered, and isn't a natural.
+fn waits_for_dealer(hands: &[Hand]) -> bool {
+    let natural = hands.len() == 1 && score(&hands[0].cards) == Score::Natural;
+    !natural && hands.iter().any(|hand| !hand.settled)
+}
+
+// A seat's result against the dealer's final score.
+fn settle(hands: &[Hand], dealer_score: Score, rules: &Rules) -> f32 {
+    let is_split = hands.len() > 1;
+    hands.iter()
+        .map(|hand| if hand.settled && score(&hand.cards) != Score::Bust {
+            -hand.bet
+        } else {
+            hand.bet * hand_expectation(hand_score(hand, is_split), dealer_score, rules)
+        })
+        .sum()
+}
+
+/// Deals and plays a round from `shoe`, heads up, with `strategy` making the
+/// player's decisions, and returns the player's result in initial bets.
+pub fn play_round<S, R>(shoe: &mut OrderedDeck, rules: &Rules, strategy: &S, rng: &mut R) -> f32
+    where S: Fn(&mut GameState) -> Action,
+          R: Rng
+{
+    play_table(shoe, rules, &HEADS_UP, strategy, strategy, rng)
+}
+
+/// Deals and plays a round from `shoe` to every seat at `table`, with
+/// `strategy` making the player's decisions and `others` the other seats',
+/// and returns the player's total result over their spots in initial bets.
+/// Each seat gets a card in turn, then the dealer, then each seat again. The
+/// strategy sees the cards it hasn't seen yet, the dealer's hole card and any
+/// burned cards included, as the deck, so each of the player's spots is
+/// played knowing what the spots before it drew. The cards of other hands on
+/// the table are left out of the shoe it would reshuffle. If it takes insurance on a spot when
+/// offered and the dealer has no natural, it is asked again with
+/// `failed_insurance` set, as the expectation engine does. The cards played
+/// go to the shoe's discards, and `rng` shuffles them back in if the shoe
+/// runs out.
+pub fn play_table<S, T, R>(shoe: &mut OrderedDeck,
+                           rules: &Rules,
+                           table: &Table,
+                           strategy: &S,
+                           others: &T,
+                           rng: &mut R)
+                           -> f32
+    where S: Fn(&mut GameState) -> Action,
+          T: Fn(&mut GameState) -> Action,
+          R: Rng
+{
+    assert!(table.spots > 0 && table.seat + table.spots <= table.seats);
+    let mut players: Vec<Vec<u16>> = (0..table.seats).map(|_| vec![shoe.deal(rng)]).collect();
+    let upcard = shoe.deal(rng);
+    for player in players.iter_mut() {
+        player.push(shoe.deal(rng));
+    }
+    let mut dealer = vec![upcard, shoe.deal(rng)];
+    let mut unseen = shoe.unseen();
+    unseen.replace(dealer[1]);
+    let mut state = GameState {
+        player: vec![],
+        dealer: vec![upcard],
+        deck: unseen,
+        shoe: unseen,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules: *rules,
+    };
+
+    let spots = table.seat..table.seat + table.spots;
+    let dealer_natural = score(&dealer) == Score::Natural;
+    let mut result = 0.0;
+    let mut failed_insurance = vec![false; table.seats];
+    for seat in spots.clone() {
+        state.player = players[seat].clone();
+        state.shoe = strategy_shoe(shoe, &state);
+        if can_insurance(&state) && strategy(&mut state) == Action::Insurance {
+            if dealer_natural {
+                result += 1.0;
+            } else {
+                result -= 0.5;
+                failed_insurance[seat] = true;
+            }
+        }
+    }
+    if dealer_natural {
+        for player in players.iter() {
+            shoe.discard(player);
+        }
+        shoe.discard(&dealer);
+        return result +
+               spots.map(|seat| hand_expectation(score(&players[seat]), Score::Natural, rules))
+            .sum::<f32>();
+    }
+
+    let mut seats = vec![];
+    for (seat, cards) in players.into_iter().enumerate() {
+        state.player = cards.clone();
+        state.failed_insurance = failed_insurance[seat];
+        seats.push(if table.is_players(seat) {
+            play_seat(shoe, cards, &mut state, strategy, rng)
+        } else {
+            play_seat(shoe, cards, &mut state, others, rng)
+        });
+    }
+
+    if seats.iter().any(|hands| waits_for_dealer(hands)) {
+        while !dealer_stands(&dealer, rules) {
+            dealer.push(shoe.deal(rng));
+        }
+    }
+    for hand in seats.iter().flatten() {
+        shoe.discard(&hand.cards);
+    }
+    shoe.discard(&dealer);
+    let dealer_score = score(&dealer);
+    result + spots.map(|seat| settle(&seats[seat], dealer_score, rules)).sum::<f32>()
+}
+
+// A shoe that deals `cards` in order, then tens.
+#[cfg(test)]
+fn stacked(cards: &[u16]) -> OrderedDeck {
+    let mut ordered: Vec<u16> = vec![10; 20];
+    ordered.extend(cards.iter().rev());
+    let mut deck = Deck {
+        cards: [0; 10],
+        size: 0,
+    };
+    for &card in ordered.iter() {
+        deck.replace(card);
+    }
+    OrderedDeck {
+        cards: ordered,
+        deck,
+        discards: vec![],
+        burned: vec![],
+        shuffles: 0,
+    }
+}
+
+#[test]
+fn test_play_round() {
+    let mut rng = rand::thread_rng();
+    let mut play = |cards: &[u16]| {
+        let mut shoe = stacked(cards);
+        play_round(&mut shoe, &DEFAULT_RULES, &basic_strategy, &mut rng)
+    };
+    // Player, upcard, player, hole card, then draws.
+    assert!(1.5 == play(&[1, 9, 10, 8]));
+    assert!(-1.0 == play(&[9, 1, 10, 10]));
+    assert!(0.0 == play(&[1, 1, 10, 10]));
+    assert!(-0.5 == play(&[10, 10, 6, 8]));
+    assert!(1.0 == play(&[10, 6, 6, 10, 10]));
+    // Doubles 11 against a 6 and the dealer busts.
+    assert!(2.0 == play(&[6, 6, 5, 10, 10]));
+    // Splits eights against a 9, one hand makes 18 and beats the dealer's 17,
+    // the other busts.
+    assert!(0.0 == play(&[8, 9, 8, 8, 10, 6, 10]));
+    // Split aces get one card each, and 21 isn't a natural.
+    assert!(2.0 == play(&[1, 7, 1, 10, 10, 10]));
+
+    let mut shoe = stacked(&[1, 1, 10, 10]);
+    let insure = |state: &mut GameState| if can_insurance(state) {
+        Action::Insurance
+    } else {
+        Action::Stand
+    };
+    assert!(1.0 == play_round(&mut shoe, &DEFAULT_RULES, &insure, &mut rng));
+    let mut shoe = stacked(&[10, 1, 9, 6]);
+    assert!(0.5 == play_round(&mut shoe, &DEFAULT_RULES, &insure, &mut rng));
+    assert!(4 == shoe.discards.len() && 20 == shoe.cards.len());
+}
+
+#[test]
+fn test_reshuffle_mid_round() {
+    let mut rng = rand::thread_rng();
+    // The shoe runs out with the dealer on 16, who then draws the only
+    // discard.
+    let play = |discard: u16, rng: &mut rand::ThreadRng| {
+        let mut shoe = OrderedDeck {
+            cards: vec![6, 10, 10, 10],
+            deck: Deck {
+                cards: [0, 0, 0, 0, 0, 1, 0, 0, 0, 3],
+                size: 4,
+            },
+            discards: vec![discard],
+            burned: vec![],
+            shuffles: 0,
+        };
+        let result = play_round(&mut shoe, &DEFAULT_RULES, &basic_strategy, rng);
+        assert!(shoe.cards.is_empty() && 5 == shoe.discards.len());
+        result
+    };
+    assert!(-1.0 == play(5, &mut rng));
+    assert!(0.0 == play(4, &mut rng));
+    assert!(1.0 == play(10, &mut rng));
+
+    // The player hits 12 from an empty shoe, and the strategy then sees the
+    // reshuffled shoe and the hole card.
+    let seen = |state: &mut GameState| {
+        if state.player.len() == 3 {
+            assert!(2 == state.deck.size && 1 == state.deck.cards[5]);
+            assert!(1 == state.deck.cards[8]);
+            Action::Stand
+        } else {
+            Action::Hit
+        }
+    };
+    let mut shoe = OrderedDeck {
+        cards: vec![6, 2, 10, 10],
+        deck: Deck {
+            cards: [0, 1, 0, 0, 0, 1, 0, 0, 0, 2],
+            size: 4,
+        },
+        discards: vec![9, 9],
+        burned: vec![],
+        shuffles: 0,
+    };
+    assert!(1.0 == play_round(&mut shoe, &DEFAULT_RULES, &seen, &mut rng));
+}
+
+#[test]
+fn test_play_table() {
+    let mut rng = rand::thread_rng();
+    let table = Table {
+        seats: 2,
+        seat: 1,
+        spots: 1,
+    };
+    let stand = |_: &mut GameState| Action::Stand;
+    // Each seat, the upcard, each seat, then the hole card. First base hits
+    // 12 against a 2 and takes the nine, so the dealer makes 17 rather than
+    // 21 against the player's 18.
+    let cards = [10, 10, 2, 2, 8, 10, 9, 5];
+    let mut shoe = stacked(&cards);
+    let basic = &basic_strategy;
+    assert!(1.0 == play_table(&mut shoe, &DEFAULT_RULES, &table, basic, basic, &mut rng));
+    assert!(8 == shoe.discards.len());
+    let mut shoe = stacked(&cards);
+    assert!(-1.0 == play_table(&mut shoe, &DEFAULT_RULES, &table, basic, &stand, &mut rng));
+    // First base's 10, 2 and 9 are on the table, so out of the shoe the
+    // player's strategy would reshuffle.
+    let without_first_base = |state: &mut GameState| {
+        assert!(25 == state.shoe.size && 0 == state.shoe.cards[8]);
+        Action::Stand
+    };
+    let mut shoe = stacked(&cards);
+    assert!(1.0 ==
+            play_table(&mut shoe, &DEFAULT_RULES, &table, &without_first_base, basic, &mut rng));
+    // The player's natural is paid while first base plays on, and once first
+    // base busts the dealer doesn't draw to 15.
+    let mut shoe = stacked(&[10, 1, 9, 2, 10, 6, 3]);
+    assert!(1.5 == play_table(&mut shoe, &DEFAULT_RULES, &table, basic, basic, &mut rng));
+    assert!(8 == shoe.discards.len() && 19 == shoe.cards.len());
+
+    // Playing both spots, the second sees what the first drew. Its 12 stands
+    // against a 2 once the first spot has taken the nine, and the dealer's
+    // 12 draws a five.
+    let both = Table {
+        seats: 2,
+        seat: 0,
+        spots: 2,
+    };
+    let seen_nine = |state: &mut GameState| if state.player == vec![10, 2] &&
+                                                state.deck.cards[8] == 0 {
+        Action::Stand
+    } else {
+        basic_strategy(state)
+    };
+    let mut shoe = stacked(&[10, 10, 2, 2, 2, 10, 9, 5]);
+    assert!(-1.0 + 1.0 == play_table(&mut shoe, &DEFAULT_RULES, &both, &seen_nine, basic,
+                                     &mut rng));
+    // Against an ace both spots are offered insurance, and the dealer's
+    // natural is settled for each of them.
+    let insure = |state: &mut GameState| if can_insurance(state) {
+        Action::Insurance
+    } else {
+        Action::Stand
+    };
+    let mut shoe = stacked(&[10, 9, 1, 10, 8, 10]);
+    assert!(2.0 - 1.0 - 1.0 == play_table(&mut shoe, &DEFAULT_RULES, &both, &insure, basic,
+                                          &mut rng));
+}
@@ -0,0 +1,63 @@
+// Generates the shoes that advantages are computed for, by dealing shuffled
+// shoes to a basic strategy player and noting what's left at the start of
+// every round until the cut card comes out.
+
+use std::error::Error;
+
+use csv;
+
+use super::{Deck, Rules};
+use super::basic::basic_strategy;
+use super::simulator::{Cut, Shoe, simulate};
+#[cfg(test)]
+use super::DEFAULT_RULES;
+
+/// Deals `shoes` shoes of `decks` decks, each until `penetration` of it has
+/// been dealt, and returns what was left at the start of each round. The same
+/// seed always deals the same shoes.
+pub fn generate_samples(decks: u16,
+                        penetration: f32,
+                        shoes: usize,
+                        seed: usize,
+                        rules: &Rules)
+                        -> Vec<Deck> {
+    let shoe = Shoe {
+        decks,
+        cut: Cut::Penetration(penetration),
+        burn: 0,
+    };
+    let mut samples = vec![];
+    simulate(&shoe, rules, shoes, seed, &basic_strategy, |round| samples.push(round.start));
+    samples
+}
+
+#[test]
+fn test_generate_samples() {
+    let samples = generate_samples(2, 0.75, 3, 11, &DEFAULT_RULES);
+    let full_shoe = samples[0];
+    assert!(104 == full_shoe.size);
+    assert!(3 == samples.iter().filter(|&&deck| deck == full_shoe).count());
+    for pair in samples.windows(2) {
+        assert!(pair[1].size < pair[0].size || pair[1] == full_shoe);
+    }
+    assert!(samples.iter().all(|deck| deck.size > 26 && deck.cards.iter().sum::<u16>() == deck.size));
+    assert!(samples == generate_samples(2, 0.75, 3, 11, &DEFAULT_RULES));
+    assert!(samples != generate_samples(2, 0.75, 3, 12, &DEFAULT_RULES));
+}
+
+/// The samples in the shape `deck_samples` reads: a header, then the count of
+/// each rank from ace to ten.
+pub fn samples_csv(samples: &[Deck]) -> Result<String, Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(["A", "2", "3", "4", "5", "6", "7", "8", "9", "10"])?;
+    for deck in samples {
+        writer.write_record(deck.cards.iter().map(|count| count.to_string()))?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+#[test]
+fn test_samples_csv() {
+    let csv = samples_csv(&[super::FULL_DECK]).unwrap();
+    assert!("A,2,3,4,5,6,7,8,9,10\n32,32,32,32,32,32,32,32,32,128\n" == csv);
+}
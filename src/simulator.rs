@@ -0,0 +1,257 @@
+// Monte Carlo simulation of whole shoes. Each shoe is shuffled, has cards
+// burned, and is dealt a round at a time until the cut card comes out, with a
+// strategy playing the player's hands and basic strategy any other seats'. A
+// round that runs the shoe dry carries on with the discards shuffled. The same
+// seed always deals the same shoes, so runs can be reproduced and compared.
+
+use rand::{SeedableRng, StdRng};
+
+use super::{Action, Deck, GameState, OrderedDeck, Rules};
+use super::basic::basic_strategy;
+use super::round::{HEADS_UP, Table, play_table};
+#[cfg(test)]
+use super::DEFAULT_RULES;
+
+/// Where the cut card goes.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum Cut {
+    /// The share of the shoe in front of the cut card.
+    Penetration(f32),
+    /// How many cards are behind the cut card.
+    Remaining(usize),
+}
+
+/// Reads a cut card as a share of the shoe, like "0.75", or a number of cards
+/// behind it, like "52".
+pub fn parse_cut(cut: &str) -> Cut {
+    if cut.contains('.') {
+        Cut::Penetration(cut.parse::<f32>().unwrap())
+    } else {
+        Cut::Remaining(cut.parse::<usize>().unwrap())
+    }
+}
+
+/// How the shoe is dealt.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Shoe {
+    pub decks: u16,
+    pub cut: Cut,
+    /// How many cards are burned after each shuffle.
+    pub burn: usize,
+}
+
+impl Shoe {
+    // How many cards are left when the cut card comes out.
+    fn cut_card(&self) -> usize {
+        let size = 52 * self.decks as usize;
+        match self.cut {
+            Cut::Penetration(penetration) => ((1.0 - penetration) * size as f32) as usize,
+            Cut::Remaining(cards) => cards.min(size),
+        }
+    }
+}
+
+#[test]
+fn test_cut_card() {
+    let shoe = |cut: &str| {
+        Shoe {
+            decks: 6,
+            cut: parse_cut(cut),
+            burn: 1,
+        }
+    };
+    assert!(Cut::Penetration(0.75) == shoe("0.75").cut);
+    assert!(78 == shoe("0.75").cut_card());
+    assert!(Cut::Remaining(52) == shoe("52").cut);
+    assert!(52 == shoe("52").cut_card());
+    assert!(312 == shoe("400").cut_card());
+    assert!(0 == shoe("1.0").cut_card());
+}
+
+/// A round dealt in a simulation.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Round {
+    /// Which shoe the round was dealt from, counting from 0.
+    pub shoe: usize,
+    /// Which round of the shoe it was, counting from 0.
+    pub number: usize,
+    /// What the player hadn't seen when the round started: what was left in
+    /// the shoe and any burned cards.
+    pub start: Deck,
+    /// The player's result in initial bets.
+    pub result: f32,
+}
+
+/// Deals `shoes` shoes, with `strategy` making the player's decisions, and
+/// passes each round to `record`. Rounds are started until the cut card comes
+/// out.
+pub fn simulate<S, R>(shoe: &Shoe,
+                      rules: &Rules,
+                      shoes: usize,
+                      seed: usize,
+                      strategy: &S,
+                      record: R)
+    where S: Fn(&mut GameState) -> Action,
+          R: FnMut(Round)
+{
+    simulate_table(shoe, &HEADS_UP, rules, shoes, seed, strategy, record)
+}
+
+/// Deals `shoes` shoes to every seat at `table`, with `strategy` making the
+/// player's decisions and the other seats playing basic strategy, and passes
+/// each of the player's rounds to `record`.
+pub fn simulate_table<S, R>(shoe: &Shoe,
+                            table: &Table,
+                            rules: &Rules,
+                            shoes: usize,
+                            seed: usize,
+                            strategy: &S,
+                            mut record: R)
+    where S: Fn(&mut GameState) -> Action,
+          R: FnMut(Round)
+{
+    let seed: &[_] = &[seed];
+    let mut rng: StdRng = SeedableRng::from_seed(seed);
+    let cut_card = shoe.cut_card();
+    for number in 0..shoes {
+        let mut cards = OrderedDeck::shuffled(shoe.decks, &mut rng);
+        for _ in 0..shoe.burn {
+            cards.burn();
+        }
+        let mut round = 0;
+        while cards.cards.len() > cut_card {
+            let start = cards.unseen();
+            let result = play_table(&mut cards, rules, table, strategy, &basic_strategy, &mut rng);
+            record(Round {
+                shoe: number,
+                number: round,
+                start,
+                result,
+            });
+            round += 1;
+        }
+    }
+}
+
+/// The mean and spread of results.
+#[derive(Debug,PartialEq,Clone,Copy,Default)]
+pub struct Summary {
+    pub rounds: usize,
+    total: f64,
+    squares: f64,
+}
+
+impl Summary {
+    pub fn add(&mut self, result: f32) {
+        self.rounds += 1;
+        self.total += result as f64;
+        self.squares += result as f64 * result as f64;
+    }
+
+    /// The mean result per round.
+    pub fn mean(&self) -> f64 {
+        self.total / self.rounds as f64
+    }
+
+    pub fn variance(&self) -> f64 {
+        let mean = self.mean();
+        (self.squares / self.rounds as f64 - mean * mean).max(0.0)
+    }
+
+    /// The standard error of the mean.
+    pub fn std_error(&self) -> f64 {
+        (self.variance() / self.rounds as f64).sqrt()
+    }
+}
+
+#[test]
+fn test_summary() {
+    let mut summary = Summary::default();
+    for &result in [1.0, -1.0, 1.5, -0.5].iter() {
+        summary.add(result);
+    }
+    assert!(4 == summary.rounds);
+    assert!(0.25 == summary.mean());
+    assert!((summary.variance() - 1.0625).abs() < 1e-12);
+    assert!((summary.std_error() - 1.0625f64.sqrt() / 2.0).abs() < 1e-12);
+}
+
+#[test]
+fn test_simulate() {
+    let shoe = Shoe {
+        decks: 2,
+        cut: Cut::Penetration(0.75),
+        burn: 1,
+    };
+    let run = |seed: usize| {
+        let mut rounds = vec![];
+        simulate(&shoe, &DEFAULT_RULES, 20, seed, &basic_strategy, |round| rounds.push(round));
+        rounds
+    };
+    let rounds = run(5);
+    assert!(rounds == run(5));
+    assert!(rounds != run(6));
+    assert!(20 == rounds.iter().filter(|round| round.number == 0).count());
+    for round in rounds.iter() {
+        assert!(round.start.size > 26);
+        assert!(round.result.abs() <= 8.0);
+        if round.number == 0 {
+            assert!(104 == round.start.size);
+        }
+    }
+    for pair in rounds.windows(2) {
+        if pair[1].shoe == pair[0].shoe {
+            assert!(pair[1].number == pair[0].number + 1);
+            assert!(pair[1].start.size < pair[0].start.size);
+        }
+    }
+
+    // Dealt to the last card, rounds run into the discards.
+    let shoe = Shoe {
+        decks: 1,
+        cut: Cut::Remaining(0),
+        burn: 0,
+    };
+    let mut rounds = vec![];
+    simulate(&shoe, &DEFAULT_RULES, 50, 5, &basic_strategy, |round| rounds.push(round));
+    assert!(rounds.iter().any(|round| round.start.size < 4));
+    assert!(rounds.iter().all(|round| round.start.size > 0 && round.result.abs() <= 8.0));
+
+    // Other seats use up the shoe in fewer rounds.
+    let shoe = Shoe {
+        decks: 6,
+        cut: Cut::Penetration(0.75),
+        burn: 1,
+    };
+    let count = |seats: usize| {
+        let table = Table {
+            seats,
+            seat: seats - 1,
+            spots: 1,
+        };
+        let mut rounds = 0;
+        simulate_table(&shoe, &table, &DEFAULT_RULES, 20, 5, &basic_strategy, |_| rounds += 1);
+        rounds
+    };
+    let (alone, full) = (count(1), count(7));
+    assert!(alone > 20 * 40 && alone < 20 * 50);
+    assert!(full > 20 * 9 && full < 20 * 12);
+
+    // Two spots a round, played by the one player.
+    let summarize = |spots: usize| {
+        let table = Table {
+            seats: 2,
+            seat: 0,
+            spots,
+        };
+        let mut summary = Summary::default();
+        simulate_table(&shoe, &table, &DEFAULT_RULES, 50, 5, &basic_strategy, |round| {
+            assert!(round.result.abs() <= 8.0 * spots as f32);
+            summary.add(round.result)
+        });
+        summary
+    };
+    let (one, two) = (summarize(1), summarize(2));
+    assert!(one.rounds == two.rounds);
+    assert!(two.variance() > 1.5 * one.variance());
+}
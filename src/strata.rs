@@ -0,0 +1,221 @@
+// Stratified choice of the next shoe to compute. Picking samples uniformly
+// fills the dataset with neutral shoes, so instead the samples are grouped by
+// true count and penetration, and the next shoe comes from the group that is
+// furthest short of its target. Each group can have a target of its own, so
+// the tails can be asked for more shoes than the middle.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use rand::Rng;
+
+use super::Deck;
+use super::counts::CountSystem;
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct Strata {
+    pub system: CountSystem,
+    /// The shoe the samples were dealt from.
+    pub shoe: Deck,
+    /// The width of each band of true counts.
+    pub true_count_width: f32,
+    /// The width of each band of penetration, as a fraction of the shoe.
+    pub penetration_width: f32,
+    /// How many computed shoes a stratum should have, unless `targets` gives
+    /// it its own.
+    pub target: usize,
+    /// Targets for particular strata, keyed by the number of the true count
+    /// band and of the penetration band, counting from the bands starting at
+    /// zero.
+    pub targets: HashMap<(i32, i32), usize>,
+}
+
+impl Strata {
+    fn stratum(&self, deck: &Deck) -> (i32, i32) {
+        let true_count = self.system.true_count(&self.shoe, deck);
+        let penetration = 1.0 - deck.size as f32 / self.shoe.size as f32;
+        ((true_count / self.true_count_width).floor() as i32,
+         (penetration / self.penetration_width).floor() as i32)
+    }
+
+    fn target(&self, stratum: &(i32, i32)) -> usize {
+        *self.targets.get(stratum).unwrap_or(&self.target)
+    }
+
+    /// Sets the targets from a comma separated list: the target for every
+    /// stratum, then any strata with targets of their own as
+    /// `true_count:penetration=target`, where the true count and penetration
+    /// are any in the stratum's bands, e.g. "10,3:0.2=40,4:0.2=40".
+    pub fn parse_targets(&mut self, targets_str: &str) -> Result<(), Box<dyn Error>> {
+        let mut parts = targets_str.split(',');
+        self.target = parts.next().unwrap_or("").trim().parse()?;
+        self.targets.clear();
+        for part in parts {
+            let (stratum, target) = part.split_once('=')
+                .ok_or_else(|| format!("Expected true_count:penetration=target, got {}", part))?;
+            let (true_count, penetration) = stratum.split_once(':')
+                .ok_or_else(|| format!("Expected true_count:penetration, got {}", stratum))?;
+            let true_count: f32 = true_count.trim().parse()?;
+            let penetration: f32 = penetration.trim().parse()?;
+            self.targets.insert(((true_count / self.true_count_width).floor() as i32,
+                                 (penetration / self.penetration_width).floor() as i32),
+                                target.trim().parse()?);
+        }
+        Ok(())
+    }
+
+    /// An uncomputed sample from the stratum furthest short of its target, as
+    /// a share of the target, breaking ties at random. Once every stratum has
+    /// reached its target, any uncomputed sample is as likely as any other.
+    /// None if every sample has been computed.
+    pub fn choose<R: Rng>(&self,
+                          samples: &[Deck],
+                          computed: &HashSet<Deck>,
+                          rng: &mut R)
+                          -> Option<Deck> {
+        let mut computed_counts: HashMap<(i32, i32), usize> = HashMap::new();
+        for deck in computed.iter() {
+            *computed_counts.entry(self.stratum(deck)).or_insert(0) += 1;
+        }
+        let mut candidates: HashMap<(i32, i32), Vec<Deck>> = HashMap::new();
+        for deck in samples.iter().filter(|deck| !computed.contains(deck)) {
+            candidates.entry(self.stratum(deck)).or_default().push(*deck);
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+        let covered = |stratum: &(i32, i32)| *computed_counts.get(stratum).unwrap_or(&0);
+        // The share of its target a stratum has, for those still short of it.
+        let filled = |stratum: &(i32, i32)| {
+            let target = self.target(stratum);
+            if covered(stratum) < target {
+                Some(covered(stratum) as f64 / target as f64)
+            } else {
+                None
+            }
+        };
+        let least = match candidates.keys().filter_map(&filled).min_by(|a, b| a.total_cmp(b)) {
+            Some(least) => least,
+            None => {
+                let uncomputed: Vec<&Deck> = candidates.values().flatten().collect();
+                return rng.choose(&uncomputed).map(|&&deck| deck);
+            }
+        };
+        let mut neediest: Vec<&(i32, i32)> =
+            candidates.keys().filter(|stratum| filled(stratum) == Some(least)).collect();
+        // Sorted so the same seed always makes the same choice.
+        neediest.sort();
+        let stratum = rng.choose(&neediest)?;
+        rng.choose(&candidates[*stratum]).cloned()
+    }
+}
+
+#[cfg(test)]
+fn deck(cards: [u16; 10]) -> Deck {
+    Deck {
+        cards,
+        size: cards.iter().sum(),
+    }
+}
+
+// Two shoes at a neutral Hi-Lo count and one at a high count, all early in
+// the shoe.
+#[cfg(test)]
+fn neutral_and_high() -> (Deck, Deck, Deck) {
+    (deck([30, 30, 30, 30, 30, 30, 30, 30, 30, 120]),
+     deck([30, 30, 30, 30, 30, 30, 31, 29, 30, 120]),
+     deck([32, 26, 26, 26, 26, 26, 32, 32, 32, 128]))
+}
+
+#[test]
+fn test_choose() {
+    use rand::{SeedableRng, StdRng};
+    use super::FULL_DECK;
+    let strata = Strata {
+        system: super::counts::systems()[0].clone(),
+        shoe: FULL_DECK,
+        true_count_width: 1.0,
+        penetration_width: 0.5,
+        target: 10,
+        targets: HashMap::new(),
+    };
+    let (neutral, neutral_too, high) = neutral_and_high();
+    // A neutral count deep in the shoe.
+    let deep = deck([10, 10, 10, 10, 10, 10, 10, 10, 10, 40]);
+    assert!(strata.stratum(&neutral) == strata.stratum(&neutral_too));
+    assert!(strata.stratum(&neutral) != strata.stratum(&high));
+    assert!(strata.stratum(&neutral) != strata.stratum(&deep));
+
+    let seed: &[_] = &[3];
+    let mut rng: StdRng = SeedableRng::from_seed(seed);
+    let samples = vec![neutral, neutral_too, high, deep];
+    let mut computed = HashSet::new();
+    computed.insert(neutral);
+    computed.insert(deep);
+    for _ in 0..10 {
+        assert!(Some(high) == strata.choose(&samples, &computed, &mut rng));
+    }
+    computed.insert(high);
+    assert!(Some(neutral_too) == strata.choose(&samples, &computed, &mut rng));
+    computed.insert(neutral_too);
+    assert!(strata.choose(&samples, &computed, &mut rng).is_none());
+
+    // With two high counts computed, the neutral stratum is short until both
+    // have reached the target.
+    let high_too = deck([32, 26, 26, 26, 27, 25, 32, 32, 32, 128]);
+    let high_three = deck([32, 26, 26, 25, 27, 26, 32, 32, 32, 128]);
+    let samples = vec![neutral, neutral_too, high, high_too, high_three];
+    let computed: HashSet<Deck> = [neutral, high, high_too].iter().cloned().collect();
+    for _ in 0..10 {
+        assert!(Some(neutral_too) == strata.choose(&samples, &computed, &mut rng));
+    }
+    let filled = Strata { target: 1, ..strata };
+    let choices: HashSet<Option<Deck>> =
+        (0..50).map(|_| filled.choose(&samples, &computed, &mut rng)).collect();
+    assert!(2 == choices.len());
+}
+
+#[test]
+fn test_targets() {
+    use rand::{SeedableRng, StdRng};
+    use super::FULL_DECK;
+    let mut strata = Strata {
+        system: super::counts::systems()[0].clone(),
+        shoe: FULL_DECK,
+        true_count_width: 1.0,
+        penetration_width: 0.5,
+        target: 0,
+        targets: HashMap::new(),
+    };
+    strata.parse_targets("2, 4.5:0.25=4").unwrap();
+    assert!(2 == strata.target);
+    let high_stratum = (4, 0);
+    assert!(4 == strata.target(&high_stratum));
+    assert!(2 == strata.target(&(0, 0)));
+    assert!(strata.parse_targets("2,4.5=4").is_err());
+    assert!(strata.parse_targets("two").is_err());
+    strata.parse_targets("2,4.5:0.25=4").unwrap();
+
+    let (neutral, neutral_too, high) = neutral_and_high();
+    let high_too = deck([32, 26, 26, 26, 27, 25, 32, 32, 32, 128]);
+    let high_three = deck([32, 26, 26, 25, 27, 26, 32, 32, 32, 128]);
+    assert!(high_stratum == strata.stratum(&high));
+    assert!(high_stratum == strata.stratum(&high_three));
+
+    // One neutral shoe is half the neutral target, but one high shoe is a
+    // quarter of the high target, so the high stratum comes first until the
+    // two are even.
+    let seed: &[_] = &[5];
+    let mut rng: StdRng = SeedableRng::from_seed(seed);
+    let samples = vec![neutral, neutral_too, high, high_too, high_three];
+    let choose_from = |computed: &[Deck], rng: &mut StdRng| -> HashSet<Option<Deck>> {
+        let computed: HashSet<Deck> = computed.iter().cloned().collect();
+        (0..50).map(|_| strata.choose(&samples, &computed, rng)).collect()
+    };
+    let set = |decks: &[Deck]| -> HashSet<Option<Deck>> {
+        decks.iter().map(|&deck| Some(deck)).collect()
+    };
+    assert!(set(&[high_too, high_three]) == choose_from(&[neutral, high], &mut rng));
+    assert!(set(&[neutral_too, high_three]) == choose_from(&[neutral, high, high_too], &mut rng));
+    assert!(set(&[neutral_too]) == choose_from(&[neutral, high, high_too, high_three], &mut rng));
+}
@@ -0,0 +1,220 @@
+// Composition-dependent strategy charts. Every cell of the usual hard, soft
+// and pair chart is filled with the expectation of each legal action for the
+// given shoe, averaged over the two-card hands that make up the cell.
+
+use std::fmt;
+use std::thread;
+
+use super::{Action, ActionReport, Deck, GameState, Rules, action_expectations};
+#[cfg(test)]
+use super::DEFAULT_RULES;
+
+/// Dealer upcards in the order charts list them.
+pub const UPCARDS: [u16; 10] = [2, 3, 4, 5, 6, 7, 8, 9, 10, 1];
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash,PartialOrd,Ord)]
+pub enum HandKind {
+    Hard(u16),
+    Soft(u16),
+    Pair(u16),
+}
+
+impl HandKind {
+    // The two-card hands that make up this row of the chart.
+    fn hands(&self) -> Vec<[u16; 2]> {
+        match *self {
+            HandKind::Hard(total) => {
+                (2..11)
+                    .filter(|&card| total > card && total - card > card && total - card <= 10)
+                    .map(|card| [card, total - card])
+                    .collect()
+            }
+            HandKind::Soft(total) => vec![[1, total - 11]],
+            HandKind::Pair(card) => vec![[card, card]],
+        }
+    }
+}
+
+impl fmt::Display for HandKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandKind::Hard(total) => write!(f, "{}", total),
+            HandKind::Soft(total) => write!(f, "A,{}", total - 11),
+            HandKind::Pair(1) => write!(f, "A,A"),
+            HandKind::Pair(card) => write!(f, "{},{}", card, card),
+        }
+    }
+}
+
+#[test]
+fn test_hand_kind_hands() {
+    assert!(vec![[2, 3]] == HandKind::Hard(5).hands());
+    assert!(vec![[2, 10], [3, 9], [4, 8], [5, 7]] == HandKind::Hard(12).hands());
+    assert!(vec![[9, 10]] == HandKind::Hard(19).hands());
+    assert!(vec![[1, 7]] == HandKind::Soft(18).hands());
+    assert!(vec![[1, 1]] == HandKind::Pair(1).hands());
+}
+
+/// The rows of a chart, hard totals first, then soft totals, then pairs.
+pub fn chart_hands() -> Vec<HandKind> {
+    let mut hands: Vec<HandKind> = (5..20).map(HandKind::Hard).collect();
+    hands.extend((13..21).map(HandKind::Soft));
+    hands.extend(UPCARDS.iter().map(|&card| HandKind::Pair(card)));
+    hands
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct ChartCell {
+    pub hand: HandKind,
+    pub upcard: u16,
+    pub report: ActionReport,
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct StrategyChart {
+    pub deck: Deck,
+    pub rules: Rules,
+    pub cells: Vec<ChartCell>,
+}
+
+impl StrategyChart {
+    pub fn cell(&self, hand: HandKind, upcard: u16) -> Option<&ChartCell> {
+        self.cells.iter().find(|cell| cell.hand == hand && cell.upcard == upcard)
+    }
+}
+
+// Averages each action's expectation over the hands in the cell, weighted by
+// how likely each hand is to be dealt. Returns None if none of them can be.
+fn chart_cell(shoe: &Deck,
+              deck: &Deck,
+              rules: &Rules,
+              hand: HandKind,
+              upcard: u16)
+              -> Option<ChartCell> {
+    let mut total_weight = 0.0;
+    let mut totals: Vec<(Action, f32)> = vec![];
+    for cards in hand.hands() {
+        let mut state = GameState {
+            player: vec![],
+            dealer: vec![upcard],
+            deck: *deck,
+            shoe: *shoe,
+            failed_insurance: false,
+            is_split: false,
+            first_split_hand: false,
+            rules: *rules,
+        };
+        let mut weight = 1.0;
+        for &card in cards.iter() {
+            let count = state.deck.cards[card as usize - 1];
+            if count == 0 {
+                weight = 0.0;
+                break;
+            }
+            weight *= count as f32;
+            state.deck.draw_to(&mut state.player, card);
+        }
+        if weight == 0.0 {
+            continue;
+        }
+        total_weight += weight;
+        for (action, exp) in action_expectations(&mut state) {
+            match totals.iter_mut().find(|&&mut (a, _)| a == action) {
+                Some(total) => total.1 += weight * exp,
+                None => totals.push((action, weight * exp)),
+            }
+        }
+    }
+    if total_weight == 0.0 {
+        return None;
+    }
+    let expectations = totals.into_iter()
+        .map(|(action, total)| (action, total / total_weight))
+        .collect();
+    Some(ChartCell {
+        hand,
+        upcard,
+        report: ActionReport::new(expectations),
+    })
+}
+
+fn upcard_cells(shoe: Deck, mut deck: Deck, rules: Rules, upcard: u16) -> Vec<ChartCell> {
+    if deck.cards[upcard as usize - 1] == 0 {
+        return vec![];
+    }
+    deck.draw(upcard);
+    chart_hands()
+        .into_iter()
+        .filter_map(|hand| chart_cell(&shoe, &deck, &rules, hand, upcard))
+        .collect()
+}
+
+/// Fills in the chart for `deck`, what's left of `shoe`, under `rules`,
+/// working out each upcard on its own thread.
+pub fn strategy_chart(shoe: &Deck, deck: Deck, rules: Rules) -> StrategyChart {
+    let shoe = *shoe;
+    let handles: Vec<thread::JoinHandle<Vec<ChartCell>>> = UPCARDS.iter()
+        .map(|&upcard| thread::spawn(move || upcard_cells(shoe, deck, rules, upcard)))
+        .collect();
+    let columns: Vec<Vec<ChartCell>> =
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+    let mut cells = vec![];
+    for hand in chart_hands() {
+        for column in columns.iter() {
+            cells.extend(column.iter().filter(|cell| cell.hand == hand).cloned());
+        }
+    }
+    StrategyChart {
+        deck,
+        rules,
+        cells,
+    }
+}
+
+#[test]
+fn test_strategy_chart() {
+    let deck = Deck {
+        cards: [1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+        size: 10,
+    };
+    let chart = strategy_chart(&deck, deck, DEFAULT_RULES);
+    assert!(chart.cell(HandKind::Pair(8), 10).is_none());
+    assert!(chart.cell(HandKind::Hard(5), 2).is_none());
+    for cell in chart.cells.iter() {
+        for &(_, exp) in cell.report.expectations.iter() {
+            assert!(exp <= cell.report.best_expectation());
+        }
+    }
+
+    let cell = chart.cell(HandKind::Hard(5), 10).unwrap();
+    let mut state = GameState {
+        player: vec![2, 3],
+        dealer: vec![10],
+        deck: Deck {
+            cards: [1, 0, 0, 1, 1, 1, 1, 1, 1, 0],
+            size: 7,
+        },
+        shoe: deck,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules: DEFAULT_RULES,
+    };
+    assert!((super::expectation(&mut state) - cell.report.best_expectation()).abs() < 1e-6);
+    assert!(Some(-0.5) == cell.report.expectation(Action::Surrender));
+    assert!(cell.report.expectation(Action::Insurance).is_none());
+
+    let first_rows: Vec<(HandKind, u16)> =
+        chart.cells.iter().take(2).map(|cell| (cell.hand, cell.upcard)).collect();
+    assert!(vec![(HandKind::Hard(5), 4), (HandKind::Hard(5), 5)] == first_rows);
+
+    // Splits are played from the chart's deck, where each eight can only
+    // make 18 against 20 and is surrendered.
+    let eights = Deck {
+        cards: [0, 0, 0, 0, 0, 0, 0, 2, 0, 20],
+        size: 22,
+    };
+    let chart = strategy_chart(&super::FULL_DECK, eights, DEFAULT_RULES);
+    let cell = chart.cell(HandKind::Pair(8), 10).unwrap();
+    assert!(Some(-1.0) == cell.report.expectation(Action::Split));
+}
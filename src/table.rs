@@ -0,0 +1,135 @@
+// How the other seats at a table change a shoe for the player. They use cards
+// the player doesn't get to bet on, so fewer of the player's rounds are dealt
+// from each shoe, and the count moves further between them.
+//
+// For the expectation engine, the cards other seats take before the player
+// acts are seen, so are simply out of the deck it is given, and the cards
+// they take afterwards are unseen and random, so leave the player's
+// expectation as it was. Either way they're on the table rather than in the
+// discards, so `play_table` leaves them out of the shoe the engine would
+// reshuffle mid-round.
+
+use std::error::Error;
+
+use csv;
+
+use super::{Rules, full_shoe};
+use super::basic::basic_strategy;
+use super::counts::CountSystem;
+use super::round::Table;
+use super::simulator::{Shoe, Summary, simulate_table};
+#[cfg(test)]
+use super::DEFAULT_RULES;
+#[cfg(test)]
+use super::counts::systems;
+#[cfg(test)]
+use super::simulator::Cut;
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct Progression {
+    pub seats: usize,
+    /// How many of the player's rounds each shoe lasted.
+    pub rounds_per_shoe: Summary,
+    /// The player's results with basic strategy.
+    pub results: Summary,
+    /// The true count at the start of the player's rounds, by round number.
+    pub true_counts: Vec<Summary>,
+}
+
+/// Deals `shoes` shoes to `table`, every seat playing basic strategy, and
+/// follows the true count for `system` through the player's rounds.
+pub fn seat_progression(shoe: &Shoe,
+                        table: &Table,
+                        rules: &Rules,
+                        shoes: usize,
+                        seed: usize,
+                        system: &CountSystem)
+                        -> Progression {
+    let full = full_shoe(shoe.decks);
+    let mut rounds = vec![0; shoes];
+    let mut progression = Progression {
+        seats: table.seats,
+        rounds_per_shoe: Summary::default(),
+        results: Summary::default(),
+        true_counts: vec![],
+    };
+    simulate_table(shoe, table, rules, shoes, seed, &basic_strategy, |round| {
+        rounds[round.shoe] += 1;
+        progression.results.add(round.result);
+        if progression.true_counts.len() <= round.number {
+            progression.true_counts.push(Summary::default());
+        }
+        progression.true_counts[round.number].add(system.true_count(&full, &round.start));
+    });
+    for &rounds in rounds.iter() {
+        progression.rounds_per_shoe.add(rounds as f32);
+    }
+    progression
+}
+
+#[test]
+fn test_seat_progression() {
+    let shoe = Shoe {
+        decks: 6,
+        cut: Cut::Penetration(0.75),
+        burn: 1,
+    };
+    let hi_lo = &systems()[0];
+    let progression = |seats: usize, seat: usize| {
+        let table = Table {
+            seats,
+            seat,
+            spots: 1,
+        };
+        seat_progression(&shoe, &table, &DEFAULT_RULES, 50, 8, hi_lo)
+    };
+    let alone = progression(1, 0);
+    let full = progression(7, 3);
+    assert!(7 == full.seats && 50 == full.rounds_per_shoe.rounds);
+    assert!(full.results.rounds as f64 == 50.0 * full.rounds_per_shoe.mean());
+    assert!(alone.rounds_per_shoe.mean() > 3.0 * full.rounds_per_shoe.mean());
+    assert!(alone.true_counts.len() > full.true_counts.len());
+    // The burn card goes face down, so the count starts at zero, and spreads
+    // out as the shoe is dealt, faster with more seats.
+    assert!(50 == full.true_counts[0].rounds && 0.0 == full.true_counts[0].variance());
+    let spread = |progression: &Progression, round: usize| {
+        progression.true_counts[round].variance().sqrt()
+    };
+    assert!(spread(&full, 6) > spread(&full, 1));
+    assert!(spread(&full, 6) > spread(&alone, 6));
+    assert!(full == progression(7, 3));
+}
+
+/// The progressions as a table with columns seats, round, shoes (how many
+/// shoes lasted to the round), mean_true_count and std_dev.
+pub fn progression_csv(progressions: &[Progression]) -> Result<String, Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(["seats", "round", "shoes", "mean_true_count", "std_dev"])?;
+    for progression in progressions {
+        for (number, true_counts) in progression.true_counts.iter().enumerate() {
+            writer.write_record(&[progression.seats.to_string(),
+                                  (number + 1).to_string(),
+                                  true_counts.rounds.to_string(),
+                                  true_counts.mean().to_string(),
+                                  true_counts.variance().sqrt().to_string()])?;
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+#[test]
+fn test_progression_csv() {
+    let mut first = Summary::default();
+    first.add(0.0);
+    first.add(1.0);
+    let mut second = Summary::default();
+    second.add(-2.0);
+    let progression = Progression {
+        seats: 3,
+        rounds_per_shoe: Summary::default(),
+        results: Summary::default(),
+        true_counts: vec![first, second],
+    };
+    assert!("seats,round,shoes,mean_true_count,std_dev\n3,1,2,0.5,0.5\n3,2,1,-2,0\n" ==
+            progression_csv(&[progression]).unwrap());
+}
@@ -0,0 +1,569 @@
+// Variance of a round, and the covariance between several hands played at once
+// against the same dealer hand.
+//
+// Every hand's result is tracked per final dealer score. Given the dealer's
+// final score, the hands at the table are treated as independent, which
+// ignores the (small) effect of the cards one spot draws on another spot's
+// hits. Each hand's draws come from the shoe left after the initial deal.
+
+use std::collections::HashMap;
+
+use super::{Action, Deck, GameState, Rules, Score};
+#[cfg(test)]
+use super::{DEFAULT_RULES, FULL_DECK, best_action};
+use super::{can_double, can_insurance, can_split, can_surrender, cannot_hit, dealer_scores,
+            hand_expectation, reshuffled, reshuffled_expectation, score, will_reshuffle};
+
+// Probability-weighted sums of a hand's result, in initial bets.
+#[derive(Debug,PartialEq,Clone,Copy)]
+struct Outcome {
+    prob: f32,
+    result: f32,
+    square: f32,
+}
+
+// Outcomes keyed by the dealer's final score. Results that don't depend on
+// the dealer (busts, surrender) are kept under `None`, so the dealer's hand
+// never has to be played out for them.
+type Profile = HashMap<Option<Score>, Outcome>;
+
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Moments {
+    pub expectation: f32,
+    pub variance: f32,
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct RoundMoments {
+    pub expectations: Vec<f32>,
+    pub covariances: Vec<Vec<f32>>,
+}
+
+impl RoundMoments {
+    pub fn total_expectation(&self) -> f32 {
+        self.expectations.iter().sum()
+    }
+    pub fn total_variance(&self) -> f32 {
+        self.covariances.iter().map(|row| row.iter().sum::<f32>()).sum()
+    }
+}
+
+fn certain_profile(result: f32) -> Profile {
+    let mut profile = Profile::new();
+    profile.insert(None,
+                   Outcome {
+                       prob: 1.0,
+                       result,
+                       square: result * result,
+                   });
+    profile
+}
+
+// Folds `other` into `profile` as the result `scale * x + shift`, weighted by
+// `weight`.
+fn add_profile(profile: &mut Profile, other: &Profile, weight: f32, scale: f32, shift: f32) {
+    for (dealer_score, outcome) in other.iter() {
+        let entry = profile.entry(*dealer_score).or_insert(Outcome {
+            prob: 0.0,
+            result: 0.0,
+            square: 0.0,
+        });
+        entry.prob += weight * outcome.prob;
+        entry.result += weight * (scale * outcome.result + shift * outcome.prob);
+        entry.square += weight *
+                        (scale * scale * outcome.square + 2.0 * scale * shift * outcome.result +
+                         shift * shift * outcome.prob);
+    }
+}
+
+fn profile_moments(profile: &Profile) -> (f32, f32) {
+    profile.values().fold((0.0, 0.0), |(result, square), outcome| {
+        (result + outcome.result, square + outcome.square)
+    })
+}
+
+// The expected result given the dealer finishes with `dealer_score`, which
+// happens with probability `dealer_prob`.
+fn conditional_result(profile: &Profile, dealer_score: Score, dealer_prob: f32) -> f32 {
+    let dependent = match profile.get(&Some(dealer_score)) {
+        Some(outcome) => outcome.result / dealer_prob,
+        None => 0.0,
+    };
+    let independent = match profile.get(&None) {
+        Some(outcome) => outcome.result,
+        None => 0.0,
+    };
+    dependent + independent
+}
+
+fn stand_profile(state: &mut GameState) -> Profile {
+    let player_score = score(&state.player);
+    if player_score == Score::Bust {
+        return certain_profile(-1.0);
+    }
+    dealer_scores(&mut state.deck,
+                  &mut state.dealer,
+                  &reshuffled(&state.shoe, &state.player, &[]),
+                  state.failed_insurance,
+                  &state.rules)
+        .into_iter()
+        .map(|(dealer_score, prob)| {
+            let result = hand_expectation(player_score, dealer_score, &state.rules);
+            (Some(dealer_score),
+             Outcome {
+                 prob,
+                 result: prob * result,
+                 square: prob * result * result,
+             })
+        })
+        .collect()
+}
+
+fn draw_profile(state: &mut GameState, double: bool) -> Profile {
+    if will_reshuffle(&state.deck) {
+        return reshuffled_expectation(state, |state| draw_profile(state, double));
+    }
+    let mut profile = Profile::new();
+    for card in 1..11 {
+        let draw_prob = state.deck.card_prob(card, false);
+        if draw_prob == 0.0 {
+            continue;
+        }
+        state.deck.draw_to(&mut state.player, card);
+        if double {
+            add_profile(&mut profile, &stand_profile(state), draw_prob, 2.0, 0.0);
+        } else {
+            add_profile(&mut profile, &hand_profile(state), draw_prob, 1.0, 0.0);
+        }
+        state.deck.replace_from(&mut state.player, card);
+    }
+    profile
+}
+
+fn insurance_profile(state: &mut GameState) -> Profile {
+    if score(&state.player) == Score::Natural {
+        return certain_profile(1.0);
+    }
+    let mut profile = Profile::new();
+    let ten_prob = state.deck.card_prob(10, false);
+    profile.insert(Some(Score::Natural),
+                   Outcome {
+                       prob: ten_prob,
+                       result: 0.0,
+                       square: 0.0,
+                   });
+    if ten_prob == 1.0 {
+        return profile;
+    }
+    state.failed_insurance = true;
+    let failed_profile = hand_profile(state);
+    state.failed_insurance = false;
+    add_profile(&mut profile, &failed_profile, 1.0 - ten_prob, 1.0, -0.5);
+    profile
+}
+
+// Follows `split_expectation`: one split hand is played from the deck as it
+// stands and the other is assumed to be independent of it given the dealer's
+// score.
+fn split_profile(state: &mut GameState) -> Profile {
+    state.is_split = true;
+    let player_card = state.player.pop().unwrap();
+    state.shoe.draw(player_card);
+    let hand_profile = draw_profile(state, false);
+    state.is_split = false;
+    state.shoe.replace(player_card);
+    state.player.push(player_card);
+
+    let independent_prob = hand_profile.get(&None).map_or(0.0, |outcome| outcome.prob);
+    let dependent_prob = 1.0 - independent_prob;
+    let mut cross_term = 0.0;
+    for (dealer_score, outcome) in hand_profile.iter() {
+        if let Some(dealer_score) = *dealer_score {
+            let dealer_prob = outcome.prob / dependent_prob;
+            let result = conditional_result(&hand_profile, dealer_score, dealer_prob);
+            cross_term += dealer_prob * result * result;
+        }
+    }
+    if dependent_prob == 0.0 {
+        let result = profile_moments(&hand_profile).0;
+        cross_term = result * result;
+    }
+    let mut profile: Profile = hand_profile.into_iter()
+        .map(|(dealer_score, outcome)| {
+            (dealer_score,
+             Outcome {
+                 prob: outcome.prob,
+                 result: 2.0 * outcome.result,
+                 square: 2.0 * outcome.square,
+             })
+        })
+        .collect();
+    profile.entry(None)
+        .or_insert(Outcome {
+            prob: 0.0,
+            result: 0.0,
+            square: 0.0,
+        })
+        .square += 2.0 * cross_term;
+    profile
+}
+
+// Picks the action with the best expectation, as `best_action` does: actions
+// are tried in the order it reports them and ties go to the first.
+fn hand_profile(state: &mut GameState) -> Profile {
+    let mut best_profile = stand_profile(state);
+    if cannot_hit(&state.player) {
+        return best_profile;
+    }
+    let mut best_expectation = profile_moments(&best_profile).0;
+    let mut consider = |profile: Profile| {
+        let exp = profile_moments(&profile).0;
+        if exp > best_expectation {
+            best_profile = profile;
+            best_expectation = exp;
+        }
+    };
+    consider(draw_profile(state, false));
+    if can_double(state) {
+        consider(draw_profile(state, true));
+    }
+    if can_split(state) {
+        consider(split_profile(state));
+    }
+    if can_surrender(state) {
+        consider(certain_profile(-0.5));
+    }
+    if can_insurance(state) {
+        consider(insurance_profile(state));
+    }
+    best_profile
+}
+
+fn moments(profile: &Profile) -> Moments {
+    let (expectation, square) = profile_moments(profile);
+    Moments {
+        expectation,
+        variance: square - expectation * expectation,
+    }
+}
+
+/// The expectation and variance of a hand played to maximize expectation.
+pub fn hand_moments(state: &mut GameState) -> Moments {
+    moments(&hand_profile(state))
+}
+
+/// The expectation and variance of taking `action`, then playing to maximize
+/// expectation. The action must be allowed.
+pub fn action_moments(state: &mut GameState, action: Action) -> Moments {
+    moments(&match action {
+        Action::Stand => stand_profile(state),
+        Action::Hit => draw_profile(state, false),
+        Action::Double => draw_profile(state, true),
+        Action::Split => split_profile(state),
+        Action::Surrender => certain_profile(-0.5),
+        Action::Insurance => insurance_profile(state),
+    })
+}
+
+#[test]
+fn test_hand_moments() {
+    let mut state = GameState {
+        player: vec![10, 9],
+        dealer: vec![10, 5],
+        deck: Deck {
+            cards: [0, 0, 0, 0, 1, 0, 0, 0, 0, 3],
+            size: 4,
+        },
+        shoe: FULL_DECK,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules: DEFAULT_RULES,
+    };
+    let moments = hand_moments(&mut state);
+    assert!(0.5 == moments.expectation);
+    assert!(0.75 == moments.variance);
+
+    state = GameState {
+        player: vec![6, 4],
+        dealer: vec![10, 9],
+        deck: Deck {
+            cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
+            size: 10,
+        },
+        shoe: FULL_DECK,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules: DEFAULT_RULES,
+    };
+    let moments = hand_moments(&mut state);
+    assert!(2.0 == moments.expectation);
+    assert!(0.0 == moments.variance);
+
+    state = GameState {
+        player: vec![10, 6],
+        dealer: vec![10, 9],
+        deck: Deck {
+            cards: [0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
+            size: 10,
+        },
+        shoe: FULL_DECK,
+        failed_insurance: false,
+        is_split: false,
+        first_split_hand: false,
+        rules: DEFAULT_RULES,
+    };
+    let moments = hand_moments(&mut state);
+    assert!(-0.5 == moments.expectation);
+    assert!(0.0 == moments.variance);
+    assert!(moments == action_moments(&mut state, Action::Surrender));
+    let moments = action_moments(&mut state, Action::Stand);
+    assert!(-1.0 == moments.expectation);
+    assert!(0.0 == moments.variance);
+
+    // Standing ties with surrendering, and best_action stands.
+    state.player = vec![10, 6];
+    state.dealer = vec![10];
+    state.deck = Deck {
+        cards: [0, 0, 0, 0, 0, 1, 0, 0, 3, 0],
+        size: 4,
+    };
+    assert!(Action::Stand == best_action(&mut state).best);
+    let moments = hand_moments(&mut state);
+    assert!(-0.5 == moments.expectation);
+    assert!(0.75 == moments.variance);
+}
+
+fn deal_hands(shoe: &Deck,
+              deck: &mut Deck,
+              rules: &Rules,
+              hands: &mut Vec<Vec<u16>>,
+              remaining: usize,
+              weight: f32,
+              totals: &mut RoundMoments) {
+    if remaining == 0 {
+        return deal_dealer(shoe, deck, rules, hands, weight, totals);
+    }
+    for card1 in 1..11 {
+        let draw_prob1 = deck.card_prob(card1, false);
+        if draw_prob1 == 0.0 {
+            continue;
+        }
+        deck.draw(card1);
+        for card2 in card1..11 {
+            let draw_prob2 = deck.card_prob(card2, false);
+            if draw_prob2 == 0.0 {
+                continue;
+            }
+            deck.draw(card2);
+            let pair_prob = if card1 == card2 {
+                draw_prob1 * draw_prob2
+            } else {
+                2.0 * draw_prob1 * draw_prob2
+            };
+            hands.push(vec![card1, card2]);
+            deal_hands(shoe, deck, rules, hands, remaining - 1, weight * pair_prob, totals);
+            hands.pop();
+            deck.replace(card2);
+        }
+        deck.replace(card1);
+    }
+}
+
+fn deal_dealer(shoe: &Deck,
+               deck: &mut Deck,
+               rules: &Rules,
+               hands: &[Vec<u16>],
+               weight: f32,
+               totals: &mut RoundMoments) {
+    for upcard in 1..11 {
+        let upcard_prob = deck.card_prob(upcard, false);
+        if upcard_prob == 0.0 {
+            continue;
+        }
+        deck.draw(upcard);
+        add_dealt_round(shoe, deck, rules, hands, upcard, weight * upcard_prob, totals);
+        deck.replace(upcard);
+    }
+}
+
+// Adds the raw moments of a round whose initial cards are already out of
+// `deck`, what's left of `shoe`. Each hand sees the others' cards on the
+// table, out of any reshuffle.
+fn add_dealt_round(shoe: &Deck,
+                   deck: &mut Deck,
+                   rules: &Rules,
+                   hands: &[Vec<u16>],
+                   upcard: u16,
+                   weight: f32,
+                   totals: &mut RoundMoments) {
+    let profiles: Vec<Profile> = hands.iter()
+        .enumerate()
+        .map(|(i, hand)| {
+            let others: Vec<u16> = hands.iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .flat_map(|(_, other)| other.iter().cloned())
+                .collect();
+            hand_profile(&mut GameState {
+                player: hand.clone(),
+                dealer: vec![upcard],
+                deck: *deck,
+                shoe: reshuffled(shoe, &others, &[]),
+                failed_insurance: false,
+                is_split: false,
+                first_split_hand: false,
+                rules: *rules,
+            })
+        })
+        .collect();
+    let table: Vec<u16> = hands.concat();
+    let dealer_probabilities =
+        dealer_scores(deck, &mut vec![upcard], &reshuffled(shoe, &table, &[]), false, rules);
+    for (i, profile_i) in profiles.iter().enumerate() {
+        let (result, square) = profile_moments(profile_i);
+        totals.expectations[i] += weight * result;
+        totals.covariances[i][i] += weight * square;
+        for (j, profile_j) in profiles.iter().enumerate().skip(i + 1) {
+            let joint: f32 = dealer_probabilities.iter()
+                .map(|(&dealer_score, &prob)| {
+                    prob * conditional_result(profile_i, dealer_score, prob) *
+                    conditional_result(profile_j, dealer_score, prob)
+                })
+                .sum();
+            totals.covariances[i][j] += weight * joint;
+            totals.covariances[j][i] += weight * joint;
+        }
+    }
+}
+
+fn empty_totals(hands: usize) -> RoundMoments {
+    RoundMoments {
+        expectations: vec![0.0; hands],
+        covariances: vec![vec![0.0; hands]; hands],
+    }
+}
+
+// Turns raw second moments into covariances.
+fn center(mut totals: RoundMoments) -> RoundMoments {
+    let hands = totals.expectations.len();
+    for i in 0..hands {
+        for j in 0..hands {
+            totals.covariances[i][j] -= totals.expectations[i] * totals.expectations[j];
+        }
+    }
+    totals
+}
+
+/// The expectation of each of `hands` hands dealt from `deck`, what's left of
+/// `shoe`, against one dealer hand, along with the covariance matrix of their
+/// results. The diagonal holds each hand's variance.
+pub fn round_moments(shoe: &Deck, deck: Deck, rules: &Rules, hands: usize) -> RoundMoments {
+    let mut totals = empty_totals(hands);
+    let mut deck = deck;
+    deal_hands(shoe, &mut deck, rules, &mut vec![], hands, 1.0, &mut totals);
+    center(totals)
+}
+
+#[test]
+fn test_round_moments() {
+    let deck = Deck {
+        cards: [1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+        size: 10,
+    };
+    let moments = round_moments(&deck, deck, &DEFAULT_RULES, 1);
+    let expectation = super::deck_expectation(&deck, deck, &DEFAULT_RULES);
+    assert!((moments.expectations[0] - expectation).abs() < 1e-4);
+    assert!(moments.covariances[0][0] > 0.0);
+}
+
+/// Like `round_moments`, but for a round that has already been dealt. `deck`
+/// must not include the cards in `hands` or the dealer's `upcard`.
+pub fn dealt_round_moments(shoe: &Deck,
+                           deck: Deck,
+                           rules: &Rules,
+                           hands: &[Vec<u16>],
+                           upcard: u16)
+                           -> RoundMoments {
+    let mut totals = empty_totals(hands.len());
+    let mut deck = deck;
+    add_dealt_round(shoe, &mut deck, rules, hands, upcard, 1.0, &mut totals);
+    center(totals)
+}
+
+#[test]
+fn test_dealt_round_moments() {
+    let deck = Deck {
+        cards: [0, 0, 0, 0, 0, 0, 2, 0, 0, 2],
+        size: 4,
+    };
+    let hands = [vec![10, 9], vec![10, 8]];
+    let moments = dealt_round_moments(&FULL_DECK, deck, &DEFAULT_RULES, &hands, 10);
+    assert!(vec![0.0, 0.0] == moments.expectations);
+    assert!(vec![vec![1.0, 1.0], vec![1.0, 1.0]] == moments.covariances);
+    assert!(4.0 == moments.total_variance());
+
+    let hands = [vec![10, 9], vec![10, 6]];
+    let moments = dealt_round_moments(&FULL_DECK, deck, &DEFAULT_RULES, &hands, 10);
+    assert!(vec![0.0, -0.5] == moments.expectations);
+    assert!(0.0 == moments.covariances[0][1]);
+    assert!(0.0 == moments.covariances[1][1]);
+}
+
+// The spots are really played in turn, each drawing from what the spots before
+// it left, and the strategy sees those cards. Even in a thirteen card shoe,
+// where that matters most, treating them as independent is close.
+#[test]
+fn test_independent_spots() {
+    use rand::{Rng, SeedableRng, StdRng};
+    use super::{OrderedDeck, optimal_strategy};
+    use super::round::{Table, play_table};
+    use super::simulator::Summary;
+    // The rest of the shoe, the hole card included.
+    let deck = Deck {
+        cards: [1, 1, 1, 1, 1, 1, 1, 1, 1, 4],
+        size: 13,
+    };
+    let hands = [vec![10, 2], vec![10, 3]];
+    let upcard = 10;
+    let mut shoe = deck;
+    for &card in hands.concat().iter().chain([upcard].iter()) {
+        shoe.replace(card);
+    }
+    let moments = dealt_round_moments(&shoe, deck, &DEFAULT_RULES, &hands, upcard);
+
+    let seed: &[_] = &[3];
+    let mut rng: StdRng = SeedableRng::from_seed(seed);
+    let table = Table {
+        seats: 2,
+        seat: 0,
+        spots: 2,
+    };
+    let mut rest = vec![];
+    for card in 1..11 {
+        rest.extend(::std::iter::repeat_n(card, deck.cards[card as usize - 1] as usize));
+    }
+    let mut results = Summary::default();
+    for _ in 0..2000 {
+        let mut cards = rest.clone();
+        rng.shuffle(&mut cards);
+        // Dealt from the end: a card to each seat, the upcard, a card to each
+        // seat, then the hole card.
+        let hole = cards.pop().unwrap();
+        cards.extend_from_slice(&[hole, hands[1][1], hands[0][1]]);
+        cards.extend_from_slice(&[upcard, hands[1][0], hands[0][0]]);
+        // A copy of the rest to reshuffle, should a round run the shoe dry.
+        let mut ordered = OrderedDeck {
+            cards,
+            deck: shoe,
+            discards: rest.clone(),
+            burned: vec![],
+            shuffles: 0,
+        };
+        let strategy = &optimal_strategy;
+        results.add(play_table(&mut ordered, &DEFAULT_RULES, &table, strategy, strategy, &mut rng));
+    }
+    let error = (results.mean() - moments.total_expectation() as f64).abs();
+    assert!(error < 3.0 * results.std_error());
+}